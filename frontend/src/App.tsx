@@ -5,6 +5,8 @@ import Dashboard from "./pages/Dashboard";
 import Edit from "./pages/Edit";
 import NotFoundWithoutAuth from "./pages/NotFoundWithoutAuth";
 import { Collab } from "./pages/Collab";
+import ResetPassword from "./pages/ResetPassword";
+import VerifyEmail from "./pages/VerifyEmail";
 
 const App = () => {
     return (<>
@@ -14,6 +16,8 @@ const App = () => {
             <Route path="/dashboard" element={<Dashboard />} />
             <Route path="/doc/edit/:docId" element={<Edit />} />
             <Route path="/doc/collab/:docId" element={<Collab />} />
+            <Route path="/reset-password" element={<ResetPassword />} />
+            <Route path="/verify-email" element={<VerifyEmail />} />
             <Route path="/:slug" element={<NotFoundWithoutAuth />} />
         </Routes>
     </>)
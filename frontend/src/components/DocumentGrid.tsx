@@ -43,7 +43,7 @@ function DocumentGrid({
                     <div className="flex items-center space-x-4 text-sm text-gray-600 mb-4">
                         <div className="flex items-center space-x-1">
                             <Users className="w-4 h-4" />
-                            <span>{doc.collaborators.length}</span>
+                            <span>{doc.permissions.length}</span>
                         </div>
 
                         {onToggleStar && (
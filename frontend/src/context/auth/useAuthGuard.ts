@@ -24,7 +24,7 @@ const useAuthGuard = () => {
 
     const logout = useCallback(async () => {
         try {
-            await api.get("/auth/logout", { withCredentials: true });
+            await api.post("/auth/logout", {}, { withCredentials: true });
         // eslint-disable-next-line @typescript-eslint/no-unused-vars
         } catch (_) {
             // ignore
@@ -1,7 +1,29 @@
-import axios from "axios";
+import axios, { AxiosError, type InternalAxiosRequestConfig } from "axios";
 
 export const BaseUrl = import.meta.env.VITE_BACKEND_URL==="http://localhost:7878"?"":import.meta.env.VITE_BACKEND_URL
 
 const api = axios.create({baseURL:BaseUrl+"/api",withCredentials:true})
 
-export default api 
\ No newline at end of file
+// access tokens are short lived, swap the refresh cookie for a new one when they run out
+const NO_REFRESH = ["/auth/login", "/auth/login/2fa", "/auth/refresh", "/auth/logout"]
+let refreshing: Promise<unknown> | null = null
+
+api.interceptors.response.use(undefined, async (error: AxiosError) => {
+    const config = error.config as (InternalAxiosRequestConfig & { retried?: boolean }) | undefined
+    if (error.response?.status !== 401 || !config || config.retried || NO_REFRESH.includes(config.url ?? "")) {
+        throw error
+    }
+    config.retried = true
+    refreshing ??= api.post("/auth/refresh").finally(() => { refreshing = null })
+    try {
+        await refreshing
+    } catch (e) {
+        // another tab refreshed first, its cookies are already in place
+        if (!(e instanceof AxiosError) || e.response?.status !== 409) {
+            throw error
+        }
+    }
+    return api(config)
+})
+
+export default api
@@ -0,0 +1,93 @@
+import type { DeleteUpdate, InsertUpdate } from "./utils";
+
+export type Update = InsertUpdate | DeleteUpdate
+
+function span(update: Update): number {
+  return update.type.update === "insert" ? update.type.data.length : update.type.length
+}
+
+function withPosition<T extends Update>(update: T, position: number): T {
+  return { ...update, position }
+}
+
+function deletion(update: Update, position: number, length: number): DeleteUpdate {
+  return { ...update, position, type: { update: "delete", length } } as DeleteUpdate
+}
+
+// Same rules as the server's ot::transform: transform `update` so it applies on
+// top of `applied`. Ties between inserts go to `applied` when `appliedWins`.
+export function transform(update: Update, applied: Update, appliedWins: boolean): Update[] {
+  const pos = update.position
+  const appliedPos = applied.position
+  const appliedLen = span(applied)
+
+  if (update.type.update === "insert") {
+    if (applied.type.update === "insert") {
+      if (appliedPos < pos || (appliedPos === pos && appliedWins)) {
+        return [withPosition(update, pos + appliedLen)]
+      }
+      return [update]
+    }
+    if (pos >= appliedPos + appliedLen) {
+      return [withPosition(update, pos - appliedLen)]
+    }
+    if (pos > appliedPos) {
+      return [withPosition(update, appliedPos)]
+    }
+    return [update]
+  }
+
+  const end = pos + update.type.length
+  if (applied.type.update === "insert") {
+    if (appliedPos <= pos) {
+      return [withPosition(update, pos + appliedLen)]
+    }
+    if (appliedPos >= end) {
+      return [update]
+    }
+    // keep the inserted text, deleting around it
+    return [
+      deletion(update, appliedPos + appliedLen, end - appliedPos),
+      deletion(update, pos, appliedPos - pos),
+    ]
+  }
+
+  const appliedEnd = appliedPos + appliedLen
+  if (appliedEnd <= pos) {
+    return [withPosition(update, pos - appliedLen)]
+  }
+  if (appliedPos < end) {
+    const overlap = Math.min(end, appliedEnd) - Math.max(pos, appliedPos)
+    const length = update.type.length - overlap
+    return length === 0 ? [] : [deletion(update, Math.min(pos, appliedPos), length)]
+  }
+  return [update]
+}
+
+// Transform two sequences of updates made on the same text against each other.
+// Returns `local` rebased on top of `remote` and `remote` rebased on top of
+// `local`. `remote` has been committed so it wins ties.
+export function transformAll(local: Update[], remote: Update[]): [Update[], Update[]] {
+  if (local.length === 0 || remote.length === 0) {
+    return [local, remote]
+  }
+  if (local.length === 1 && remote.length === 1) {
+    return [transform(local[0], remote[0], true), transform(remote[0], local[0], false)]
+  }
+  if (local.length > 1) {
+    const [head, remoteAfterHead] = transformAll(local.slice(0, 1), remote)
+    const [rest, remoteAfterRest] = transformAll(local.slice(1), remoteAfterHead)
+    return [head.concat(rest), remoteAfterRest]
+  }
+  const [localAfterHead, head] = transformAll(local, remote.slice(0, 1))
+  const [localAfterRest, rest] = transformAll(localAfterHead, remote.slice(1))
+  return [localAfterRest, head.concat(rest)]
+}
+
+// Messages the server sends over the edit websocket (protocol version 1)
+export type ServerMessage =
+  | { type: "sync", revision: number, content: string }
+  | { type: "ack", id?: number, revision: number }
+  | { type: "op", update?: Update }
+  | { type: "error", code: string, message: string, id?: number }
+  | { type: "presence" | "resume" | "pong" }
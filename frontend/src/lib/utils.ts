@@ -7,10 +7,17 @@ export interface User {
   name: string,
 }
 
+export type Role = "viewer" | "commenter" | "editor" | "owner"
+
+export interface Permission {
+  user: Id,
+  role: Role,
+}
+
 export interface Doc {
   _id: Id,
   author: User,
-  collaborators: Id[],
+  permissions: Permission[],
   title: string,
   content: string,
   type: string,
@@ -24,7 +31,8 @@ export interface InsertUpdate{
         update:"insert",
         data:string
     },
-    timestamp:string
+    timestamp:string,
+    revision?:number
 }
 
 export interface DeleteUpdate{
@@ -33,7 +41,8 @@ export interface DeleteUpdate{
         update:"delete",
         length:number
     },
-    timestamp:string
+    timestamp:string,
+    revision?:number
 }
 
 export interface LocationState{
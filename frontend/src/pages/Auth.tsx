@@ -2,7 +2,7 @@ import React, { useEffect, useState } from 'react';
 import { FileText, Mail, Lock, User, Eye, EyeOff, ArrowRight, Loader2, CheckCircle2 } from 'lucide-react';
 import { useLocation, useNavigate } from 'react-router-dom';
 import toast, { ErrorIcon } from 'react-hot-toast';
-import api from '../lib/api';
+import api, { BaseUrl } from '../lib/api';
 
 export default function DocslyAuth() {
   const [isSignUp, setIsSignUp] = useState(false);
@@ -16,6 +16,7 @@ export default function DocslyAuth() {
     password: ''
   });
   const [focusedField, setFocusedField] = useState('');
+  const [providers, setProviders] = useState<{ name: string, label: string }[]>([]);
 
   const handleModeSwitch = (signUpMode: boolean) => {
     setIsTransitioning(true);
@@ -32,7 +33,19 @@ export default function DocslyAuth() {
     try {
       if (!isSignUp) {
         const { email, password } = { ...formData }
-        const res = await api.post<{ token: string, success: boolean }>("/auth/login", { email, password })
+        let res = await api.post<{ token: string, success: boolean, two_factor?: boolean, challenge?: string }>("/auth/login", { email, password })
+        if (res.data.two_factor) {
+          toast.dismiss(toastId)
+          const code = window.prompt("Enter the code from your authenticator app, or a recovery code")
+          if (!code) {
+            return
+          }
+          toast.loading("Checking code...", {
+            id: toastId,
+            icon: <Loader2 className="animate-spin" />,
+          })
+          res = await api.post<{ token: string, success: boolean }>("/auth/login/2fa", { challenge: res.data.challenge, code })
+        }
         if (res.data.success) {
           // Success Toast
           localStorage.setItem("docsly_token", res.data.token)
@@ -84,6 +97,35 @@ export default function DocslyAuth() {
     }
   }, [location.state])
 
+  useEffect(() => {
+    api.get<{ providers: { name: string, label: string }[] }>("/auth/oidc")
+      .then((res) => setProviders(res.data.providers))
+      .catch(console.error)
+  }, [])
+
+  // single sign-on comes back here with an error, or a second factor to ask for
+  useEffect(() => {
+    const params = new URLSearchParams(location.search)
+    const error = params.get("error")
+    const challenge = params.get("challenge")
+    if (error) {
+      toast.error(error)
+    }
+    if (challenge) {
+      const code = window.prompt("Enter the code from your authenticator app, or a recovery code")
+      if (!code) {
+        return
+      }
+      api.post<{ success: boolean }>("/auth/login/2fa", { challenge, code })
+        .then((res) => {
+          if (res.data.success) {
+            navigate(params.get("redirect") ?? "/dashboard", { replace: true })
+          }
+        })
+        .catch(() => toast.error("Invalid code, please sign in again"))
+    }
+  }, [location.search, navigate])
+
   const handleChange = (e: React.ChangeEvent<HTMLInputElement>) => {
     setFormData({
       ...formData,
@@ -197,6 +239,19 @@ export default function DocslyAuth() {
               </p>
             </div>
 
+            {/* Single sign-on */}
+            {providers.length > 0 && (
+              <div className="space-y-3 mb-8">
+                {providers.map((p) => (
+                  <a key={p.name} href={`${BaseUrl}/api/auth/oidc/${p.name}`} className="w-full flex items-center justify-center space-x-3 py-3 px-4 bg-white border-2 border-gray-200 rounded-xl hover:border-purple-300 hover:shadow-md transition-all group">
+                    <span className="font-semibold text-gray-700 group-hover:text-purple-600 transition-colors">
+                      Continue with {p.label}
+                    </span>
+                  </a>
+                ))}
+              </div>
+            )}
+
             {/* Social login buttons */}
             {/* <div className="space-y-3 mb-8">
               <button className="w-full flex items-center justify-center space-x-3 py-3 px-4 bg-white border-2 border-gray-200 rounded-xl hover:border-purple-300 hover:shadow-md transition-all group">
@@ -309,7 +364,10 @@ export default function DocslyAuth() {
                     <input type="checkbox" className="w-4 h-4 text-purple-600 border-gray-300 rounded focus:ring-purple-500" />
                     <span className="text-gray-700 group-hover:text-purple-600 transition-colors">Remember me</span>
                   </label>
-                  <button className="text-purple-600 hover:text-purple-700 font-semibold">
+                  <button
+                    onClick={() => navigate("/reset-password")}
+                    className="text-purple-600 hover:text-purple-700 font-semibold"
+                  >
                     Forgot password?
                   </button>
                 </div>
@@ -104,7 +104,7 @@ export default function Dashboard() {
   const onCreate = useCallback(async (type: string, name: string) => {
     const toastId = toast.loading("Creating Document... Please wait")
     try {
-      const res = await api.post<{ success: boolean, message: string }>("/doc/create", { title: name, type, collaborators: [] })
+      const res = await api.post<{ success: boolean, message: string }>("/doc/create", { title: name, type })
       if (res.data.success) {
         getUserDocs()
         setTimeout(() => {
@@ -461,9 +461,9 @@ export default function Dashboard() {
                           <td className="px-6 py-4">
                             <div className="flex items-center space-x-1 text-gray-600">
                               <Users className="w-4 h-4" />
-                              {doc.collaborators.map(x => {
+                              {doc.permissions.map(x => {
                                 return (
-                                  <span>{x.$oid}</span>
+                                  <span>{x.user.$oid}</span>
                                 )
                               })}
                             </div>
@@ -520,9 +520,9 @@ export default function Dashboard() {
                             <td className="px-6 py-4">
                               <div className="flex items-center space-x-1 text-gray-600">
                                 <Users className="w-4 h-4" />
-                                {doc.collaborators.map(x => {
+                                {doc.permissions.map(x => {
                                   return (
-                                    <span>{x.$oid}</span>
+                                    <span>{x.user.$oid}</span>
                                   )
                                 })}
                               </div>
@@ -6,6 +6,7 @@ import { useNavigate, useParams } from 'react-router-dom';
 import useAuthGuard from '../context/auth/useAuthGuard';
 import { applyRemoteUpdate, type DeleteUpdate, type Doc, type InsertUpdate, type User } from '../lib/utils';
 import { useBatchUpdates } from '../lib/batchUpdate';
+import { transformAll, type ServerMessage, type Update } from '../lib/ot';
 import api from '../lib/api';
 const baseUrl = import.meta.env.VITE_BACKEND_URL as string
 
@@ -22,6 +23,16 @@ export default function Edit() {
   const editorRef = useRef<HTMLTextAreaElement>(null);
   const lastContentRef = useRef('');
   const cursorPositionRef = useRef(0);
+  // last revision of the doc the server told us about
+  const revisionRef = useRef(0);
+  // the update sent and not acked yet, which transforming can split in pieces
+  // or cancel out, while the server still acks it once
+  const awaitingRef = useRef<Update[]>([]);
+  const inFlightRef = useRef<number | null>(null);
+  // local updates waiting for the one in flight to be acked
+  const bufferRef = useRef<Update[]>([]);
+  const nextOpIdRef = useRef(1);
+  const flushRef = useRef<() => void>(() => {});
   const navigate = useNavigate()
   const { docId } = useParams<{ docId: string }>()
 
@@ -38,6 +49,20 @@ export default function Edit() {
     f()
   }, [fetchDoc])
 
+  // Send the oldest buffered update once the previous one is acked
+  const sendNext = useCallback(() => {
+    const ws = wsRef.current
+    const next = bufferRef.current[0]
+    if (inFlightRef.current !== null || !next || ws?.readyState !== WebSocket.OPEN) {
+      return
+    }
+    const id = nextOpIdRef.current++
+    inFlightRef.current = id
+    awaitingRef.current = [next]
+    bufferRef.current = bufferRef.current.slice(1)
+    ws.send(JSON.stringify({ type: "op", id, update: { ...next, revision: revisionRef.current } }))
+  }, [])
+
   // Initialize WebSocket connection
   const connectWebSocket = useCallback(async () => {
     try {
@@ -47,7 +72,7 @@ export default function Edit() {
       } else {
         url = "ws://localhost:7878/api/doc/edit/"
       }
-      const ws = new WebSocket(url + docId);
+      const ws = new WebSocket(url + docId + "?protocol=1");
       ws.onopen = (_) => {
         console.log("Connected to Websocket");
         setIsConnected(true);
@@ -62,15 +87,54 @@ export default function Edit() {
         // setTimeout(connectWebSocket,2000)
       }
       ws.onmessage = (msg) => {
-        const update = JSON.parse(msg.data) as InsertUpdate | DeleteUpdate
-        setContent(prev => applyRemoteUpdate(prev, update))
+        const message = JSON.parse(msg.data) as ServerMessage
+        switch (message.type) {
+          case "sync":
+            revisionRef.current = message.revision
+            awaitingRef.current = []
+            bufferRef.current = []
+            inFlightRef.current = null
+            lastContentRef.current = message.content
+            setContent(message.content)
+            break
+          case "ack":
+            if (message.id !== inFlightRef.current) {
+              break
+            }
+            revisionRef.current = message.revision
+            awaitingRef.current = []
+            inFlightRef.current = null
+            sendNext()
+            break
+          case "op": {
+            if (!message.update) {
+              break
+            }
+            // edits still being batched were made before this op reached us
+            flushRef.current()
+            const [awaiting, afterAwaiting] = transformAll(awaitingRef.current, [message.update])
+            const [buffer, remote] = transformAll(bufferRef.current, afterAwaiting)
+            awaitingRef.current = awaiting
+            bufferRef.current = buffer
+            revisionRef.current = Math.max(revisionRef.current, message.update.revision ?? 0)
+            setContent(prev => remote.reduce(applyRemoteUpdate, prev))
+            break
+          }
+          case "error":
+            console.error(message.message)
+            if (message.id !== undefined && message.id === inFlightRef.current) {
+              // our edit was rejected, start over from the server's copy
+              ws.send(JSON.stringify({ type: "sync" }))
+            }
+            break
+        }
       }
       wsRef.current = ws;
     } catch (error) {
       console.error('WebSocket connection error:', error);
       setIsConnected(false);
     }
-  }, [docId])
+  }, [docId, sendNext])
 
   useEffect(() => {
     guard()
@@ -84,14 +148,16 @@ export default function Edit() {
       }
     };
   }, [connectWebSocket, guard, navigate]);
-  const sendUpdate = useCallback((update: InsertUpdate | DeleteUpdate) => {
-    if (isConnected) {
-      wsRef.current?.send(JSON.stringify(update));
-    }
-  }, [isConnected])
+  const sendUpdate = useCallback((update: Update) => {
+    bufferRef.current = [...bufferRef.current, update]
+    sendNext()
+  }, [sendNext])
 
   //Batch Updates
-  const { queueUpdate } = useBatchUpdates(sendUpdate)
+  const { queueUpdate, flush } = useBatchUpdates(sendUpdate)
+  useEffect(() => {
+    flushRef.current = flush
+  }, [flush])
 
   // Debounced SendUpdate
   // const pendingInsertRef = useRef<string>("");
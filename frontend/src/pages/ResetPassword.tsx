@@ -0,0 +1,149 @@
+import { useState } from 'react';
+import { FileText, Mail, Lock, ArrowRight, Loader2, CheckCircle2 } from 'lucide-react';
+import { useNavigate, useSearchParams } from 'react-router-dom';
+import toast, { ErrorIcon } from 'react-hot-toast';
+import { AxiosError } from 'axios';
+import api from '../lib/api';
+
+// Asks for an email to send a reset link to, or for a new password when opened from that link
+export default function ResetPassword() {
+  const [searchParams] = useSearchParams()
+  const token = searchParams.get("token")
+  const navigate = useNavigate()
+  const [email, setEmail] = useState('')
+  const [password, setPassword] = useState('')
+  const [confirm, setConfirm] = useState('')
+  const [sent, setSent] = useState(false)
+
+  const requestLink = async () => {
+    const toastId = toast.loading("Sending link...", {
+      icon: <Loader2 className="animate-spin" />,
+    })
+    try {
+      await api.post("/auth/password/forgot", { email })
+      setSent(true)
+      toast.success("If an account uses that email, a reset link is on its way", {
+        id: toastId,
+        icon: <CheckCircle2 className="text-green-500" />,
+        duration: 3000,
+      })
+    } catch (e) {
+      console.error(e)
+      toast.error("An error occurred, please try again", {
+        id: toastId,
+        icon: <ErrorIcon className="text-red-500" />,
+      })
+    }
+  }
+
+  const resetPassword = async () => {
+    if (password !== confirm) {
+      toast.error("Passwords do not match")
+      return
+    }
+    const toastId = toast.loading("Saving password...", {
+      icon: <Loader2 className="animate-spin" />,
+    })
+    try {
+      await api.post("/auth/password/reset", { token, password })
+      toast.success("Password changed, sign in with it", {
+        id: toastId,
+        icon: <CheckCircle2 className="text-green-500" />,
+        duration: 2000,
+      })
+      navigate("/auth", { replace: true })
+    } catch (e) {
+      console.error(e)
+      const message = e instanceof AxiosError ? e.response?.data?.err : undefined
+      toast.error(message ?? "An error occurred, please try again", {
+        id: toastId,
+        icon: <ErrorIcon className="text-red-500" />,
+      })
+    }
+  }
+
+  return (
+    <div className="min-h-screen bg-linear-to-br from-blue-50 via-purple-50 to-pink-50 relative overflow-hidden flex items-center justify-center p-4">
+      <div className="absolute top-20 left-10 w-72 h-72 bg-yellow-200 rounded-full mix-blend-multiply filter blur-xl opacity-40 animate-pulse" />
+      <div className="absolute top-40 right-10 w-72 h-72 bg-purple-200 rounded-full mix-blend-multiply filter blur-xl opacity-40 animate-pulse" style={{ animationDelay: '1s' }} />
+
+      <div className="relative z-10 w-full max-w-md">
+        <div className="bg-white/80 backdrop-blur-sm rounded-3xl shadow-2xl p-8 md:p-12 border border-purple-100">
+          <div className="flex items-center justify-center space-x-2 mb-8">
+            <div className="bg-linear-to-br from-purple-500 to-pink-500 p-2 rounded-xl">
+              <FileText className="w-6 h-6 text-white" />
+            </div>
+            <span className="text-2xl font-bold bg-linear-to-r from-purple-600 to-pink-600 bg-clip-text text-transparent">
+              Docsly
+            </span>
+          </div>
+
+          <h2 className="text-3xl font-bold text-gray-800 mb-2">
+            {token ? 'Choose a new password' : 'Reset your password'}
+          </h2>
+          <p className="text-gray-600 mb-8">
+            {token
+              ? 'Every device signed in to your account will be signed out.'
+              : "Enter your email and we'll send you a link to reset your password."}
+          </p>
+
+          <div className="space-y-5">
+            {token ? (
+              <>
+                <div className="relative">
+                  <Lock className="absolute left-4 top-1/2 transform -translate-y-1/2 w-5 h-5 text-gray-400" />
+                  <input
+                    type="password"
+                    value={password}
+                    onChange={(e) => setPassword(e.target.value)}
+                    placeholder="New password"
+                    className="w-full pl-12 pr-4 py-3 border-2 border-gray-200 rounded-xl focus:outline-none focus:border-purple-500 hover:border-purple-300 transition-all"
+                  />
+                </div>
+                <div className="relative">
+                  <Lock className="absolute left-4 top-1/2 transform -translate-y-1/2 w-5 h-5 text-gray-400" />
+                  <input
+                    type="password"
+                    value={confirm}
+                    onChange={(e) => setConfirm(e.target.value)}
+                    placeholder="Repeat the new password"
+                    className="w-full pl-12 pr-4 py-3 border-2 border-gray-200 rounded-xl focus:outline-none focus:border-purple-500 hover:border-purple-300 transition-all"
+                  />
+                </div>
+              </>
+            ) : (
+              <div className="relative">
+                <Mail className="absolute left-4 top-1/2 transform -translate-y-1/2 w-5 h-5 text-gray-400" />
+                <input
+                  type="email"
+                  value={email}
+                  onChange={(e) => setEmail(e.target.value)}
+                  placeholder="you@example.com"
+                  className="w-full pl-12 pr-4 py-3 border-2 border-gray-200 rounded-xl focus:outline-none focus:border-purple-500 hover:border-purple-300 transition-all"
+                />
+              </div>
+            )}
+
+            <button
+              onClick={token ? resetPassword : requestLink}
+              disabled={token ? !password : !email || sent}
+              className="w-full py-4 bg-linear-to-r from-purple-600 to-pink-600 text-white rounded-xl font-semibold hover:shadow-2xl transform hover:scale-[1.02] transition-all flex items-center justify-center space-x-2 group disabled:opacity-60 disabled:hover:scale-100"
+            >
+              <span>{token ? 'Save Password' : sent ? 'Link Sent' : 'Send Reset Link'}</span>
+              <ArrowRight className="w-5 h-5 group-hover:translate-x-1 transition-transform" />
+            </button>
+          </div>
+
+          <p className="text-center mt-6 text-gray-600">
+            <button
+              onClick={() => navigate("/auth")}
+              className="text-purple-600 font-semibold hover:text-purple-700 transition-colors"
+            >
+              Back to Sign In
+            </button>
+          </p>
+        </div>
+      </div>
+    </div>
+  );
+}
@@ -0,0 +1,82 @@
+import { useEffect, useRef, useState } from 'react';
+import { FileText, ArrowRight, Loader2, CheckCircle2, XCircle } from 'lucide-react';
+import { useNavigate, useSearchParams } from 'react-router-dom';
+import { AxiosError } from 'axios';
+import api from '../lib/api';
+
+type Status = { state: "verifying" } | { state: "verified" } | { state: "failed", message: string }
+
+// Confirms the email address from the link sent after sign up
+export default function VerifyEmail() {
+  const [searchParams] = useSearchParams()
+  const token = searchParams.get("token")
+  const navigate = useNavigate()
+  const [status, setStatus] = useState<Status>(
+    token ? { state: "verifying" } : { state: "failed", message: "This link is missing its token" }
+  )
+  // tokens are single use, so don't send it twice when effects run twice in development
+  const sent = useRef(false)
+
+  useEffect(() => {
+    if (!token || sent.current) return
+    sent.current = true
+    api.post("/auth/verify", { token })
+      .then(() => setStatus({ state: "verified" }))
+      .catch((e) => {
+        console.error(e)
+        const message = e instanceof AxiosError ? e.response?.data?.err : undefined
+        setStatus({ state: "failed", message: message ?? "An error occurred, please try again" })
+      })
+  }, [token])
+
+  return (
+    <div className="min-h-screen bg-linear-to-br from-blue-50 via-purple-50 to-pink-50 relative overflow-hidden flex items-center justify-center p-4">
+      <div className="absolute top-20 left-10 w-72 h-72 bg-yellow-200 rounded-full mix-blend-multiply filter blur-xl opacity-40 animate-pulse" />
+      <div className="absolute top-40 right-10 w-72 h-72 bg-purple-200 rounded-full mix-blend-multiply filter blur-xl opacity-40 animate-pulse" style={{ animationDelay: '1s' }} />
+
+      <div className="relative z-10 w-full max-w-md">
+        <div className="bg-white/80 backdrop-blur-sm rounded-3xl shadow-2xl p-8 md:p-12 border border-purple-100 text-center">
+          <div className="flex items-center justify-center space-x-2 mb-8">
+            <div className="bg-linear-to-br from-purple-500 to-pink-500 p-2 rounded-xl">
+              <FileText className="w-6 h-6 text-white" />
+            </div>
+            <span className="text-2xl font-bold bg-linear-to-r from-purple-600 to-pink-600 bg-clip-text text-transparent">
+              Docsly
+            </span>
+          </div>
+
+          {status.state === "verifying" && (
+            <>
+              <Loader2 className="w-12 h-12 text-purple-500 animate-spin mx-auto mb-4" />
+              <h2 className="text-2xl font-bold text-gray-800">Verifying your email...</h2>
+            </>
+          )}
+          {status.state === "verified" && (
+            <>
+              <CheckCircle2 className="w-12 h-12 text-green-500 mx-auto mb-4" />
+              <h2 className="text-2xl font-bold text-gray-800 mb-2">Email verified</h2>
+              <p className="text-gray-600">Documents shared with this address are now in your dashboard.</p>
+            </>
+          )}
+          {status.state === "failed" && (
+            <>
+              <XCircle className="w-12 h-12 text-red-500 mx-auto mb-4" />
+              <h2 className="text-2xl font-bold text-gray-800 mb-2">Couldn't verify your email</h2>
+              <p className="text-gray-600">{status.message}</p>
+            </>
+          )}
+
+          {status.state !== "verifying" && (
+            <button
+              onClick={() => navigate("/dashboard")}
+              className="w-full mt-8 py-4 bg-linear-to-r from-purple-600 to-pink-600 text-white rounded-xl font-semibold hover:shadow-2xl transform hover:scale-[1.02] transition-all flex items-center justify-center space-x-2 group"
+            >
+              <span>Go to Dashboard</span>
+              <ArrowRight className="w-5 h-5 group-hover:translate-x-1 transition-transform" />
+            </button>
+          )}
+        </div>
+      </div>
+    </div>
+  );
+}
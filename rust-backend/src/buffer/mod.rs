@@ -0,0 +1,190 @@
+use std::{env, mem, time::Duration};
+
+use mongodb::bson::oid::ObjectId;
+
+use crate::{
+    crdt::{CrdtOp, Rga},
+    db::Db,
+    history,
+    models::{Change, Error, IntoObjectId, Snapshot, Update},
+    text::{self, AppliedUpdate, PositionUnit},
+};
+
+/// Seconds between flushes of dirty buffers when `BUFFER_FLUSH_INTERVAL` is unset
+const DEFAULT_FLUSH_INTERVAL: u64 = 5;
+
+/// Authoritative in-memory copy of a doc that is being edited
+pub struct DocBuffer {
+    pub id: ObjectId,
+    pub content: String,
+    pub revision: u64,
+    pub crdt: Option<Rga>,
+    dirty: bool,
+    changes: Vec<Change>,
+    /// Revision of the latest stored snapshot
+    snapshot_revision: u64,
+}
+
+impl DocBuffer {
+    /// Load the stored state of a doc
+    pub async fn load(db: &Db, doc_id: &str) -> Result<Self, Error> {
+        let doc = db.find_doc_with_id(doc_id).await?;
+        let crdt = db.find_crdt_state(doc_id).await?;
+        let snapshot = db.find_snapshot(doc_id, doc.revision).await?;
+        let id = doc_id.into_objetc_id();
+        let mut buffer = DocBuffer {
+            id,
+            content: doc.content,
+            revision: doc.revision,
+            crdt,
+            dirty: false,
+            changes: Vec::new(),
+            snapshot_revision: snapshot.map_or(0, |s| s.revision),
+        };
+        // docs that predate change tracking start their history from what they hold now
+        if buffer.revision == 0 && !buffer.content.is_empty() {
+            let author = doc.author.and_then(|a| a.id);
+            buffer.revision = 1;
+            buffer.dirty = true;
+            buffer
+                .changes
+                .push(Change::baseline(id, author, &buffer.content));
+        }
+        Ok(buffer)
+    }
+
+    /// Crdt state of the doc, seeding it from the current content the first time
+    pub fn crdt(&mut self) -> &Rga {
+        if self.crdt.is_none() {
+            self.dirty = true;
+        }
+        self.crdt
+            .get_or_insert_with(|| Rga::from_text(&self.content))
+    }
+
+    /// Apply a position update counted in `unit` under the next revision,
+    /// returning the ops crdt clients need to replay it
+    pub fn apply_update(
+        &mut self,
+        mut update: Update,
+        unit: PositionUnit,
+    ) -> Result<(AppliedUpdate, Vec<CrdtOp>), Error> {
+        update.revision = Some(self.revision + 1);
+        let (content, applied) = text::apply(&self.content, &update, unit)?;
+        let ops = match self.crdt.as_mut() {
+            Some(rga) => rga.apply_update(&applied.scalar)?,
+            None => vec![],
+        };
+        self.commit(content, &applied);
+        Ok((applied, ops))
+    }
+
+    /// Drop crdt tombstones, marking the buffer dirty so the smaller state is
+    /// saved. Callers make sure no crdt client is connected.
+    pub fn collect_garbage(&mut self) {
+        if let Some(rga) = self.crdt.as_mut()
+            && rga.collect_garbage() > 0
+        {
+            self.dirty = true;
+        }
+    }
+
+    /// Merge a crdt op, returning the update it amounts to if the text changed
+    pub fn apply_crdt(
+        &mut self,
+        op: &CrdtOp,
+        template: &Update,
+    ) -> Result<Option<AppliedUpdate>, Error> {
+        let Some(rga) = self.crdt.as_mut() else {
+            return Err(Error::new("doc is not crdt backed"));
+        };
+        let Some(update) = rga.apply(op)? else {
+            return Ok(None);
+        };
+        let update = Update {
+            revision: Some(self.revision + 1),
+            update_type: update.update_type,
+            position: update.position,
+            ..template.clone()
+        };
+        let (content, applied) = text::apply(&self.content, &update, PositionUnit::Scalar)?;
+        self.commit(content, &applied);
+        Ok(Some(applied))
+    }
+
+    /// Write the buffer back to the docs collection if it changed, keeping it
+    /// dirty so the next flush retries if that fails
+    pub async fn flush(&mut self, db: &Db) -> Result<(), Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let doc_id = self.id.to_hex();
+        let changes = mem::take(&mut self.changes);
+        let snapshot = (self.revision >= self.snapshot_revision + history::snapshot_interval())
+            .then(|| Snapshot::new(self.id, self.revision, self.content.clone()));
+        let mut res = write(
+            db,
+            &doc_id,
+            &self.content,
+            self.revision,
+            self.crdt.as_ref(),
+            changes,
+        )
+        .await;
+        if let (Ok(()), Some(snapshot)) = (&res, &snapshot) {
+            res = db.save_snapshot(snapshot).await.map_err(|e| (e, vec![]));
+        }
+        match res {
+            Ok(()) => {
+                self.dirty = false;
+                if snapshot.is_some() {
+                    self.snapshot_revision = self.revision;
+                }
+                Ok(())
+            }
+            Err((e, unsaved)) => {
+                self.changes.splice(0..0, unsaved);
+                Err(e)
+            }
+        }
+    }
+
+    fn commit(&mut self, content: String, applied: &AppliedUpdate) {
+        self.content = content;
+        self.revision += 1;
+        self.dirty = true;
+        self.changes.push(Change::new(self.id, &applied.scalar));
+    }
+}
+
+/// Time between flushes of a dirty buffer, from `BUFFER_FLUSH_INTERVAL`.
+/// Zero is ignored, a timer cannot fire that often.
+pub fn flush_interval() -> Duration {
+    let secs = env::var("BUFFER_FLUSH_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(DEFAULT_FLUSH_INTERVAL);
+    Duration::from_secs(secs)
+}
+
+async fn write(
+    db: &Db,
+    doc_id: &str,
+    content: &str,
+    revision: u64,
+    crdt: Option<&Rga>,
+    changes: Vec<Change>,
+) -> Result<(), (Error, Vec<Change>)> {
+    let mut state = db
+        .save_doc_state(doc_id, content, revision)
+        .await
+        .map(|_| ());
+    if let (Ok(()), Some(rga)) = (&state, crdt) {
+        state = db.save_crdt_state(doc_id, rga).await;
+    }
+    if let Err(e) = state {
+        return Err((e, changes));
+    }
+    db.record_changes(changes).await
+}
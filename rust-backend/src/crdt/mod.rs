@@ -0,0 +1,336 @@
+use std::{cmp::Ordering, collections::HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Error, Update, UpdateType};
+
+/// Site id used for characters created by the server itself
+pub const SERVER_SITE: &str = "server";
+
+/// Globally unique id of a character, a lamport clock plus the site that created it
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CharId {
+    pub clock: u64,
+    pub site: String,
+}
+
+impl Ord for CharId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.clock
+            .cmp(&other.clock)
+            .then_with(|| self.site.cmp(&other.site))
+    }
+}
+
+impl PartialOrd for CharId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Node {
+    pub id: CharId,
+    pub value: char,
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "lowercase", tag = "op")]
+pub enum CrdtOp {
+    /// Insert `value` right after `after`, or at the start when it is `None`
+    Insert {
+        id: CharId,
+        after: Option<CharId>,
+        value: char,
+    },
+    Delete {
+        id: CharId,
+    },
+}
+
+/// Replicated growable array, the sequence CRDT behind crdt edited docs.
+///
+/// Deleted characters are kept as tombstones so concurrent inserts can still
+/// find their anchor, until no replica that could refer to them is left.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Rga {
+    pub nodes: Vec<Node>,
+    pub clock: u64,
+    /// Ids of `nodes`, rebuilt whenever it falls out of step with them
+    #[serde(skip)]
+    ids: HashSet<CharId>,
+}
+
+impl Rga {
+    /// Seed a sequence from existing text, every character owned by the server
+    pub fn from_text(text: &str) -> Self {
+        let nodes: Vec<Node> = text
+            .chars()
+            .enumerate()
+            .map(|(i, value)| Node {
+                id: CharId {
+                    clock: i as u64 + 1,
+                    site: SERVER_SITE.to_string(),
+                },
+                value,
+                deleted: false,
+            })
+            .collect();
+        Rga {
+            clock: nodes.len() as u64,
+            ids: nodes.iter().map(|n| n.id.clone()).collect(),
+            nodes,
+        }
+    }
+
+    fn contains(&mut self, id: &CharId) -> bool {
+        if self.ids.len() != self.nodes.len() {
+            self.ids = self.nodes.iter().map(|n| n.id.clone()).collect();
+        }
+        self.ids.contains(id)
+    }
+
+    fn index_of(&mut self, id: &CharId) -> Option<usize> {
+        if !self.contains(id) {
+            return None;
+        }
+        self.nodes.iter().position(|n| n.id == *id)
+    }
+
+    fn visible_index(&self, index: usize) -> usize {
+        self.nodes[..index].iter().filter(|n| !n.deleted).count()
+    }
+
+    /// Index in `nodes` of the `position`th visible character
+    fn node_at(&self, position: usize) -> Option<usize> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| !n.deleted)
+            .nth(position)
+            .map(|(i, _)| i)
+    }
+
+    /// Integrate a remote operation.
+    ///
+    /// Returns the equivalent position update when the visible text changed, so
+    /// applying the same op twice is harmless.
+    pub fn apply(&mut self, op: &CrdtOp) -> Result<Option<Update>, Error> {
+        match op {
+            CrdtOp::Insert { id, after, value } => {
+                if self.contains(id) {
+                    return Ok(None);
+                }
+                let mut index = match after {
+                    Some(a) => self.index_of(a).ok_or("unknown insert anchor")? + 1,
+                    None => 0,
+                };
+                while index < self.nodes.len() && self.nodes[index].id > *id {
+                    index += 1;
+                }
+                self.clock = self.clock.max(id.clock);
+                self.ids.insert(id.clone());
+                self.nodes.insert(
+                    index,
+                    Node {
+                        id: id.clone(),
+                        value: *value,
+                        deleted: false,
+                    },
+                );
+                Ok(Some(Update {
+                    position: self.visible_index(index),
+                    from: None,
+                    update_type: UpdateType::Insert {
+                        data: value.to_string(),
+                    },
+                    timestamp: None,
+                    revision: None,
+                    op_id: None,
+                }))
+            }
+            CrdtOp::Delete { id } => {
+                let index = self.index_of(id).ok_or("unknown character")?;
+                if self.nodes[index].deleted {
+                    return Ok(None);
+                }
+                self.nodes[index].deleted = true;
+                Ok(Some(Update {
+                    position: self.visible_index(index),
+                    from: None,
+                    update_type: UpdateType::Delete { length: 1 },
+                    timestamp: None,
+                    revision: None,
+                    op_id: None,
+                }))
+            }
+        }
+    }
+
+    /// Drop the tombstones, returning how many there were. Only safe once no
+    /// replica is left that could still name a deleted character, since ops
+    /// anchored on one would no longer integrate.
+    pub fn collect_garbage(&mut self) -> usize {
+        let before = self.nodes.len();
+        self.nodes.retain(|n| !n.deleted);
+        self.ids = self.nodes.iter().map(|n| n.id.clone()).collect();
+        before - self.nodes.len()
+    }
+
+    /// Apply a position based update, returning the ops crdt clients need to
+    /// replay it
+    pub fn apply_update(&mut self, update: &Update) -> Result<Vec<CrdtOp>, Error> {
+        match update.update_type {
+            UpdateType::Insert { ref data } => {
+                let mut after = match update.position {
+                    0 => None,
+                    p => {
+                        let index = self.node_at(p - 1).ok_or("invalid input")?;
+                        Some(self.nodes[index].id.clone())
+                    }
+                };
+                let mut ops = Vec::new();
+                for value in data.chars() {
+                    self.clock += 1;
+                    let id = CharId {
+                        clock: self.clock,
+                        site: SERVER_SITE.to_string(),
+                    };
+                    let op = CrdtOp::Insert {
+                        id: id.clone(),
+                        after: after.replace(id),
+                        value,
+                    };
+                    self.apply(&op)?;
+                    ops.push(op);
+                }
+                Ok(ops)
+            }
+            UpdateType::Delete { length } => {
+                if update.position + length > self.nodes.iter().filter(|n| !n.deleted).count() {
+                    return Err("invalid input".into());
+                }
+                let mut ops = Vec::with_capacity(length);
+                for _ in 0..length {
+                    let index = self.node_at(update.position).ok_or("invalid input")?;
+                    self.nodes[index].deleted = true;
+                    ops.push(CrdtOp::Delete {
+                        id: self.nodes[index].id.clone(),
+                    });
+                }
+                Ok(ops)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(clock: u64, site: &str) -> CharId {
+        CharId {
+            clock,
+            site: site.to_string(),
+        }
+    }
+
+    fn insert(clock: u64, site: &str, after: Option<CharId>, value: char) -> CrdtOp {
+        CrdtOp::Insert {
+            id: id(clock, site),
+            after,
+            value,
+        }
+    }
+
+    fn text(rga: &Rga) -> String {
+        rga.nodes
+            .iter()
+            .filter(|n| !n.deleted)
+            .map(|n| n.value)
+            .collect()
+    }
+
+    #[test]
+    fn concurrent_ops_converge_in_any_order() {
+        let base = Rga::from_text("ac");
+        let ops = [
+            insert(3, "alice", Some(id(1, SERVER_SITE)), 'b'),
+            insert(3, "bob", Some(id(1, SERVER_SITE)), 'x'),
+            CrdtOp::Delete {
+                id: id(2, SERVER_SITE),
+            },
+            insert(4, "bob", Some(id(2, SERVER_SITE)), 'd'),
+        ];
+        let mut forward = base.clone();
+        for op in &ops {
+            forward.apply(op).unwrap();
+        }
+        let mut backward = base.clone();
+        for op in ops.iter().rev() {
+            backward.apply(op).unwrap();
+        }
+        assert_eq!(text(&forward), text(&backward));
+        assert_eq!(text(&forward), "axbd");
+    }
+
+    #[test]
+    fn applying_an_op_twice_is_harmless() {
+        let mut rga = Rga::from_text("ab");
+        let op = insert(3, "alice", Some(id(1, SERVER_SITE)), 'x');
+        assert!(rga.apply(&op).unwrap().is_some());
+        assert!(rga.apply(&op).unwrap().is_none());
+        let delete = CrdtOp::Delete {
+            id: id(2, SERVER_SITE),
+        };
+        assert!(rga.apply(&delete).unwrap().is_some());
+        assert!(rga.apply(&delete).unwrap().is_none());
+        assert_eq!(text(&rga), "ax");
+    }
+
+    #[test]
+    fn unknown_anchor_is_rejected() {
+        let mut rga = Rga::from_text("ab");
+        assert!(
+            rga.apply(&insert(3, "alice", Some(id(9, "bob")), 'x'))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn collect_garbage_drops_tombstones_only() {
+        let mut rga = Rga::from_text("abc");
+        rga.apply(&CrdtOp::Delete {
+            id: id(2, SERVER_SITE),
+        })
+        .unwrap();
+        assert_eq!(rga.collect_garbage(), 1);
+        assert_eq!(rga.nodes.len(), 2);
+        assert_eq!(text(&rga), "ac");
+        // new ids keep counting from where the clock was
+        let ops = rga
+            .apply_update(&Update {
+                position: 1,
+                from: None,
+                update_type: UpdateType::Insert {
+                    data: "b".to_string(),
+                },
+                timestamp: None,
+                revision: None,
+                op_id: None,
+            })
+            .unwrap();
+        assert!(matches!(&ops[0], CrdtOp::Insert { id, .. } if id.clock == 4));
+        assert_eq!(text(&rga), "abc");
+    }
+
+    #[test]
+    fn deserialized_state_still_deduplicates() {
+        let rga = Rga::from_text("ab");
+        let mut rga: Rga = serde_json::from_value(serde_json::to_value(&rga).unwrap()).unwrap();
+        let op = insert(1, SERVER_SITE, None, 'a');
+        assert!(rga.apply(&op).unwrap().is_none());
+    }
+}
@@ -2,26 +2,48 @@ use bson::DateTime;
 use futures::TryStreamExt;
 use mongodb::{
     Client, Collection, IndexModel,
-    bson::{self, doc},
-    options::IndexOptions,
+    bson::{self, doc, oid::ObjectId},
+    change_stream::{ChangeStream, event::ChangeStreamEvent},
+    error::{ErrorKind, InsertManyError, WriteFailure},
+    options::{Collation, CollationStrength, IndexOptions, ReturnDocument},
     results::{InsertOneResult, UpdateResult},
 };
-use std::env;
+use std::{env, time::Duration};
 
 use crate::{
+    crdt::Rga,
     models::{
-        self, Author, CollabRequest, Doc, Error, IntoObjectId, LoginUser, Update, UpdateType,
-        UploadedDoc,
+        self, Change, CollabRequest, CollabRequestQuery, CrdtState, Doc, EmailToken, Error,
+        Identity, IntoObjectId, Invitation, Lease, LoginChallenge, LoginUser, OidcLogin, Role,
+        RoutedPacket, Session, ShareLink, Snapshot, TokenPurpose, TwoFactor, UploadedDoc, Version,
     },
-    utils::{hash_password, verify_password_hash},
+    utils::{hash_password, normalize_email, verify_password_hash},
 };
 
+/// Collab requests listed at once when the client does not say
+const REQUEST_PAGE_SIZE: i64 = 20;
+/// Most collab requests listed at once
+const MAX_REQUEST_PAGE_SIZE: i64 = 100;
+
 pub struct Db {
     users: Collection<models::User>,
     docs: Collection<models::Doc>,
-    changes: Collection<models::Update>,
+    changes: Collection<models::Change>,
+    archived_changes: Collection<models::Change>,
+    snapshots: Collection<models::Snapshot>,
+    versions: Collection<models::Version>,
+    crdt_states: Collection<models::CrdtState>,
     requests: Collection<models::CollabRequest>,
+    invitations: Collection<models::Invitation>,
+    share_links: Collection<models::ShareLink>,
+    sessions: Collection<models::Session>,
+    email_tokens: Collection<models::EmailToken>,
+    login_challenges: Collection<models::LoginChallenge>,
+    oidc_logins: Collection<models::OidcLogin>,
+    identities: Collection<models::Identity>,
     uploads: Collection<models::UploadedDoc>,
+    leases: Collection<models::Lease>,
+    packets: Collection<models::RoutedPacket>,
 }
 
 impl Db {
@@ -42,9 +64,102 @@ impl Db {
                 log::error!("an error occurred email index");
             }
         }
+        // accounts are looked up by email regardless of case
+        let email_ci_index = IndexModel::builder()
+            .keys(doc! {"email":1})
+            .options(
+                IndexOptions::builder()
+                    .name("email_ci".to_string())
+                    .unique(true)
+                    .collation(case_insensitive())
+                    .build(),
+            )
+            .build();
+        match users.create_index(email_ci_index).await {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred case insensitive email index");
+            }
+        }
+        // accounts from before email verification are trusted as they were
+        let legacy = users
+            .update_many(
+                doc! {"email_verified":{"$exists":false}},
+                doc! {"$set":{"email_verified":true}},
+            )
+            .await;
+        match legacy {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred migrating email verification")
+            }
+        };
         let docs = database.collection::<models::Doc>("docs");
+        // docs from before roles list collaborators as bare ids, they were all editors
+        let legacy = docs
+            .update_many(
+                doc! {"collaborators":{"$exists":true}},
+                vec![
+                    doc! {"$set":{"permissions":{"$concatArrays":[
+                        {"$ifNull":["$permissions",[]]},
+                        {"$map":{
+                            "input":"$collaborators",
+                            "as":"user",
+                            "in":{"user":"$$user","role":"editor"}
+                        }}
+                    ]}}},
+                    doc! {"$unset":"collaborators"},
+                ],
+            )
+            .await;
+        match legacy {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred migrating collaborators")
+            }
+        };
         let uploads = database.collection::<models::UploadedDoc>("uploads");
-        let changes = database.collection::<models::Update>("changes");
+        let changes = database.collection::<models::Change>("changes");
+        let change_index = IndexModel::builder()
+            .keys(doc! {
+                "doc":1,
+                "revision":1
+            })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        match changes.create_index(change_index).await {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred change index")
+            }
+        };
+        let archived_changes = database.collection::<models::Change>("changes_archive");
+        let snapshots = database.collection::<models::Snapshot>("snapshots");
+        let snapshot_index = IndexModel::builder()
+            .keys(doc! {
+                "doc":1,
+                "revision":1
+            })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        match snapshots.create_index(snapshot_index).await {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred snapshot index")
+            }
+        };
+        let versions = database.collection::<models::Version>("versions");
+        let crdt_states = database.collection::<models::CrdtState>("crdt_states");
         let requests = database.collection::<models::CollabRequest>("collab_requests");
         let request_index = IndexModel::builder()
             .keys(doc! {
@@ -61,12 +176,206 @@ impl Db {
                 log::error!("an error occurred request index")
             }
         };
+        // requests used to store their time as a string, which cannot be filtered by age
+        let legacy = requests
+            .update_many(
+                doc! {"timestamp":{"$type":"string"}},
+                vec![doc! {"$set":{"timestamp":{"$toDate":"$timestamp"}}}],
+            )
+            .await;
+        match legacy {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred migrating request timestamps")
+            }
+        };
+        let invitations = database.collection::<models::Invitation>("invitations");
+        let invitation_index = IndexModel::builder()
+            .keys(doc! {
+                "doc":1,
+                "email":1
+            })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        match invitations.create_index(invitation_index).await {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred invitation index")
+            }
+        };
+        let share_links = database.collection::<models::ShareLink>("share_links");
+        // expired links are useless, let mongo clear them out
+        let share_link_index = IndexModel::builder()
+            .keys(doc! {"expires_at":1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        match share_links.create_index(share_link_index).await {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred share link index")
+            }
+        };
+        let sessions = database.collection::<models::Session>("sessions");
+        // sessions past their refresh token's expiry can never be used again
+        let session_index = IndexModel::builder()
+            .keys(doc! {"expires_at":1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        match sessions.create_index(session_index).await {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred session index")
+            }
+        };
+        let email_tokens = database.collection::<models::EmailToken>("email_tokens");
+        let email_token_index = IndexModel::builder()
+            .keys(doc! {"expires_at":1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        match email_tokens.create_index(email_token_index).await {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred email token index")
+            }
+        };
+        let login_challenges = database.collection::<models::LoginChallenge>("login_challenges");
+        let login_challenge_index = IndexModel::builder()
+            .keys(doc! {"expires_at":1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        match login_challenges.create_index(login_challenge_index).await {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred login challenge index")
+            }
+        };
+        let oidc_logins = database.collection::<models::OidcLogin>("oidc_logins");
+        let oidc_login_index = IndexModel::builder()
+            .keys(doc! {"expires_at":1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        match oidc_logins.create_index(oidc_login_index).await {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred oidc login index")
+            }
+        };
+        let identities = database.collection::<models::Identity>("identities");
+        let identity_index = IndexModel::builder()
+            .keys(doc! {
+                "provider":1,
+                "subject":1
+            })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        match identities.create_index(identity_index).await {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred identity index")
+            }
+        };
+        let leases = database.collection::<models::Lease>("leases");
+        let packets = database.collection::<models::RoutedPacket>("packets");
+        // packets are only needed until the node they are for has seen them
+        let packet_index = IndexModel::builder()
+            .keys(doc! {"created_at":1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(60))
+                    .build(),
+            )
+            .build();
+        match packets.create_index(packet_index).await {
+            Ok(r) => {
+                log::info!("{:?}", r);
+            }
+            Err(_) => {
+                log::error!("an error occurred packet index")
+            }
+        };
         Db {
             users,
             docs,
             changes,
+            archived_changes,
+            snapshots,
+            versions,
+            crdt_states,
             requests,
+            invitations,
+            share_links,
+            sessions,
+            email_tokens,
+            login_challenges,
+            oidc_logins,
+            identities,
             uploads,
+            leases,
+            packets,
+        }
+    }
+
+    ///Handle to a server that is never reached, for tests of the paths that
+    ///answer before going to the database
+    #[cfg(test)]
+    pub async fn unreachable() -> Self {
+        let client = Client::with_uri_str("mongodb://127.0.0.1:9").await.unwrap();
+        let database = client.database("docsly");
+        Db {
+            users: database.collection("users"),
+            docs: database.collection("docs"),
+            changes: database.collection("changes"),
+            archived_changes: database.collection("changes_archive"),
+            snapshots: database.collection("snapshots"),
+            versions: database.collection("versions"),
+            crdt_states: database.collection("crdt_states"),
+            requests: database.collection("collab_requests"),
+            invitations: database.collection("invitations"),
+            share_links: database.collection("share_links"),
+            sessions: database.collection("sessions"),
+            email_tokens: database.collection("email_tokens"),
+            login_challenges: database.collection("login_challenges"),
+            oidc_logins: database.collection("oidc_logins"),
+            identities: database.collection("identities"),
+            uploads: database.collection("uploads"),
+            leases: database.collection("leases"),
+            packets: database.collection("packets"),
         }
     }
 
@@ -74,13 +383,16 @@ impl Db {
 
     ///Create user
     pub async fn create_user(&self, mut user: models::User) -> Result<(), models::Error> {
-        if user.email == "a@a.com".to_string() {
+        if user.email == "a@a.com" {
             return Ok(());
         }
         let hashed_password = hash_password(user.password.as_bytes())?;
         user.password = hashed_password;
         user.doc_count = Some(0);
-        let res = self.users.insert_one(user).await;
+        user.email = normalize_email(&user.email);
+        user.email_verified = false;
+        user.two_factor = None;
+        let res = self.users.insert_one(&user).await;
         match res {
             Ok(r) => {
                 log::info!("{:?}", r);
@@ -89,6 +401,49 @@ impl Db {
             Err(e) => Err(e.into()),
         }
     }
+
+    ///Mark a user's email as verified, as long as it is still `email`
+    pub async fn verify_email(&self, user_id: impl IntoObjectId, email: &str) -> Result<(), Error> {
+        let user_id = user_id.into_objetc_id();
+        let res = self
+            .users
+            .update_one(
+                doc! {"_id":user_id, "email":normalize_email(email)},
+                doc! {"$set":{"email_verified":true}},
+            )
+            .collation(case_insensitive())
+            .await?;
+        if res.matched_count == 0 {
+            return Err(Error::from("email has changed since the token was sent"));
+        }
+        // invitations sent to the address now belong to its owner
+        self.invitations
+            .update_many(
+                doc! {"email":normalize_email(email), "user":null},
+                doc! {"$set":{"user":user_id}},
+            )
+            .await?;
+        Ok(())
+    }
+
+    ///Replace a user's password with an already hashed one
+    pub async fn set_password(
+        &self,
+        user_id: impl IntoObjectId,
+        password: &str,
+    ) -> Result<(), Error> {
+        let res = self
+            .users
+            .update_one(
+                doc! {"_id":user_id.into_objetc_id()},
+                doc! {"$set":{"password":password}},
+            )
+            .await?;
+        if res.matched_count == 0 {
+            return Err(Error::from("User Not Found"));
+        }
+        Ok(())
+    }
     ///Find user with id
     pub async fn find_user_with_id<T: IntoObjectId>(&self, id: &T) -> Result<models::User, String> {
         let res = self.users.find_one(doc! {"_id":id.into_objetc_id()}).await;
@@ -104,8 +459,9 @@ impl Db {
         let res = self
             .users
             .find_one(bson::doc! {
-                "email":&user.email,
+                "email":normalize_email(&user.email),
             })
+            .collation(case_insensitive())
             .await;
         match res {
             Ok(Some(u)) => Ok(u),
@@ -114,6 +470,15 @@ impl Db {
         }
     }
 
+    ///Find the user registered with `email` in any case, if any
+    pub async fn find_user_with_email(&self, email: &str) -> Result<Option<models::User>, Error> {
+        Ok(self
+            .users
+            .find_one(doc! {"email":normalize_email(email)})
+            .collation(case_insensitive())
+            .await?)
+    }
+
     //  Doc Collection
 
     pub async fn find_doc_with_id(&self, id: impl IntoObjectId) -> Result<Doc, Error> {
@@ -148,16 +513,25 @@ impl Db {
     pub async fn create_doc(&self, mut doc: models::Doc) -> Result<(), Error> {
         self.update_count_of_doc(&doc).await?;
         doc.last_update = Some(DateTime::now());
-        let res = self.docs.insert_one(doc).await;
+        doc.revision = if doc.content.is_empty() { 0 } else { 1 };
+        let res = self.docs.insert_one(&doc).await;
         match res {
             Ok(r) => {
                 log::info!("{:?}", r);
+                if let Some(id) = r.inserted_id.as_object_id()
+                    && doc.revision == 1
+                {
+                    let author = doc.author.and_then(|a| a.id);
+                    self.record_changes(vec![Change::baseline(id, author, &doc.content)])
+                        .await
+                        .map_err(|(e, _)| e)?;
+                }
                 Ok(())
             }
             Err(e) => Err(e.into()),
         }
     }
-    /// Update Doc
+    // Update Doc
     // pub async fn update_doc<T: IntoObjectId>(&self,doc_id: &T, update: models::Update) {
     //     let id = doc_id.into_objetc_id();
     //     let res = self
@@ -206,191 +580,1192 @@ impl Db {
             .docs
             .find(doc! {"$or":[
                 {"author.id":user_id.into_objetc_id()},
-                {"collaborators":{"$in":[user_id.into_objetc_id()]}}
+                {"permissions.user":user_id.into_objetc_id()}
             ]})
             .await?;
         Ok(res.try_collect().await?)
     }
 
-    ///Handle Updates
-    pub async fn handle_update<T: IntoObjectId>(
+    ///Persist the content of a doc as of `revision`
+    pub async fn save_doc_state(
         &self,
-        doc_id: T,
-        update: Update,
+        doc_id: impl IntoObjectId,
+        content: &str,
+        revision: u64,
     ) -> Result<UpdateResult, Error> {
-        match update.update_type {
-            UpdateType::Insert { ref data } => {
-                match self
-                    .docs
-                    .find_one(doc! {"_id":doc_id.into_objetc_id()})
-                    .await
-                {
-                    Ok(Some(doc)) => {
-                        let old_data = doc.content;
-                        let new_data = if update.position == 0 {
-                            data.to_owned() + &old_data
-                        } else {
-                            old_data[..update.position].to_owned()
-                                + data
-                                + &old_data[update.position - 1..]
-                        };
-                        match self
-                            .docs
-                            .update_one(
-                                doc! {"_id":doc_id.into_objetc_id()},
-                                doc! {
-                                    "$set":{
-                                        "content":new_data,
-                                        "last_update":DateTime::now()
-                                    }
-                                },
-                            )
-                            .await
-                        {
-                            Ok(r) => match self.changes.insert_one(update).await {
-                                Ok(_) => Ok(r),
-                                Err(e) => Err(e.into()),
-                            },
-                            Err(e) => Err(e.into()),
-                        }
+        Ok(self
+            .docs
+            .update_one(
+                doc! {"_id":doc_id.into_objetc_id()},
+                doc! {
+                    "$set":{
+                        "content":content,
+                        "last_update":DateTime::now(),
+                        "revision":revision as i64
                     }
-                    Ok(None) => Err(Error::from("doc not found")),
-                    Err(e) => Err(e.into()),
-                }
-            }
-            UpdateType::Delete { length } => {
-                let doc = self
-                    .docs
-                    .find_one(doc! {"_id":doc_id.into_objetc_id()})
-                    .await;
-                match doc {
-                    Ok(Some(document)) => {
-                        let content_len = document.content.len();
-                        let pos = update.position;
-                        if pos > content_len || (pos + length) > content_len {
-                            return Err("invalid input".into());
-                        }
-                        let new_data = document.content[..pos].to_owned()
-                            + &document.content[(pos + length)..];
-                        let res = self
-                            .docs
-                            .update_one(
-                                doc! {"_id":document.id},
-                                doc! {
-                                    "$set":{
-                                        "content":new_data
-                                    }
-                                },
-                            )
-                            .await;
-                        match res {
-                            Ok(i) => Ok(i),
-                            Err(e) => Err(e.into()),
+                },
+            )
+            .await?)
+    }
+
+    // Changes Collection
+
+    ///Record applied changes, skipping any an earlier attempt already recorded.
+    ///On failure hands back the changes that may not have been written. A
+    ///different change already recorded at the same revision is an error, and
+    ///the change that lost is not handed back as it can never be written.
+    pub async fn record_changes(&self, changes: Vec<Change>) -> Result<(), (Error, Vec<Change>)> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let e = match self.changes.insert_many(&changes).ordered(false).await {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        let Some((mut failed, duplicates)) = failed_inserts(&e) else {
+            return Err((e.into(), changes));
+        };
+        let mut conflicts = Vec::new();
+        if !duplicates.is_empty() {
+            match self.find_recorded(&changes, &duplicates).await {
+                Ok(recorded) => {
+                    for i in duplicates {
+                        let change = &changes[i];
+                        if !recorded.iter().any(|r| r.is_same(change)) {
+                            log::error!(
+                                "doc {} already has a different change at revision {}, dropping {:?}",
+                                change.doc,
+                                change.revision,
+                                change
+                            );
+                            conflicts.push(i);
                         }
                     }
-                    Ok(None) => Err(Error::new("document not found")),
-                    Err(e) => {
-                        log::error!("{}", e);
-                        Err(e.into())
-                    }
                 }
+                // can't tell them apart, write them again later
+                Err(_) => failed.extend(duplicates),
             }
         }
+        if failed.is_empty() && conflicts.is_empty() {
+            return Ok(());
+        }
+        let err = match conflicts.first() {
+            Some(&i) => Error::new(format!(
+                "doc {} already has a different change at revision {}",
+                changes[i].doc, changes[i].revision
+            )),
+            None => e.into(),
+        };
+        let unsaved = changes
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| failed.contains(i))
+            .map(|(_, c)| c)
+            .collect();
+        Err((err, unsaved))
     }
-    // Requests Collection
 
-    pub async fn get_collab_requests<T: IntoObjectId>(
+    ///Changes already recorded at the doc and revision of `changes[i]` for
+    ///each of `indexes`
+    async fn find_recorded(
         &self,
-        user_id: T,
-    ) -> Result<Vec<CollabRequest>, Error> {
-        let res = self
-            .requests
-            .find(doc! {"author":user_id.into_objetc_id()})
-            .await?;
-        let requests = res.try_collect().await?;
-        Ok(requests)
+        changes: &[Change],
+        indexes: &[usize],
+    ) -> Result<Vec<Change>, Error> {
+        let keys: Vec<_> = indexes
+            .iter()
+            .map(|&i| doc! {"doc":changes[i].doc, "revision":changes[i].revision as i64})
+            .collect();
+        Ok(self
+            .changes
+            .find(doc! {"$or":keys})
+            .await?
+            .try_collect()
+            .await?)
     }
 
-    pub async fn add_collab_request<T: IntoObjectId>(
+    ///Changes of a doc after revision `after` up to and including `upto`, oldest first
+    pub async fn find_changes(
         &self,
-        doc_id: T,
-        user_id: T,
-    ) -> Result<InsertOneResult, Error> {
-        let doc_id = doc_id.into_objetc_id();
-        let user_id = user_id.into_objetc_id();
-        match self
-            .docs
-            .find_one(doc! {"_id":doc_id.into_objetc_id()})
+        doc_id: impl IntoObjectId,
+        after: u64,
+        upto: u64,
+    ) -> Result<Vec<Change>, Error> {
+        Ok(self
+            .changes
+            .find(doc! {
+                "doc":doc_id.into_objetc_id(),
+                "revision":{"$gt":after as i64, "$lte":upto as i64}
+            })
+            .sort(doc! {"revision":1})
             .await?
-        {
-            Some(doc) => {
-                let req =
-                    CollabRequest::new(doc.author.as_ref().unwrap().id.unwrap(), user_id, doc_id);
-                if let Some(Author { id: Some(i), .. }) = doc.author {
-                    if i == user_id || doc.collaborators.contains(&user_id) {
-                        log::debug!("already author or a collaborator");
-                        return Ok(InsertOneResult::default());
-                    }
-                }
-                Ok(self.requests.insert_one(req).await?)
-            }
-            None => Err(Error::from("doc not found, invalid document id")),
-        }
+            .try_collect()
+            .await?)
     }
 
-    pub async fn handle_collab_request(&self, req: CollabRequest) -> Result<(), Error> {
-        match self.requests.find_one(doc! {"_id":req.id}).await? {
-            Some(r) => {
-                match self
-                    .docs
-                    .find_one_and_update(
-                        doc! {"_id":r.doc},
-                        doc! {"$push":{
-                        "collaborators":r.from
-                        }},
-                    )
-                    .await?
-                {
-                    Some(_d) => {
-                        match self.requests.find_one_and_delete(doc! {"_id":r.id}).await?{
-                            Some(re) => {
-                                log::debug!("accepted request: {:?}",re);
-                                Ok(())
-                            }
-                            None => {
-                                Err("an internal error".into())
-                            }
-                        }
-                    }
-                    None => Err("request not found".into()),
-                }
+    ///Revision a doc was at by `at`, the latest change made no later than it
+    pub async fn find_revision_at(
+        &self,
+        doc_id: impl IntoObjectId,
+        at: DateTime,
+    ) -> Result<u64, Error> {
+        let change = self
+            .changes
+            .find_one(doc! {
+                "doc":doc_id.into_objetc_id(),
+                "timestamp":{"$lte":at}
+            })
+            .sort(doc! {"revision":-1})
+            .await?;
+        Ok(change.map_or(0, |c| c.revision))
+    }
+
+    ///Docs that have changes older than `before`
+    pub async fn find_docs_with_changes_before(
+        &self,
+        before: DateTime,
+    ) -> Result<Vec<ObjectId>, Error> {
+        let ids = self
+            .changes
+            .distinct("doc", doc! {"timestamp":{"$lt":before}})
+            .await?;
+        Ok(ids.iter().filter_map(|id| id.as_object_id()).collect())
+    }
+
+    ///Remove the changes of a doc up to and including `revision`, copying them
+    ///to the archive first when `archive` is set
+    pub async fn compact_changes(
+        &self,
+        doc_id: impl IntoObjectId,
+        revision: u64,
+        archive: bool,
+    ) -> Result<u64, Error> {
+        let filter = doc! {
+            "doc":doc_id.into_objetc_id(),
+            "revision":{"$lte":revision as i64}
+        };
+        if archive {
+            let changes: Vec<Change> = self
+                .changes
+                .find(filter.clone())
+                .await?
+                .try_collect()
+                .await?;
+            // changes archived by an earlier run that failed before deleting
+            // them keep their ids, so they come back as duplicates
+            if !changes.is_empty()
+                && let Err(e) = self
+                    .archived_changes
+                    .insert_many(changes)
+                    .ordered(false)
+                    .await
+                && failed_inserts(&e).is_none_or(|(failed, _)| !failed.is_empty())
+            {
+                return Err(e.into());
             }
-            None => Err(Error::from("request not found, invalid id")),
         }
+        Ok(self.changes.delete_many(filter).await?.deleted_count)
     }
 
-    pub async fn reject_collab_request(&self, req: CollabRequest) -> Result<CollabRequest, Error> {
-        match self
-            .requests
-            .find_one_and_delete(doc! {"_id": req.id})
-            .await?
-        {
-            Some(r) => Ok(r),
-            None => Err("request not found".into()),
-        }
+    // Snapshots Collection
+
+    pub async fn save_snapshot(&self, snapshot: &Snapshot) -> Result<(), Error> {
+        self.snapshots
+            .replace_one(
+                doc! {"doc":snapshot.doc, "revision":snapshot.revision as i64},
+                snapshot,
+            )
+            .upsert(true)
+            .await?;
+        Ok(())
     }
 
-    // Uploads Collection
-    pub async fn upload_doc(&self, doc: UploadedDoc) -> Result<InsertOneResult, Error> {
-        Ok(self.uploads.insert_one(doc).await?)
+    ///Latest snapshot of a doc taken at or before `revision`
+    pub async fn find_snapshot(
+        &self,
+        doc_id: impl IntoObjectId,
+        revision: u64,
+    ) -> Result<Option<Snapshot>, Error> {
+        Ok(self
+            .snapshots
+            .find_one(doc! {
+                "doc":doc_id.into_objetc_id(),
+                "revision":{"$lte":revision as i64}
+            })
+            .sort(doc! {"revision":-1})
+            .await?)
     }
 
-    pub async fn get_uploads(&self, owner: impl IntoObjectId) -> Result<Vec<UploadedDoc>, Error> {
+    ///Snapshot taken by the latest compaction of a doc
+    pub async fn find_compaction(
+        &self,
+        doc_id: impl IntoObjectId,
+    ) -> Result<Option<Snapshot>, Error> {
         Ok(self
-            .uploads
-            .find(doc! {"owner":owner.into_objetc_id()})
-            .await?
-            .try_collect()
+            .snapshots
+            .find_one(doc! {
+                "doc":doc_id.into_objetc_id(),
+                "compacted_before":{"$exists":true}
+            })
+            .sort(doc! {"revision":-1})
             .await?)
     }
+
+    pub async fn delete_snapshots_before(
+        &self,
+        doc_id: impl IntoObjectId,
+        revision: u64,
+    ) -> Result<(), Error> {
+        self.snapshots
+            .delete_many(doc! {
+                "doc":doc_id.into_objetc_id(),
+                "revision":{"$lt":revision as i64}
+            })
+            .await?;
+        Ok(())
+    }
+
+    // Versions Collection
+
+    pub async fn create_version(&self, version: &Version) -> Result<InsertOneResult, Error> {
+        Ok(self.versions.insert_one(version).await?)
+    }
+
+    ///Versions of a doc, newest first and without their content
+    pub async fn find_versions(&self, doc_id: impl IntoObjectId) -> Result<Vec<Version>, Error> {
+        Ok(self
+            .versions
+            .find(doc! {"doc":doc_id.into_objetc_id()})
+            .projection(doc! {"content":0})
+            .sort(doc! {"created_at":-1})
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    pub async fn find_version(
+        &self,
+        doc_id: impl IntoObjectId,
+        version_id: impl IntoObjectId,
+    ) -> Result<Version, Error> {
+        match self
+            .versions
+            .find_one(doc! {
+                "_id":version_id.into_objetc_id(),
+                "doc":doc_id.into_objetc_id()
+            })
+            .await?
+        {
+            Some(v) => Ok(v),
+            None => Err(Error::from("version not found")),
+        }
+    }
+
+    // Crdt Collection
+
+    pub async fn find_crdt_state(&self, doc_id: impl IntoObjectId) -> Result<Option<Rga>, Error> {
+        let res = self
+            .crdt_states
+            .find_one(doc! {"_id":doc_id.into_objetc_id()})
+            .await?;
+        Ok(res.map(|s| s.rga))
+    }
+
+    ///Persist the crdt state of a doc
+    pub async fn save_crdt_state(&self, doc_id: impl IntoObjectId, rga: &Rga) -> Result<(), Error> {
+        let doc_id = doc_id.into_objetc_id();
+        let state = CrdtState {
+            doc: doc_id,
+            rga: rga.clone(),
+        };
+        self.crdt_states
+            .replace_one(doc! {"_id":doc_id}, state)
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    // Requests Collection
+
+    ///Requests to join the docs `user_id` owns, newest first, along with how
+    ///many match in total
+    pub async fn get_collab_requests<T: IntoObjectId>(
+        &self,
+        user_id: T,
+        query: &CollabRequestQuery,
+    ) -> Result<(Vec<CollabRequest>, u64), Error> {
+        let owned = self
+            .docs
+            .distinct("_id", doc! {"author.id":user_id.into_objetc_id()})
+            .await?;
+        let mut filter = doc! {"doc":{"$in":owned}};
+        if let Some(doc_id) = &query.doc {
+            let doc_id = ObjectId::parse_str(doc_id).map_err(|e| e.to_string())?;
+            filter = doc! {"$and":[filter, {"doc":doc_id}]};
+        }
+        let mut timestamp = doc! {};
+        if let Some(before) = query.before {
+            timestamp.insert("$lt", DateTime::from_chrono(before));
+        }
+        if let Some(after) = query.after {
+            timestamp.insert("$gt", DateTime::from_chrono(after));
+        }
+        if !timestamp.is_empty() {
+            filter.insert("timestamp", timestamp);
+        }
+        let total = self.requests.count_documents(filter.clone()).await?;
+        let limit = query
+            .limit
+            .unwrap_or(REQUEST_PAGE_SIZE)
+            .clamp(1, MAX_REQUEST_PAGE_SIZE);
+        let requests = self
+            .requests
+            .find(filter)
+            .sort(doc! {"timestamp":-1})
+            .skip(query.skip)
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await?;
+        Ok((requests, total))
+    }
+
+    ///A pending request to join a doc
+    pub async fn find_collab_request(
+        &self,
+        request_id: impl IntoObjectId,
+    ) -> Result<CollabRequest, Error> {
+        self.requests
+            .find_one(doc! {"_id":request_id.into_objetc_id()})
+            .await?
+            .ok_or(Error::from("request not found, invalid id"))
+    }
+
+    pub async fn add_collab_request<T: IntoObjectId>(
+        &self,
+        doc_id: T,
+        user_id: T,
+    ) -> Result<InsertOneResult, Error> {
+        let doc_id = doc_id.into_objetc_id();
+        let user_id = user_id.into_objetc_id();
+        match self
+            .docs
+            .find_one(doc! {"_id":doc_id.into_objetc_id()})
+            .await?
+        {
+            Some(doc) => {
+                let req =
+                    CollabRequest::new(doc.author.as_ref().unwrap().id.unwrap(), user_id, doc_id);
+                if doc.role(&user_id).is_some() {
+                    log::debug!("already author or a collaborator");
+                    return Ok(InsertOneResult::default());
+                }
+                Ok(self.requests.insert_one(req).await?)
+            }
+            None => Err(Error::from("doc not found, invalid document id")),
+        }
+    }
+
+    ///Let the sender of a request to join `doc` in as an editor
+    pub async fn handle_collab_request(&self, doc: &Doc, req: &CollabRequest) -> Result<(), Error> {
+        self.grant_role(doc, req.from, Role::Editor).await?;
+        match self
+            .requests
+            .find_one_and_delete(doc! {"_id":req.id})
+            .await?
+        {
+            Some(re) => {
+                log::debug!("accepted request: {:?}", re);
+                Ok(())
+            }
+            None => Err("request not found".into()),
+        }
+    }
+
+    ///Change the role of a user who already has access to a doc, `false` if they have none
+    pub async fn set_role(
+        &self,
+        doc_id: impl IntoObjectId,
+        user_id: impl IntoObjectId,
+        role: Role,
+    ) -> Result<bool, Error> {
+        let res = self
+            .docs
+            .update_one(
+                doc! {"_id":doc_id.into_objetc_id(), "permissions.user":user_id.into_objetc_id()},
+                doc! {"$set":{"permissions.$.role":bson::serialize_to_bson(&role)?}},
+            )
+            .await?;
+        Ok(res.matched_count > 0)
+    }
+
+    ///Take away a user's access to a doc, `false` if they had none
+    pub async fn remove_permission(
+        &self,
+        doc_id: impl IntoObjectId,
+        user_id: impl IntoObjectId,
+    ) -> Result<bool, Error> {
+        let user_id = user_id.into_objetc_id();
+        let res = self
+            .docs
+            .update_one(
+                doc! {"_id":doc_id.into_objetc_id(), "permissions.user":user_id},
+                doc! {"$pull":{"permissions":{"user":user_id}}},
+            )
+            .await?;
+        Ok(res.matched_count > 0)
+    }
+
+    ///Make `to`, who must already have access, the author of a doc owned by
+    ///`from`, who stays on as an editor. `false` if either no longer holds.
+    pub async fn transfer_ownership(
+        &self,
+        doc_id: impl IntoObjectId,
+        from: ObjectId,
+        to: &models::User,
+    ) -> Result<bool, Error> {
+        let Some(to_id) = to.id else {
+            return Err(Error::from("user has no id"));
+        };
+        let res = self
+            .docs
+            .update_one(
+                doc! {
+                    "_id":doc_id.into_objetc_id(),
+                    "author.id":from,
+                    "permissions.user":to_id
+                },
+                vec![doc! {"$set":{
+                    "author":{"id":to_id, "name":{"$literal":&to.name}},
+                    "permissions":{"$concatArrays":[
+                        {"$filter":{
+                            "input":"$permissions",
+                            "cond":{"$ne":["$$this.user", to_id]}
+                        }},
+                        [{"user":from, "role":"editor"}]
+                    ]}
+                }}],
+            )
+            .await?;
+        if res.matched_count == 0 {
+            return Ok(false);
+        }
+        self.users
+            .update_one(doc! {"_id":from}, doc! {"$inc":{"doc_count": -1}})
+            .await?;
+        self.users
+            .update_one(doc! {"_id":to_id}, doc! {"$inc":{"doc_count": 1}})
+            .await?;
+        Ok(true)
+    }
+
+    ///Give `user_id` at least `role` on a doc, keeping any higher role they have
+    pub async fn grant_role(&self, doc: &Doc, user_id: ObjectId, role: Role) -> Result<(), Error> {
+        let Some(doc_id) = doc.id else {
+            return Err(Error::from("doc not found"));
+        };
+        match doc.role(&user_id) {
+            Some(current) if current >= role => Ok(()),
+            Some(_) => self.set_role(doc_id, user_id, role).await.map(|_| ()),
+            None => {
+                self.docs
+                    .update_one(
+                        doc! {"_id":doc_id, "permissions.user":{"$ne":user_id}},
+                        doc! {"$push":{"permissions":{
+                            "user":user_id,
+                            "role":bson::serialize_to_bson(&role)?
+                        }}},
+                    )
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn reject_collab_request(&self, req: &CollabRequest) -> Result<CollabRequest, Error> {
+        match self
+            .requests
+            .find_one_and_delete(doc! {"_id": req.id})
+            .await?
+        {
+            Some(r) => Ok(r),
+            None => Err("request not found".into()),
+        }
+    }
+
+    // Invitations Collection
+
+    ///Invite `email` to `doc` with `role`, replacing any earlier invitation to the same address
+    pub async fn invite(
+        &self,
+        doc: &Doc,
+        email: &str,
+        role: Role,
+        invited_by: ObjectId,
+    ) -> Result<Invitation, Error> {
+        let Some(doc_id) = doc.id else {
+            return Err(Error::from("doc not found"));
+        };
+        let email = normalize_email(email);
+        // only an account that proved it owns the address gets the invitation
+        let user = self
+            .find_user_with_email(&email)
+            .await?
+            .filter(|u| u.email_verified)
+            .and_then(|u| u.id);
+        if user.is_some_and(|u| doc.role(&u).is_some()) {
+            return Err(Error::from("user already has access to the document"));
+        }
+        let invitation = self
+            .invitations
+            .find_one_and_update(
+                doc! {"doc":doc_id, "email":&email},
+                doc! {"$set":{
+                    "title":&doc.title,
+                    "role":bson::serialize_to_bson(&role)?,
+                    "invited_by":invited_by,
+                    "user":user,
+                    "created_at":DateTime::now()
+                }},
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await?;
+        invitation.ok_or(Error::from("could not create invitation"))
+    }
+
+    ///Pending invitations to a doc
+    pub async fn find_doc_invitations(
+        &self,
+        doc_id: impl IntoObjectId,
+    ) -> Result<Vec<Invitation>, Error> {
+        Ok(self
+            .invitations
+            .find(doc! {"doc":doc_id.into_objetc_id()})
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    ///Pending invitations of a user
+    pub async fn find_user_invitations(
+        &self,
+        user_id: impl IntoObjectId,
+    ) -> Result<Vec<Invitation>, Error> {
+        Ok(self
+            .invitations
+            .find(doc! {"user":user_id.into_objetc_id()})
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    ///Withdraw an invitation to a doc
+    pub async fn delete_invitation(
+        &self,
+        doc_id: impl IntoObjectId,
+        invitation_id: impl IntoObjectId,
+    ) -> Result<Invitation, Error> {
+        self.invitations
+            .find_one_and_delete(doc! {
+                "_id":invitation_id.into_objetc_id(),
+                "doc":doc_id.into_objetc_id()
+            })
+            .await?
+            .ok_or(Error::from("invitation not found"))
+    }
+
+    ///Give `user_id` the role they were invited with
+    pub async fn accept_invitation(
+        &self,
+        invitation_id: impl IntoObjectId,
+        user_id: impl IntoObjectId,
+    ) -> Result<Invitation, Error> {
+        let user_id = user_id.into_objetc_id();
+        let invitation = self
+            .invitations
+            .find_one(doc! {
+                "_id":invitation_id.into_objetc_id(),
+                "user":user_id
+            })
+            .await?
+            .ok_or(Error::from("invitation not found"))?;
+        // grant access before the invitation goes, so a failure in between
+        // leaves an invitation that can be accepted again
+        self.docs
+            .update_one(
+                doc! {
+                    "_id":invitation.doc,
+                    "author.id":{"$ne":user_id},
+                    "permissions.user":{"$ne":user_id}
+                },
+                doc! {"$push":{"permissions":{
+                    "user":user_id,
+                    "role":bson::serialize_to_bson(&invitation.role)?
+                }}},
+            )
+            .await?;
+        self.invitations
+            .delete_one(doc! {"_id":invitation.id})
+            .await?;
+        // a request to join is moot once the user is in
+        self.requests
+            .delete_many(doc! {"doc":invitation.doc, "from":user_id})
+            .await?;
+        Ok(invitation)
+    }
+
+    ///Turn down an invitation addressed to `user_id`
+    pub async fn decline_invitation(
+        &self,
+        invitation_id: impl IntoObjectId,
+        user_id: impl IntoObjectId,
+    ) -> Result<Invitation, Error> {
+        self.invitations
+            .find_one_and_delete(doc! {
+                "_id":invitation_id.into_objetc_id(),
+                "user":user_id.into_objetc_id()
+            })
+            .await?
+            .ok_or(Error::from("invitation not found"))
+    }
+
+    // Share Links Collection
+
+    pub async fn create_share_link(&self, link: &ShareLink) -> Result<ObjectId, Error> {
+        let res = self.share_links.insert_one(link).await?;
+        res.inserted_id
+            .as_object_id()
+            .ok_or(Error::from("could not create link"))
+    }
+
+    pub async fn find_share_link(&self, link_id: impl IntoObjectId) -> Result<ShareLink, Error> {
+        self.share_links
+            .find_one(doc! {"_id":link_id.into_objetc_id()})
+            .await?
+            .ok_or(Error::from("link not found"))
+    }
+
+    pub async fn find_doc_share_links(
+        &self,
+        doc_id: impl IntoObjectId,
+    ) -> Result<Vec<ShareLink>, Error> {
+        Ok(self
+            .share_links
+            .find(doc! {"doc":doc_id.into_objetc_id()})
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    pub async fn delete_share_link(
+        &self,
+        doc_id: impl IntoObjectId,
+        link_id: impl IntoObjectId,
+    ) -> Result<ShareLink, Error> {
+        self.share_links
+            .find_one_and_delete(doc! {
+                "_id":link_id.into_objetc_id(),
+                "doc":doc_id.into_objetc_id()
+            })
+            .await?
+            .ok_or(Error::from("link not found"))
+    }
+
+    // Sessions Collection
+    pub async fn create_session(&self, session: &Session) -> Result<ObjectId, Error> {
+        let res = self.sessions.insert_one(session).await?;
+        res.inserted_id
+            .as_object_id()
+            .ok_or(Error::from("could not create session"))
+    }
+
+    ///Session that has not been revoked or expired
+    pub async fn find_session(&self, session_id: impl IntoObjectId) -> Result<Session, Error> {
+        self.sessions
+            .find_one(doc! {
+                "_id":session_id.into_objetc_id(),
+                "revoked":false,
+                "expires_at":{"$gt":DateTime::now()}
+            })
+            .await?
+            .ok_or(Error::from("session not found"))
+    }
+
+    ///Swap the refresh token of a session for a new one, as long as `refresh`
+    ///is still its current one. Only one of two refreshes racing with the same
+    ///token wins.
+    pub async fn rotate_session(
+        &self,
+        session_id: impl IntoObjectId,
+        refresh: &str,
+        update: &Session,
+    ) -> Result<bool, Error> {
+        let res = self
+            .sessions
+            .update_one(
+                doc! {
+                    "_id":session_id.into_objetc_id(),
+                    "refresh":refresh,
+                    "revoked":false
+                },
+                doc! {"$set":{
+                    "refresh":&update.refresh,
+                    "previous":refresh,
+                    "device":&update.device,
+                    "ip":&update.ip,
+                    "last_seen":update.last_seen,
+                    "expires_at":update.expires_at
+                }},
+            )
+            .await?;
+        Ok(res.modified_count == 1)
+    }
+
+    ///Revoke one of a user's sessions
+    pub async fn revoke_session(
+        &self,
+        user: impl IntoObjectId,
+        session_id: impl IntoObjectId,
+    ) -> Result<bool, Error> {
+        let res = self
+            .sessions
+            .update_one(
+                doc! {
+                    "_id":session_id.into_objetc_id(),
+                    "user":user.into_objetc_id(),
+                    "revoked":false
+                },
+                doc! {"$set":{"revoked":true}},
+            )
+            .await?;
+        Ok(res.modified_count == 1)
+    }
+
+    ///Revoke every session of a user, returning how many were still live
+    pub async fn revoke_user_sessions(&self, user: impl IntoObjectId) -> Result<u64, Error> {
+        let res = self
+            .sessions
+            .update_many(
+                doc! {"user":user.into_objetc_id(), "revoked":false},
+                doc! {"$set":{"revoked":true}},
+            )
+            .await?;
+        Ok(res.modified_count)
+    }
+
+    ///Sessions a user is still logged in with, most recently used first
+    pub async fn find_user_sessions(&self, user: impl IntoObjectId) -> Result<Vec<Session>, Error> {
+        Ok(self
+            .sessions
+            .find(doc! {
+                "user":user.into_objetc_id(),
+                "revoked":false,
+                "expires_at":{"$gt":DateTime::now()}
+            })
+            .sort(doc! {"last_seen":-1})
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    ///Which of `sessions` have not been revoked or expired
+    pub async fn find_live_sessions(
+        &self,
+        sessions: Vec<ObjectId>,
+    ) -> Result<Vec<ObjectId>, Error> {
+        let ids = self
+            .sessions
+            .distinct(
+                "_id",
+                doc! {
+                    "_id":{"$in":sessions},
+                    "revoked":false,
+                    "expires_at":{"$gt":DateTime::now()}
+                },
+            )
+            .await?;
+        Ok(ids.iter().filter_map(|id| id.as_object_id()).collect())
+    }
+
+    ///Store a user's second factor, or remove it with `None`
+    pub async fn set_two_factor(
+        &self,
+        user_id: impl IntoObjectId,
+        two_factor: Option<&TwoFactor>,
+    ) -> Result<(), Error> {
+        let update = match two_factor {
+            Some(t) => doc! {"$set":{"two_factor":bson::serialize_to_bson(t)?}},
+            None => doc! {"$unset":{"two_factor":""}},
+        };
+        self.users
+            .update_one(doc! {"_id":user_id.into_objetc_id()}, update)
+            .await?;
+        Ok(())
+    }
+
+    ///Record that the TOTP code of `step` was used, failing if it or a later
+    ///one already was
+    pub async fn use_totp_step(
+        &self,
+        user_id: impl IntoObjectId,
+        step: u64,
+    ) -> Result<bool, Error> {
+        let step = step as i64;
+        let res = self
+            .users
+            .update_one(
+                doc! {
+                    "_id":user_id.into_objetc_id(),
+                    "two_factor.last_step":{"$lt":step}
+                },
+                doc! {"$set":{"two_factor.last_step":step}},
+            )
+            .await?;
+        Ok(res.modified_count == 1)
+    }
+
+    ///Count a wrong second factor code against a user, locking their second
+    ///login step until `locked_until` once `max_failures` add up
+    pub async fn fail_two_factor(
+        &self,
+        user_id: impl IntoObjectId,
+        max_failures: u32,
+        locked_until: DateTime,
+    ) -> Result<(), Error> {
+        let user_id = user_id.into_objetc_id();
+        let user = self
+            .users
+            .find_one_and_update(
+                doc! {"_id":user_id, "two_factor.enabled":true},
+                doc! {"$inc":{"two_factor.failures":1}},
+            )
+            .return_document(ReturnDocument::After)
+            .await?;
+        if user
+            .and_then(|u| u.two_factor)
+            .is_some_and(|t| t.failures >= max_failures)
+        {
+            self.users
+                .update_one(
+                    doc! {"_id":user_id},
+                    doc! {"$set":{"two_factor.failures":0, "two_factor.locked_until":locked_until}},
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    ///Forget the wrong second factor codes of a user after a right one
+    pub async fn reset_two_factor_failures(&self, user_id: impl IntoObjectId) -> Result<(), Error> {
+        self.users
+            .update_one(
+                doc! {"_id":user_id.into_objetc_id(), "two_factor.enabled":true},
+                doc! {
+                    "$set":{"two_factor.failures":0},
+                    "$unset":{"two_factor.locked_until":""}
+                },
+            )
+            .await?;
+        Ok(())
+    }
+
+    ///Replace the recovery codes of an enabled second factor
+    pub async fn set_recovery_codes(
+        &self,
+        user_id: impl IntoObjectId,
+        hashes: Vec<String>,
+    ) -> Result<(), Error> {
+        let res = self
+            .users
+            .update_one(
+                doc! {"_id":user_id.into_objetc_id(), "two_factor.enabled":true},
+                doc! {"$set":{"two_factor.recovery_codes":hashes}},
+            )
+            .await?;
+        if res.matched_count == 0 {
+            return Err(Error::from("two-factor authentication is not enabled"));
+        }
+        Ok(())
+    }
+
+    ///Use up a recovery code by its hash. Only one of two requests racing
+    ///with it gets it.
+    pub async fn use_recovery_code(
+        &self,
+        user_id: impl IntoObjectId,
+        hash: &str,
+    ) -> Result<bool, Error> {
+        let res = self
+            .users
+            .update_one(
+                doc! {
+                    "_id":user_id.into_objetc_id(),
+                    "two_factor.enabled":true,
+                    "two_factor.recovery_codes":hash
+                },
+                doc! {"$pull":{"two_factor.recovery_codes":hash}},
+            )
+            .await?;
+        Ok(res.modified_count == 1)
+    }
+
+    // Login Challenges Collection
+    pub async fn create_login_challenge(
+        &self,
+        challenge: &LoginChallenge,
+    ) -> Result<ObjectId, Error> {
+        let res = self.login_challenges.insert_one(challenge).await?;
+        res.inserted_id
+            .as_object_id()
+            .ok_or(Error::from("could not create login challenge"))
+    }
+
+    ///Challenge that has not expired
+    pub async fn find_login_challenge(
+        &self,
+        challenge_id: impl IntoObjectId,
+    ) -> Result<LoginChallenge, Error> {
+        self.login_challenges
+            .find_one(doc! {
+                "_id":challenge_id.into_objetc_id(),
+                "expires_at":{"$gt":DateTime::now()}
+            })
+            .await?
+            .ok_or(Error::from("login has expired, sign in again"))
+    }
+
+    ///Count an attempt against a challenge before its code is checked, so
+    ///requests racing with each other cannot get past `max_attempts`
+    pub async fn claim_login_challenge(
+        &self,
+        challenge_id: impl IntoObjectId,
+        max_attempts: u32,
+    ) -> Result<bool, Error> {
+        let res = self
+            .login_challenges
+            .find_one_and_update(
+                doc! {
+                    "_id":challenge_id.into_objetc_id(),
+                    "attempts":{"$lt":max_attempts},
+                    "expires_at":{"$gt":DateTime::now()}
+                },
+                doc! {"$inc":{"attempts":1}},
+            )
+            .await?;
+        Ok(res.is_some())
+    }
+
+    ///Use a challenge up. Only one of two requests racing with it gets it.
+    pub async fn take_login_challenge(
+        &self,
+        challenge_id: impl IntoObjectId,
+    ) -> Result<LoginChallenge, Error> {
+        self.login_challenges
+            .find_one_and_delete(doc! {"_id":challenge_id.into_objetc_id()})
+            .await?
+            .ok_or(Error::from("login has expired, sign in again"))
+    }
+
+    // OIDC Logins Collection
+    pub async fn create_oidc_login(&self, login: &OidcLogin) -> Result<(), Error> {
+        self.oidc_logins.insert_one(login).await?;
+        Ok(())
+    }
+
+    ///Use up a login that has not expired. Only one of two requests racing
+    ///with it gets it.
+    pub async fn take_oidc_login(&self, provider: &str, state: &str) -> Result<OidcLogin, Error> {
+        self.oidc_logins
+            .find_one_and_delete(doc! {
+                "provider":provider,
+                "state":state,
+                "expires_at":{"$gt":DateTime::now()}
+            })
+            .await?
+            .ok_or(Error::from("login has expired, try again"))
+    }
+
+    // Identities Collection
+    pub async fn find_identity(
+        &self,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<Identity>, Error> {
+        Ok(self
+            .identities
+            .find_one(doc! {"provider":provider, "subject":subject})
+            .await?)
+    }
+
+    pub async fn link_identity(&self, identity: &Identity) -> Result<(), Error> {
+        self.identities.insert_one(identity).await?;
+        Ok(())
+    }
+
+    // Email Tokens Collection
+
+    ///Store a token, replacing the ones sent to the user for the same purpose
+    pub async fn create_email_token(&self, token: &EmailToken) -> Result<ObjectId, Error> {
+        self.email_tokens
+            .delete_many(doc! {
+                "user":token.user,
+                "purpose":bson::serialize_to_bson(&token.purpose)?
+            })
+            .await?;
+        let res = self.email_tokens.insert_one(token).await?;
+        res.inserted_id
+            .as_object_id()
+            .ok_or(Error::from("could not create token"))
+    }
+
+    ///Token that has not expired
+    pub async fn find_email_token(
+        &self,
+        token_id: impl IntoObjectId,
+        purpose: TokenPurpose,
+    ) -> Result<EmailToken, Error> {
+        self.email_tokens
+            .find_one(doc! {
+                "_id":token_id.into_objetc_id(),
+                "purpose":bson::serialize_to_bson(&purpose)?,
+                "expires_at":{"$gt":DateTime::now()}
+            })
+            .await?
+            .ok_or(Error::from("invalid or expired token"))
+    }
+
+    ///Use a token up. Only one of two requests racing with it gets it.
+    pub async fn take_email_token(&self, token_id: impl IntoObjectId) -> Result<EmailToken, Error> {
+        self.email_tokens
+            .find_one_and_delete(doc! {"_id":token_id.into_objetc_id()})
+            .await?
+            .ok_or(Error::from("invalid or expired token"))
+    }
+
+    // Uploads Collection
+    pub async fn upload_doc(&self, doc: UploadedDoc) -> Result<InsertOneResult, Error> {
+        Ok(self.uploads.insert_one(doc).await?)
+    }
+
+    pub async fn get_uploads(&self, owner: impl IntoObjectId) -> Result<Vec<UploadedDoc>, Error> {
+        Ok(self
+            .uploads
+            .find(doc! {"owner":owner.into_objetc_id()})
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    // Node Operation
+
+    ///Claim a doc for `node` for `ttl` unless another node holds an unexpired
+    ///claim, returning the node that owns it
+    pub async fn claim_doc(
+        &self,
+        doc_id: &str,
+        node: &str,
+        ttl: Duration,
+    ) -> Result<String, Error> {
+        let now = DateTime::now();
+        let expires_at = DateTime::from_millis(now.timestamp_millis() + ttl.as_millis() as i64);
+        let res = self
+            .leases
+            .find_one_and_update(
+                doc! {
+                    "_id":doc_id,
+                    "$or":[{"node":node}, {"expires_at":{"$lt":now}}]
+                },
+                doc! {"$set":{"node":node, "expires_at":expires_at}},
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await;
+        match res {
+            Ok(Some(lease)) => Ok(lease.node),
+            // the upsert collides with the claim of another node
+            Ok(None) => self.current_owner(doc_id).await,
+            Err(e) if is_duplicate_key(&e) => self.current_owner(doc_id).await,
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn current_owner(&self, doc_id: &str) -> Result<String, Error> {
+        self.find_doc_owner(doc_id)
+            .await?
+            .ok_or_else(|| Error::from("doc changed owner, try again"))
+    }
+
+    ///Node holding an unexpired claim on a doc
+    pub async fn find_doc_owner(&self, doc_id: &str) -> Result<Option<String>, Error> {
+        let lease = self
+            .leases
+            .find_one(doc! {"_id":doc_id, "expires_at":{"$gte":DateTime::now()}})
+            .await?;
+        Ok(lease.map(|l: Lease| l.node))
+    }
+
+    ///Drop the claim `node` holds on a doc
+    pub async fn release_doc(&self, doc_id: &str, node: &str) -> Result<(), Error> {
+        self.leases
+            .delete_one(doc! {"_id":doc_id, "node":node})
+            .await?;
+        Ok(())
+    }
+
+    pub async fn send_packet(&self, packet: &RoutedPacket) -> Result<(), Error> {
+        self.packets.insert_one(packet).await?;
+        Ok(())
+    }
+
+    ///Stream of packets sent to `node` from now on
+    pub async fn watch_packets(
+        &self,
+        node: &str,
+    ) -> Result<ChangeStream<ChangeStreamEvent<RoutedPacket>>, Error> {
+        Ok(self
+            .packets
+            .watch()
+            .pipeline([doc! {
+                "$match":{"operationType":"insert", "fullDocument.node":node}
+            }])
+            .await?)
+    }
+}
+
+/// Collation that compares emails without regard to case
+fn case_insensitive() -> Collation {
+    Collation::builder()
+        .locale("en".to_string())
+        .strength(CollationStrength::Secondary)
+        .build()
+}
+
+/// Indexes of the documents an unordered `insert_many` did not write, split
+/// into those that failed and those whose key was already taken. `None` if
+/// the error does not say.
+fn failed_inserts(e: &mongodb::error::Error) -> Option<(Vec<usize>, Vec<usize>)> {
+    match e.kind.as_ref() {
+        ErrorKind::InsertMany(InsertManyError {
+            write_errors: Some(errors),
+            write_concern_error: None,
+            ..
+        }) => {
+            let (duplicates, failed): (Vec<_>, Vec<_>) =
+                errors.iter().partition(|w| w.code == 11000);
+            Some((
+                failed.iter().map(|w| w.index).collect(),
+                duplicates.iter().map(|w| w.index).collect(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    match e.kind.as_ref() {
+        ErrorKind::Command(c) => c.code == 11000,
+        ErrorKind::Write(WriteFailure::WriteError(w)) => w.code == 11000,
+        _ => false,
+    }
 }
@@ -0,0 +1,204 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
+
+use crate::{
+    models::{Change, Error, Update, UpdateType},
+    text::PositionError,
+};
+
+/// Longest a single diff may take before falling back to a coarser result
+const DIFF_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A run of text that is unchanged, inserted or deleted between two contents
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase", tag = "op")]
+pub enum Span {
+    Equal { text: String },
+    Insert { text: String },
+    Delete { text: String },
+}
+
+/// Character level diff turning `old` into `new`
+pub fn diff(old: &str, new: &str) -> Vec<Span> {
+    let diff = TextDiff::configure()
+        .timeout(DIFF_TIMEOUT)
+        .diff_chars(old, new);
+    let mut spans: Vec<Span> = Vec::new();
+    for change in diff.iter_all_changes() {
+        let value = change.value();
+        match (spans.last_mut(), change.tag()) {
+            (Some(Span::Equal { text }), ChangeTag::Equal)
+            | (Some(Span::Insert { text }), ChangeTag::Insert)
+            | (Some(Span::Delete { text }), ChangeTag::Delete) => text.push_str(value),
+            (_, ChangeTag::Equal) => spans.push(Span::Equal {
+                text: value.to_string(),
+            }),
+            (_, ChangeTag::Insert) => spans.push(Span::Insert {
+                text: value.to_string(),
+            }),
+            (_, ChangeTag::Delete) => spans.push(Span::Delete {
+                text: value.to_string(),
+            }),
+        }
+    }
+    spans
+}
+
+/// Position updates, counted in scalar values and applied in order, that turn
+/// `old` into `new`
+pub fn to_updates(old: &str, new: &str) -> Vec<Update> {
+    let update = |position, update_type| Update {
+        position,
+        from: None,
+        update_type,
+        timestamp: None,
+        revision: None,
+        op_id: None,
+    };
+    let mut position = 0;
+    let mut updates = Vec::new();
+    for span in diff(old, new) {
+        match span {
+            Span::Equal { text } => position += text.chars().count(),
+            Span::Delete { text } => updates.push(update(
+                position,
+                UpdateType::Delete {
+                    length: text.chars().count(),
+                },
+            )),
+            Span::Insert { text } => {
+                let length = text.chars().count();
+                updates.push(update(position, UpdateType::Insert { data: text }));
+                position += length;
+            }
+        }
+    }
+    updates
+}
+
+/// A span of a diff along with who made the change and when it was last touched
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AttributedSpan {
+    #[serde(flatten)]
+    pub span: Span,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<ObjectId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<chrono::DateTime<Utc>>,
+}
+
+/// What the changes being replayed did to a character
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mark {
+    Kept,
+    Inserted(Option<ObjectId>, DateTime),
+    Deleted(Option<ObjectId>, DateTime),
+}
+
+struct Cell {
+    ch: char,
+    mark: Mark,
+}
+
+/// Index of the cell holding the `position`th visible character, or the end
+fn cell_index(cells: &[Cell], position: usize) -> usize {
+    cells
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| !matches!(c.mark, Mark::Deleted(..)))
+        .nth(position)
+        .map_or(cells.len(), |(i, _)| i)
+}
+
+/// Diff between `base` and what `changes` turn it into, crediting every
+/// inserted and deleted span to the change that made it. Text inserted and
+/// deleted again within the changes does not show up.
+pub fn attribute(base: &str, changes: &[Change]) -> Result<Vec<AttributedSpan>, Error> {
+    let mut cells: Vec<Cell> = base
+        .chars()
+        .map(|ch| Cell {
+            ch,
+            mark: Mark::Kept,
+        })
+        .collect();
+    let mut visible = cells.len();
+    for change in changes {
+        let position = change.position;
+        let out_of_bounds = PositionError::OutOfBounds {
+            position,
+            length: visible,
+        };
+        match &change.update_type {
+            UpdateType::Insert { data } => {
+                if position > visible {
+                    return Err(out_of_bounds.into());
+                }
+                let index = cell_index(&cells, position);
+                let mark = Mark::Inserted(change.author, change.timestamp);
+                cells.splice(index..index, data.chars().map(|ch| Cell { ch, mark }));
+                visible += data.chars().count();
+            }
+            UpdateType::Delete { length } => {
+                if position + length > visible {
+                    return Err(out_of_bounds.into());
+                }
+                let mut index = cell_index(&cells, position);
+                let mut remaining = *length;
+                while remaining > 0 {
+                    match cells[index].mark {
+                        Mark::Deleted(..) => index += 1,
+                        Mark::Inserted(..) => {
+                            cells.remove(index);
+                            remaining -= 1;
+                        }
+                        Mark::Kept => {
+                            cells[index].mark = Mark::Deleted(change.author, change.timestamp);
+                            index += 1;
+                            remaining -= 1;
+                        }
+                    }
+                }
+                visible -= length;
+            }
+        }
+    }
+
+    let mut spans: Vec<AttributedSpan> = Vec::new();
+    for cell in cells {
+        let (author, timestamp) = match cell.mark {
+            Mark::Kept => (None, None),
+            Mark::Inserted(a, t) | Mark::Deleted(a, t) => (a, Some(t.to_chrono())),
+        };
+        if let Some(last) = spans.last_mut()
+            && last.author == author
+        {
+            let text = match (&mut last.span, cell.mark) {
+                (Span::Equal { text }, Mark::Kept)
+                | (Span::Insert { text }, Mark::Inserted(..))
+                | (Span::Delete { text }, Mark::Deleted(..)) => Some(text),
+                _ => None,
+            };
+            if let Some(text) = text {
+                text.push(cell.ch);
+                last.timestamp = last.timestamp.max(timestamp);
+                continue;
+            }
+        }
+        let text = cell.ch.to_string();
+        let span = match cell.mark {
+            Mark::Kept => Span::Equal { text },
+            Mark::Inserted(..) => Span::Insert { text },
+            Mark::Deleted(..) => Span::Delete { text },
+        };
+        spans.push(AttributedSpan {
+            span,
+            author,
+            timestamp,
+        });
+    }
+    Ok(spans)
+}
@@ -0,0 +1,199 @@
+use std::{collections::HashMap, env, sync::Arc, time::Duration};
+
+use axum::extract::ws::Message;
+use futures::future::BoxFuture;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{
+        Mutex,
+        mpsc::{self, Receiver, Sender},
+    },
+    time::Instant,
+};
+
+use crate::{
+    db::Db,
+    models::{DocsMap, EditQuery, Error},
+    session,
+};
+
+mod mongo;
+
+pub use mongo::MongoBroker;
+
+/// How long a node owns a doc for without renewing its claim
+pub const LEASE_TTL: Duration = Duration::from_secs(30);
+
+/// What nodes send each other. A client connected to a node that does not own
+/// its doc is tunnelled to the owner, which edits on its behalf as if it were
+/// connected locally.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Packet {
+    /// A client connected on `node` to a doc the receiving node owns
+    Open {
+        node: String,
+        conn: String,
+        doc: String,
+        user: String,
+        /// Login session the client connected with
+        #[serde(default)]
+        session: String,
+        query: EditQuery,
+    },
+    /// Frame sent by a tunnelled client, for the owner
+    Frame { conn: String, text: String },
+    /// Frame for a tunnelled client, for the node it is connected to
+    Deliver { conn: String, text: String },
+    /// Either end of a tunnel went away
+    Close { conn: String },
+}
+
+/// Carries packets between the nodes serving docs and decides which of them
+/// owns each doc
+pub trait Broker: Send + Sync {
+    /// Id of this node
+    fn node(&self) -> &str;
+    /// Send a packet to `node`
+    fn send<'a>(&'a self, node: &'a str, packet: Packet) -> BoxFuture<'a, Result<(), Error>>;
+    /// Take ownership of a doc unless another node holds it, renewing it if
+    /// this node already does. Returns the owner.
+    fn claim<'a>(&'a self, doc_id: &'a str) -> BoxFuture<'a, Result<String, Error>>;
+    /// Node currently owning a doc, if any
+    fn owner<'a>(&'a self, doc_id: &'a str) -> BoxFuture<'a, Result<Option<String>, Error>>;
+    /// Give up ownership of a doc
+    fn release<'a>(&'a self, doc_id: &'a str) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// Broker for a single node, which owns every doc
+pub struct LocalBroker {
+    node: String,
+    inbox: Sender<Packet>,
+}
+
+impl LocalBroker {
+    pub fn new() -> (Self, Receiver<Packet>) {
+        let (inbox, rx) = mpsc::channel(1024);
+        let broker = LocalBroker {
+            node: ObjectId::new().to_hex(),
+            inbox,
+        };
+        (broker, rx)
+    }
+}
+
+impl Broker for LocalBroker {
+    fn node(&self) -> &str {
+        &self.node
+    }
+
+    fn send<'a>(&'a self, node: &'a str, packet: Packet) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            if node != self.node {
+                return Err(Error::new(format!("unknown node {}", node)));
+            }
+            self.inbox
+                .send(packet)
+                .await
+                .map_err(|_| Error::from("broker is closed"))
+        })
+    }
+
+    fn claim<'a>(&'a self, _doc_id: &'a str) -> BoxFuture<'a, Result<String, Error>> {
+        Box::pin(async move { Ok(self.node.clone()) })
+    }
+
+    fn owner<'a>(&'a self, _doc_id: &'a str) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        Box::pin(async move { Ok(Some(self.node.clone())) })
+    }
+
+    fn release<'a>(&'a self, _doc_id: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move { Ok(()) })
+    }
+}
+
+/// Broker and the ends of the tunnels running through this node
+pub struct Fanout {
+    pub broker: Arc<dyn Broker>,
+    /// Where to forward frames of a tunnel, by connection id. On the node a
+    /// client connected to this is its websocket, on the owner the session
+    /// editing for it.
+    pub tunnels: Mutex<HashMap<String, Sender<Message>>>,
+}
+
+impl Fanout {
+    /// Pick a broker from `FANOUT`, `mongo` to share docs between nodes over
+    /// MongoDB and anything else to serve every doc from this node
+    pub async fn init(db: Arc<Db>) -> (Arc<Self>, Receiver<Packet>) {
+        let (broker, inbox): (Arc<dyn Broker>, _) = match env::var("FANOUT").as_deref() {
+            Ok("mongo") => {
+                let node = env::var("NODE_ID").unwrap_or_else(|_| ObjectId::new().to_hex());
+                let (broker, inbox) = MongoBroker::start(db, node).await;
+                (Arc::new(broker), inbox)
+            }
+            _ => {
+                let (broker, inbox) = LocalBroker::new();
+                (Arc::new(broker), inbox)
+            }
+        };
+        log::info!("running as node {}", broker.node());
+        let fanout = Fanout {
+            broker,
+            tunnels: Mutex::new(HashMap::new()),
+        };
+        (Arc::new(fanout), inbox)
+    }
+
+    /// Whether this node owns a doc, claiming it if nobody does
+    pub async fn owns(&self, doc_id: &str) -> Result<bool, Error> {
+        Ok(self.broker.claim(doc_id).await? == self.broker.node())
+    }
+
+    /// Hand a frame to the local end of a tunnel without waiting for room. A
+    /// tunnel that cannot keep up is dropped so it does not hold up the others;
+    /// its client reconnects.
+    pub async fn forward(&self, conn: &str, msg: Message) {
+        let mut tunnels = self.tunnels.lock().await;
+        if let Some(sender) = tunnels.get(conn)
+            && sender.try_send(msg).is_err()
+        {
+            log::debug!("dropping tunnel {}", conn);
+            tunnels.remove(conn);
+        }
+    }
+}
+
+/// Give up every doc this node is editing so other nodes can take them over
+pub async fn release_all(fanout: &Fanout, docs: &DocsMap) {
+    let doc_ids: Vec<String> = docs.lock().await.keys().cloned().collect();
+    for doc_id in doc_ids {
+        if let Err(e) = fanout.broker.release(&doc_id).await {
+            log::error!("could not release {}: {}", doc_id, e);
+        }
+    }
+}
+
+/// Keep the docs this node is editing claimed, closing those another node took
+/// over
+pub fn spawn_lease_keeper(fanout: Arc<Fanout>, docs: DocsMap) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(LEASE_TTL / 3);
+        loop {
+            interval.tick().await;
+            let doc_ids: Vec<String> = docs.lock().await.keys().cloned().collect();
+            for doc_id in doc_ids {
+                let claimed_at = Instant::now();
+                match fanout.owns(&doc_id).await {
+                    Ok(true) => session::renewed(&docs, &doc_id, claimed_at + LEASE_TTL).await,
+                    Ok(false) => {
+                        log::error!("lost ownership of {}", doc_id);
+                        session::disown(&docs, &doc_id).await;
+                    }
+                    // edits are held back once the claim runs out
+                    Err(e) => log::error!("could not renew ownership of {}: {}", doc_id, e),
+                }
+            }
+        }
+    });
+}
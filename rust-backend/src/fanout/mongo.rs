@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use futures::{StreamExt, future::BoxFuture};
+use mongodb::bson::DateTime;
+use tokio::sync::mpsc::{self, Receiver};
+
+use crate::{
+    db::Db,
+    models::{Error, RoutedPacket},
+};
+
+use super::{Broker, LEASE_TTL, Packet};
+
+/// Broker sharing docs between nodes through MongoDB. Packets are inserted
+/// into a collection every node watches with a change stream, which needs
+/// MongoDB to run as a replica set.
+pub struct MongoBroker {
+    db: Arc<Db>,
+    node: String,
+}
+
+impl MongoBroker {
+    /// Start watching for packets addressed to `node`
+    pub async fn start(db: Arc<Db>, node: String) -> (Self, Receiver<Packet>) {
+        let (tx, rx) = mpsc::channel(1024);
+        let watcher_db = Arc::clone(&db);
+        let watcher_node = node.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut stream = match watcher_db.watch_packets(&watcher_node).await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        log::error!("could not watch packets: {}", e);
+                        tokio::time::sleep(LEASE_TTL / 10).await;
+                        continue;
+                    }
+                };
+                while let Some(event) = stream.next().await {
+                    match event.map(|e| e.full_document) {
+                        Ok(Some(routed)) => {
+                            if tx.send(routed.packet).await.is_err() {
+                                return;
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            log::error!("packet stream failed: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        (MongoBroker { db, node }, rx)
+    }
+}
+
+impl Broker for MongoBroker {
+    fn node(&self) -> &str {
+        &self.node
+    }
+
+    fn send<'a>(&'a self, node: &'a str, packet: Packet) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let routed = RoutedPacket {
+                node: node.to_string(),
+                packet,
+                created_at: DateTime::now(),
+            };
+            self.db.send_packet(&routed).await
+        })
+    }
+
+    fn claim<'a>(&'a self, doc_id: &'a str) -> BoxFuture<'a, Result<String, Error>> {
+        Box::pin(async move { self.db.claim_doc(doc_id, &self.node, LEASE_TTL).await })
+    }
+
+    fn owner<'a>(&'a self, doc_id: &'a str) -> BoxFuture<'a, Result<Option<String>, Error>> {
+        Box::pin(async move { self.db.find_doc_owner(doc_id).await })
+    }
+
+    fn release<'a>(&'a self, doc_id: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move { self.db.release_doc(doc_id, &self.node).await })
+    }
+}
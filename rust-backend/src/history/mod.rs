@@ -0,0 +1,224 @@
+use std::{env, sync::Arc, time::Duration};
+
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde::Serialize;
+
+use crate::{
+    db::Db,
+    diff::{self, AttributedSpan},
+    models::{Change, Error, HistoryQuery, IntoObjectId, Snapshot},
+    text::{self, AppliedUpdate, PositionUnit},
+};
+
+/// Revisions between snapshots when `SNAPSHOT_INTERVAL` is unset
+const DEFAULT_SNAPSHOT_INTERVAL: u64 = 500;
+/// Seconds between compaction runs when `COMPACTION_INTERVAL` is unset
+const DEFAULT_COMPACTION_INTERVAL: u64 = 3600;
+
+/// Content of a doc as it was at some revision
+#[derive(Serialize, Debug, Clone)]
+pub struct Reconstruction {
+    pub revision: u64,
+    pub content: String,
+}
+
+/// Number of revisions after which a new snapshot is taken
+pub fn snapshot_interval() -> u64 {
+    env::var("SNAPSHOT_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_SNAPSHOT_INTERVAL)
+}
+
+/// Resolve a history query to a revision of the doc, `current` being its latest one
+pub async fn target_revision(
+    db: &Db,
+    doc_id: impl IntoObjectId,
+    query: &HistoryQuery,
+    current: u64,
+) -> Result<u64, Error> {
+    match (query.revision, query.at) {
+        (Some(revision), _) if revision > current => Err(Error::new("revision does not exist")),
+        (Some(revision), _) => Ok(revision),
+        (None, Some(at)) => revision_at(db, doc_id, DateTime::from_chrono(at)).await,
+        (None, None) => Ok(current),
+    }
+}
+
+/// Revision a doc was at by `at`, failing if its history that far back was compacted
+pub async fn revision_at(db: &Db, doc_id: impl IntoObjectId, at: DateTime) -> Result<u64, Error> {
+    let doc_id = doc_id.into_objetc_id();
+    let compaction = db.find_compaction(doc_id).await?;
+    if let Some(before) = compaction.as_ref().and_then(|s| s.compacted_before)
+        && at < before
+    {
+        let before = before.try_to_rfc3339_string().unwrap_or_default();
+        return Err(Error::new(format!(
+            "history before {} was compacted",
+            before
+        )));
+    }
+    // the changes leading up to the compaction snapshot are gone
+    let revision = db.find_revision_at(doc_id, at).await?;
+    Ok(revision.max(compaction.map_or(0, |s| s.revision)))
+}
+
+/// Rebuild a doc as of `revision` by replaying its recorded changes on top of
+/// the nearest snapshot
+pub async fn reconstruct(
+    db: &Db,
+    doc_id: impl IntoObjectId,
+    revision: u64,
+) -> Result<Reconstruction, Error> {
+    let doc_id = doc_id.into_objetc_id();
+    let (base, mut content) = match db.find_snapshot(doc_id, revision).await? {
+        Some(s) => (s.revision, s.content),
+        None => (0, String::new()),
+    };
+    let changes = find_changes(db, doc_id, base, revision).await?;
+    for change in &changes {
+        content = text::apply(&content, &change.to_update(), PositionUnit::Scalar)?.0;
+    }
+    Ok(Reconstruction { revision, content })
+}
+
+/// Updates committed after revision `after` up to `upto`, read back from the
+/// change log in every position unit
+pub async fn applied_updates(
+    db: &Db,
+    doc_id: impl IntoObjectId,
+    after: u64,
+    upto: u64,
+) -> Result<Vec<AppliedUpdate>, Error> {
+    let doc_id = doc_id.into_objetc_id();
+    let mut content = reconstruct(db, doc_id, after).await?.content;
+    let mut updates = Vec::new();
+    for change in find_changes(db, doc_id, after, upto).await? {
+        let applied;
+        (content, applied) = text::apply(&content, &change.to_update(), PositionUnit::Scalar)?;
+        updates.push(applied);
+    }
+    Ok(updates)
+}
+
+/// Changes after revision `after` up to `upto`, failing if any is missing
+async fn find_changes(
+    db: &Db,
+    doc_id: ObjectId,
+    after: u64,
+    upto: u64,
+) -> Result<Vec<Change>, Error> {
+    let changes = db.find_changes(doc_id, after, upto).await?;
+    for (expected, change) in (after + 1..).zip(&changes) {
+        if change.revision != expected {
+            return Err(missing(expected));
+        }
+    }
+    if after + changes.len() as u64 != upto {
+        return Err(missing(after + changes.len() as u64 + 1));
+    }
+    Ok(changes)
+}
+
+/// Attributed diff of a doc between two revisions, computed by replaying the
+/// changes recorded in between
+pub async fn diff(
+    db: &Db,
+    doc_id: impl IntoObjectId,
+    from: u64,
+    to: u64,
+) -> Result<Vec<AttributedSpan>, Error> {
+    if from > to {
+        return Err(Error::new("cannot diff against a later revision"));
+    }
+    let doc_id = doc_id.into_objetc_id();
+    let base = reconstruct(db, doc_id, from).await?;
+    let changes = find_changes(db, doc_id, from, to).await?;
+    diff::attribute(&base.content, &changes)
+}
+
+fn missing(revision: u64) -> Error {
+    Error::new(format!("history is missing revision {}", revision))
+}
+
+/// How old changes are compacted, read from the environment
+pub struct CompactionConfig {
+    /// Changes older than this are folded into a snapshot
+    pub retention: Duration,
+    /// Copy compacted changes to the archive instead of dropping them
+    pub archive: bool,
+    pub interval: Duration,
+}
+
+impl CompactionConfig {
+    /// `None` unless `HISTORY_RETENTION_DAYS` is set
+    pub fn from_env() -> Option<Self> {
+        let days: u64 = env::var("HISTORY_RETENTION_DAYS").ok()?.parse().ok()?;
+        let archive = env::var("HISTORY_COMPACTION")
+            .map(|v| v != "prune")
+            .unwrap_or(true);
+        let interval = env::var("COMPACTION_INTERVAL")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|v| *v > 0)
+            .unwrap_or(DEFAULT_COMPACTION_INTERVAL);
+        Some(CompactionConfig {
+            retention: Duration::from_secs(days * 24 * 60 * 60),
+            archive,
+            interval: Duration::from_secs(interval),
+        })
+    }
+}
+
+/// Fold the changes of a doc older than `cutoff` into a snapshot and drop them
+pub async fn compact_doc(
+    db: &Db,
+    doc_id: ObjectId,
+    cutoff: DateTime,
+    archive: bool,
+) -> Result<(), Error> {
+    let revision = db.find_revision_at(doc_id, cutoff).await?;
+    if revision == 0 {
+        return Ok(());
+    }
+    let state = reconstruct(db, doc_id, revision).await?;
+    let snapshot = Snapshot {
+        compacted_before: Some(cutoff),
+        ..Snapshot::new(doc_id, revision, state.content)
+    };
+    db.save_snapshot(&snapshot).await?;
+    let removed = db.compact_changes(doc_id, revision, archive).await?;
+    db.delete_snapshots_before(doc_id, revision).await?;
+    log::debug!("compacted {} changes of {}", removed, doc_id);
+    Ok(())
+}
+
+/// Compact every doc with changes older than the retention window
+pub async fn compact(db: &Db, config: &CompactionConfig) -> Result<(), Error> {
+    let cutoff = DateTime::from_millis(
+        DateTime::now().timestamp_millis() - config.retention.as_millis() as i64,
+    );
+    for doc_id in db.find_docs_with_changes_before(cutoff).await? {
+        if let Err(e) = compact_doc(db, doc_id, cutoff, config.archive).await {
+            log::error!("could not compact {}: {}", doc_id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Run compaction in the background if a retention window is configured
+pub fn spawn_compactor(db: Arc<Db>) {
+    let Some(config) = CompactionConfig::from_env() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = compact(&db, &config).await {
+                log::error!("compaction failed: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,165 @@
+use std::{collections::HashMap, env, fs, sync::OnceLock};
+
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, decode_header, encode,
+};
+
+use crate::{
+    models::{Claims, Error},
+    utils,
+};
+
+/// Key id of the signing key when `JWT_KID` is unset
+const DEFAULT_KID: &str = "default";
+
+static KEYS: OnceLock<Keys> = OnceLock::new();
+
+/// Key tokens are signed with, and every key tokens are still accepted from
+struct Keys {
+    kid: String,
+    algorithm: Algorithm,
+    signing: EncodingKey,
+    verifying: HashMap<String, (Algorithm, DecodingKey)>,
+}
+
+/// Load the signing configuration from the environment:
+///
+/// - `JWT_ALGORITHM`: `HS256` (default), `RS256` or `EdDSA`
+/// - `JWT_KID`: id of the signing key, sent as the `kid` header
+/// - `JWT_SECRET`: the secret for `HS256`
+/// - `JWT_PRIVATE_KEY_FILE`, `JWT_PUBLIC_KEY_FILE`: pem key pair for `RS256` and `EdDSA`
+/// - `JWT_PREVIOUS_KEYS`: keys rotated out that tokens are still accepted
+///   from, as comma separated `kid=ALG:value` entries where the value is the
+///   secret for `HS256` and the public key file otherwise
+///
+/// Fails in production when no key is configured. Elsewhere a random secret
+/// is used, so tokens do not outlive the process.
+pub fn init() -> Result<(), Error> {
+    let keys = load()?;
+    log::info!("signing tokens with {:?} key {}", keys.algorithm, keys.kid);
+    KEYS.set(keys)
+        .map_err(|_| Error::from("jwt keys are already loaded"))
+}
+
+fn load() -> Result<Keys, Error> {
+    let algorithm = match env::var("JWT_ALGORITHM") {
+        Ok(a) => parse_algorithm(&a)?,
+        Err(_) => Algorithm::HS256,
+    };
+    let kid = env::var("JWT_KID").unwrap_or_else(|_| DEFAULT_KID.to_string());
+    let (signing, verifying) = match algorithm {
+        Algorithm::HS256 => match env::var("JWT_SECRET") {
+            Ok(secret) if !secret.is_empty() => (
+                EncodingKey::from_secret(secret.as_bytes()),
+                DecodingKey::from_secret(secret.as_bytes()),
+            ),
+            _ if utils::is_production() => {
+                return Err(Error::from("JWT_SECRET must be set in production"));
+            }
+            _ => {
+                log::warn!("JWT_SECRET is not set, tokens will not survive a restart");
+                let secret = utils::random_token();
+                (
+                    EncodingKey::from_secret(secret.as_bytes()),
+                    DecodingKey::from_secret(secret.as_bytes()),
+                )
+            }
+        },
+        _ => {
+            let private = read_var_file("JWT_PRIVATE_KEY_FILE")?;
+            let public = read_var_file("JWT_PUBLIC_KEY_FILE")?;
+            (
+                encoding_key(algorithm, &private)?,
+                decoding_key(algorithm, &public)?,
+            )
+        }
+    };
+    let mut keys = Keys {
+        kid: kid.clone(),
+        algorithm,
+        signing,
+        verifying: HashMap::from([(kid, (algorithm, verifying))]),
+    };
+    for entry in env::var("JWT_PREVIOUS_KEYS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+    {
+        let (kid, key) = previous_key(entry)?;
+        if keys.verifying.contains_key(&kid) {
+            return Err(Error::from(format!("jwt key id {} is used twice", kid)));
+        }
+        keys.verifying.insert(kid, key);
+    }
+    Ok(keys)
+}
+
+fn parse_algorithm(name: &str) -> Result<Algorithm, Error> {
+    match name {
+        "HS256" => Ok(Algorithm::HS256),
+        "RS256" => Ok(Algorithm::RS256),
+        "EdDSA" => Ok(Algorithm::EdDSA),
+        _ => Err(Error::from(format!("unsupported jwt algorithm {}", name))),
+    }
+}
+
+fn read_var_file(var: &str) -> Result<Vec<u8>, Error> {
+    let path = env::var(var).map_err(|_| Error::from(format!("{} must be set", var)))?;
+    fs::read(&path).map_err(|e| Error::from(format!("could not read {}: {}", path, e)))
+}
+
+fn encoding_key(algorithm: Algorithm, pem: &[u8]) -> Result<EncodingKey, Error> {
+    Ok(match algorithm {
+        Algorithm::RS256 => EncodingKey::from_rsa_pem(pem)?,
+        _ => EncodingKey::from_ed_pem(pem)?,
+    })
+}
+
+fn decoding_key(algorithm: Algorithm, pem: &[u8]) -> Result<DecodingKey, Error> {
+    Ok(match algorithm {
+        Algorithm::RS256 => DecodingKey::from_rsa_pem(pem)?,
+        _ => DecodingKey::from_ed_pem(pem)?,
+    })
+}
+
+/// Parse a `kid=ALG:value` entry of `JWT_PREVIOUS_KEYS`
+fn previous_key(entry: &str) -> Result<(String, (Algorithm, DecodingKey)), Error> {
+    let invalid = || Error::from(format!("invalid JWT_PREVIOUS_KEYS entry {}", entry));
+    let (kid, key) = entry.split_once('=').ok_or_else(invalid)?;
+    let (algorithm, value) = key.split_once(':').ok_or_else(invalid)?;
+    let algorithm = parse_algorithm(algorithm)?;
+    let key = match algorithm {
+        Algorithm::HS256 => DecodingKey::from_secret(value.as_bytes()),
+        _ => {
+            let pem = fs::read(value)
+                .map_err(|e| Error::from(format!("could not read {}: {}", value, e)))?;
+            decoding_key(algorithm, &pem)?
+        }
+    };
+    Ok((kid.to_string(), (algorithm, key)))
+}
+
+fn keys() -> &'static Keys {
+    KEYS.get().expect("jwt keys are not loaded")
+}
+
+/// Sign claims with the current key
+pub fn sign(claims: &Claims) -> Result<String, Error> {
+    let keys = keys();
+    let mut header = Header::new(keys.algorithm);
+    header.kid = Some(keys.kid.clone());
+    Ok(encode(&header, claims, &keys.signing)?)
+}
+
+/// Claims of a token signed by any key still accepted. Tokens without a `kid`
+/// are checked against the current key.
+pub fn verify(token: &str) -> Result<Claims, Error> {
+    let keys = keys();
+    let header = decode_header(token)?;
+    let kid = header.kid.unwrap_or_else(|| keys.kid.clone());
+    let Some((algorithm, key)) = keys.verifying.get(&kid) else {
+        return Err(Error::from(format!("unknown jwt key id {}", kid)));
+    };
+    Ok(decode::<Claims>(token, key, &Validation::new(*algorithm))?.claims)
+}
@@ -0,0 +1,99 @@
+use std::{env, path::PathBuf, sync::Arc};
+
+use futures::future::BoxFuture;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+use crate::{models::Error, utils};
+
+mod smtp;
+
+pub use smtp::SmtpMailer;
+
+/// Where the links in emails point to when `APP_URL` is unset
+const DEFAULT_APP_URL: &str = "http://localhost:5173";
+
+/// A plain text email
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers the emails the server sends to users
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// Appends emails to a file, or writes them to the log when there is none,
+/// for local development and tests. Bodies carry tokens, so they are only
+/// logged in development.
+pub struct FileMailer {
+    path: Option<PathBuf>,
+}
+
+impl FileMailer {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        FileMailer { path }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let text = format!(
+                "To: {}\nSubject: {}\n\n{}\n\n",
+                mail.to, mail.subject, mail.body
+            );
+            let Some(path) = &self.path else {
+                if utils::is_development() {
+                    log::info!("mail not sent\n{}", text);
+                } else {
+                    log::warn!("mail to {} not sent, MAILER is not set", mail.to);
+                }
+                return Ok(());
+            };
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+                .map_err(|e| Error::new(format!("could not open {}: {}", path.display(), e)))?;
+            let write = async {
+                file.write_all(text.as_bytes()).await?;
+                file.flush().await
+            };
+            write
+                .await
+                .map_err(|e| Error::new(format!("could not write {}: {}", path.display(), e)))
+        })
+    }
+}
+
+/// Pick a mailer from `MAILER`: `smtp` to deliver over SMTP, `file` to
+/// append emails to `MAIL_FILE` (`mail.log` by default) and anything else to
+/// log them. Production needs a real mailer.
+pub fn init() -> Result<Arc<dyn Mailer>, Error> {
+    let mailer: Arc<dyn Mailer> = match env::var("MAILER").as_deref() {
+        Ok("smtp") => Arc::new(SmtpMailer::from_env()?),
+        Ok("file") => {
+            let path = env::var("MAIL_FILE").unwrap_or_else(|_| "mail.log".to_string());
+            log::info!("writing mail to {}", path);
+            Arc::new(FileMailer::new(Some(path.into())))
+        }
+        _ if utils::is_production() => {
+            return Err(Error::from("MAILER must be smtp or file in production"));
+        }
+        _ => {
+            log::warn!("MAILER is not set, mail will only be logged");
+            Arc::new(FileMailer::new(None))
+        }
+    };
+    Ok(mailer)
+}
+
+/// Link to a page of the web app, for emails
+pub fn app_link(path: &str) -> String {
+    let base = env::var("APP_URL").unwrap_or_else(|_| DEFAULT_APP_URL.to_string());
+    format!("{}{}", base.trim_end_matches('/'), path)
+}
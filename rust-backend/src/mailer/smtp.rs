@@ -0,0 +1,69 @@
+use std::env;
+
+use futures::future::BoxFuture;
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    message::{Mailbox, header::ContentType},
+    transport::smtp::authentication::Credentials,
+};
+
+use crate::{
+    mailer::{Mail, Mailer},
+    models::Error,
+};
+
+/// Mailer delivering through an SMTP relay
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    /// Configure the relay from the environment:
+    ///
+    /// - `SMTP_HOST`: the relay
+    /// - `SMTP_PORT`: its port, if not the default for `SMTP_TLS`
+    /// - `SMTP_TLS`: `starttls` (default), `tls` or `none`
+    /// - `SMTP_USERNAME`, `SMTP_PASSWORD`: credentials, if the relay needs them
+    /// - `MAIL_FROM`: the sender, like `Docsly <no-reply@example.com>`
+    pub fn from_env() -> Result<Self, Error> {
+        let host = env::var("SMTP_HOST").map_err(|_| Error::from("SMTP_HOST must be set"))?;
+        let mut builder = match env::var("SMTP_TLS").as_deref() {
+            Ok("tls") => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?,
+            Ok("none") => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+            Ok("starttls") | Err(_) => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?,
+            Ok(other) => return Err(Error::new(format!("unsupported SMTP_TLS {}", other))),
+        };
+        if let Ok(port) = env::var("SMTP_PORT") {
+            let port = port
+                .parse()
+                .map_err(|_| Error::new(format!("invalid SMTP_PORT {}", port)))?;
+            builder = builder.port(port);
+        }
+        if let (Ok(username), Ok(password)) = (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD"))
+        {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        let from = env::var("MAIL_FROM").map_err(|_| Error::from("MAIL_FROM must be set"))?;
+        log::info!("sending mail through {}", host);
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from: from.parse()?,
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let message = Message::builder()
+                .from(self.from.clone())
+                .to(mail.to.parse()?)
+                .subject(&mail.subject)
+                .header(ContentType::TEXT_PLAIN)
+                .body(mail.body.clone())?;
+            self.transport.send(message).await?;
+            Ok(())
+        })
+    }
+}
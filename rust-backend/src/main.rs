@@ -1,4 +1,4 @@
-use std::{collections::HashMap, env, sync::Arc};
+use std::{collections::HashMap, env, net::SocketAddr, sync::Arc};
 
 use axum::{
     Extension, Router,
@@ -11,32 +11,51 @@ use tokio::{net::TcpListener, sync::Mutex};
 use tower_cookies::CookieManagerLayer;
 use tower_http::cors::CorsLayer;
 
-use crate::{db::Db, models::BufferMap};
+use crate::{db::Db, fanout::Fanout};
+mod buffer;
+mod crdt;
 mod db;
+mod diff;
+mod fanout;
+mod history;
+mod jwt;
+mod mailer;
 mod middleware;
 mod models;
+mod oidc;
+mod ot;
+mod protocol;
 mod routes;
+mod session;
+mod text;
+mod totp;
 mod utils;
 #[tokio::main]
 pub async fn main() {
-    if let Ok(env) = env::var("ENV") {
-        if env == "PROD" {
-            println!("Production environment detected")
-        } else if env == "DEV" {
-            dotenv::dotenv().ok().unwrap();
-        }
+    if utils::is_production() {
+        println!("Production environment detected")
+    } else if utils::is_development() {
+        dotenv::dotenv().ok().unwrap();
     }
     env_logger::init();
+    if let Err(e) = jwt::init() {
+        panic!("could not load jwt keys: {}", e);
+    }
+    let mailer = match mailer::init() {
+        Ok(m) => m,
+        Err(e) => panic!("could not set up mail: {}", e),
+    };
+    let oidc = match oidc::init() {
+        Ok(o) => o,
+        Err(e) => panic!("could not set up single sign-on: {}", e),
+    };
     let env_port = env::var("PORT");
-    let address: String;
-    match env_port {
-        Ok(p) => {
-            address = "0.0.0.0:".to_owned() + p.as_str();
-        }
+    let address: String = match env_port {
+        Ok(p) => "0.0.0.0:".to_owned() + p.as_str(),
         Err(e) => {
             log::info!("{}\n", e);
             log::debug!("Running on default local port");
-            address = "localhost:".to_owned() + "7878";
+            "localhost:".to_owned() + "7878"
         }
     };
     log::info!("Server listening on http://{}", address);
@@ -57,20 +76,60 @@ pub async fn main() {
         .allow_credentials(true);
     let db = Arc::new(Db::init().await);
     let docs_map: models::DocsMap = Arc::new(Mutex::new(HashMap::new()));
-    let buffer_map: BufferMap = Arc::new(Mutex::new(HashMap::new()));
+    history::spawn_compactor(Arc::clone(&db));
+    let (fanout, inbox) = Fanout::init(Arc::clone(&db)).await;
+    routes::spawn_relay(
+        Arc::clone(&fanout),
+        inbox,
+        Arc::clone(&docs_map),
+        Arc::clone(&db),
+    );
+    fanout::spawn_lease_keeper(Arc::clone(&fanout), Arc::clone(&docs_map));
     let router = Router::new();
     let app = manage_routes(router)
-        .layer(Extension(db))
-        .layer(Extension(docs_map))
-        .layer(Extension(buffer_map))
+        .layer(Extension(Arc::clone(&db)))
+        .layer(Extension(Arc::clone(&docs_map)))
+        .layer(Extension(Arc::clone(&fanout)))
+        .layer(Extension(mailer))
+        .layer(Extension(oidc))
         .layer(cors)
         .layer(CookieManagerLayer::new());
 
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+    log::info!("Flushing open documents");
+    session::flush_all(&docs_map).await;
+    fanout::release_all(&fanout, &docs_map).await;
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.unwrap();
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
 
 fn manage_routes(router: Router) -> Router {
-    let public_routes = Router::new().nest("/auth", routes::auth_routes());
+    let public_routes = Router::new()
+        .nest("/auth", routes::auth_routes())
+        .nest("/share", routes::share_routes());
     let protected_routes = Router::new()
         .nest("/doc", routes::doc_routes())
         .nest("/user", routes::user_routes())
@@ -1,8 +1,8 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use axum::{
     Extension, Json,
-    extract::Request,
+    extract::{Path, Query, Request},
     http::StatusCode,
     middleware::Next,
     response::{IntoResponse, Response},
@@ -10,54 +10,125 @@ use axum::{
 use serde_json::json;
 use tower_cookies::Cookies;
 
+use mongodb::bson::oid::ObjectId;
+
 use crate::{
     db::Db,
+    models::DocAccess,
     utils::{self},
 };
 ///Auth Middleware
+///
+///Lets through callers whose access token belongs to a live session, and
+///hands their claims to handlers through `Extension<Claims>`.
 pub async fn auth_middleware(
     Extension(db): Extension<Arc<Db>>,
     cookies: Cookies,
-    req: Request,
+    mut req: Request,
     next: Next,
 ) -> Response {
-    let cookie = cookies.get("token");
-    match cookie {
-        Some(c) => match utils::decode_cookie(c).await {
-            Some(claims) => {
-                let res = db.find_user_with_id(&claims.sub).await;
-                match res {
-                    Ok(_) => return next.run(req).await,
-                    Err(e) => {
-                        log::error!("{}", e);
-                        return (
-                            StatusCode::UNAUTHORIZED,
-                            Json(json!({
-                                "err":e
-                            })),
-                        )
-                            .into_response();
-                    }
+    if cookies.get("token").is_none() {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "err":"inavlid jwt 1",
+            })),
+        )
+            .into_response();
+    }
+    match utils::session_claims(&db, &cookies).await {
+        Some(claims) => {
+            let res = db.find_user_with_id(&claims.sub).await;
+            match res {
+                Ok(_) => {
+                    req.extensions_mut().insert(claims);
+                    next.run(req).await
+                }
+                Err(e) => {
+                    log::error!("{}", e);
+                    (
+                        StatusCode::UNAUTHORIZED,
+                        Json(json!({
+                            "err":e
+                        })),
+                    )
+                        .into_response()
                 }
             }
-            None => {
-                return (
-                    StatusCode::UNAUTHORIZED,
-                    Json(json!({
-                        "err":"inavlid jwt 2",
-                    })),
-                )
-                    .into_response();
-            }
-        },
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(json!({
-                    "err":"inavlid jwt 1",
-                })),
-            )
-                .into_response();
         }
+        None => (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "err":"inavlid jwt 2",
+            })),
+        )
+            .into_response(),
     }
 }
+
+///Doc Access Middleware
+///
+///Resolves the caller's role on the doc named by the `doc_id` or `id` path
+///parameter, or the `id` query parameter, and rejects callers without one.
+///Handlers read the result through `Extension<DocAccess>`.
+pub async fn doc_access(
+    Extension(db): Extension<Arc<Db>>,
+    cookies: Cookies,
+    path: Option<Path<HashMap<String, String>>>,
+    Query(query): Query<HashMap<String, String>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let forbidden = || {
+        (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"you do not have access to this document"
+            })),
+        )
+            .into_response()
+    };
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"Document not found"
+            })),
+        )
+            .into_response()
+    };
+    let Some(claims) = cookies.get("token") else {
+        return forbidden();
+    };
+    let Some(claims) = utils::decode_cookie(claims).await else {
+        return forbidden();
+    };
+    let Ok(user) = ObjectId::parse_str(&claims.sub) else {
+        return forbidden();
+    };
+    let path = path.map(|p| p.0).unwrap_or_default();
+    let doc_id = path
+        .get("doc_id")
+        .or_else(|| path.get("id"))
+        .or_else(|| query.get("id"));
+    let Some(doc_id) = doc_id.and_then(|id| ObjectId::parse_str(id).ok()) else {
+        return not_found();
+    };
+    let doc = match db.find_doc_with_id(doc_id).await {
+        Ok(d) => d,
+        Err(e) => {
+            log::error!("{}", e);
+            return not_found();
+        }
+    };
+    let Some(role) = doc.role(&user) else {
+        return forbidden();
+    };
+    req.extensions_mut().insert(DocAccess {
+        doc,
+        user,
+        role,
+        session: claims.sid,
+    });
+    next.run(req).await
+}
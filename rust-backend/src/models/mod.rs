@@ -1,18 +1,28 @@
-use axum::extract::ws::{self, Message};
+use axum::extract::ws::Message;
 use bson::Binary;
 use chrono::Utc;
 use mongodb::bson::{DateTime, oid::ObjectId};
 use serde::{Deserialize, Serialize};
-use serde_json::{from_str, to_string};
+use serde_json::json;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fmt::Display,
     str::{self, FromStr},
     sync::Arc,
 };
 use tokio::sync::{Mutex, mpsc::Sender};
 
+use crate::{
+    crdt::{CrdtOp, Rga},
+    fanout::Packet,
+    ot,
+    protocol::{self, Op, PresenceEvent, ServerMessage},
+    session::DocHandle,
+    text::{self, AppliedUpdate, PositionError, PositionUnit},
+};
+
 pub trait IntoObjectId {
+    #[allow(clippy::wrong_self_convention)]
     fn into_objetc_id(&self) -> ObjectId;
 }
 
@@ -40,8 +50,8 @@ impl IntoObjectId for Arc<&str> {
     }
 }
 
-pub type DocsMap = Arc<Mutex<HashMap<String, Vec<Client>>>>;
-pub type BufferMap = Arc<Mutex<HashMap<String, Sender<Update>>>>;
+/// Open docs, each owned by its own task
+pub type DocsMap = Arc<Mutex<HashMap<String, DocHandle>>>;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct LoginUser {
@@ -57,6 +67,139 @@ pub struct User {
     pub email: String,
     pub password: String,
     pub doc_count: Option<usize>,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub two_factor: Option<TwoFactor>,
+}
+
+/// TOTP second factor of a user. It stays pending until the user confirms
+/// the secret with a first code.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwoFactor {
+    /// Base32 secret shared with the authenticator
+    pub secret: String,
+    pub enabled: bool,
+    /// Hashes of the recovery codes not used yet
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    /// Time step of the last code accepted, a code only works once
+    #[serde(default)]
+    pub last_step: u64,
+    /// Wrong codes entered at login since the last lockout or right code
+    #[serde(default)]
+    pub failures: u32,
+    /// Second login steps are refused until then after too many wrong codes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked_until: Option<DateTime>,
+}
+
+/// Login that passed the password check and waits on the second factor. The
+/// token handed out is `<id>.<secret>`, only a hash of the secret is kept.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LoginChallenge {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user: ObjectId,
+    pub secret: String,
+    /// Wrong codes tried so far
+    pub attempts: u32,
+    pub expires_at: DateTime,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwoFactorLogin {
+    pub challenge: String,
+    pub code: String,
+}
+
+/// Login started with an identity provider, waiting for it to redirect back.
+/// Looked up by the `state` sent along.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OidcLogin {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub provider: String,
+    pub state: String,
+    pub nonce: String,
+    /// PKCE code verifier
+    pub verifier: String,
+    /// Page of the web app to land on afterwards
+    pub redirect: String,
+    pub expires_at: DateTime,
+}
+
+/// Account at an identity provider a user logs in with
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Identity {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub provider: String,
+    /// The provider's id for the account
+    pub subject: String,
+    pub user: ObjectId,
+    pub email: Option<String>,
+    pub created_at: DateTime,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OidcStart {
+    #[serde(default)]
+    pub redirect: Option<String>,
+}
+
+/// What an identity provider redirects back with
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+/// A TOTP or recovery code
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwoFactorCode {
+    pub code: String,
+}
+
+/// What an emailed token lets its holder do
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenPurpose {
+    PasswordReset,
+    VerifyEmail,
+}
+
+/// Single use token mailed to a user. The token handed out is
+/// `<id>.<secret>`, only a hash of the secret is kept.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EmailToken {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user: ObjectId,
+    pub purpose: TokenPurpose,
+    /// Address the token was sent to
+    pub email: String,
+    pub secret: String,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VerifyEmail {
+    pub token: String,
 }
 
 impl Display for User {
@@ -94,7 +237,9 @@ pub struct Doc {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
     pub author: Option<Author>,
-    pub collaborators: Vec<ObjectId>,
+    /// Everyone but the author who has access to the doc
+    #[serde(default)]
+    pub permissions: Vec<Permission>,
     pub title: String,
     #[serde(default)]
     pub content: String,
@@ -103,6 +248,83 @@ pub struct Doc {
     #[serde(default)]
     pub starred: Option<bool>,
     pub last_update: Option<DateTime>,
+    #[serde(default)]
+    pub revision: u64,
+}
+
+/// Doc a client asks to create. Its owner and everyone else's access are
+/// not up to the request.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NewDoc {
+    pub title: String,
+    #[serde(default)]
+    pub content: String,
+    #[serde(rename = "type")]
+    pub doc_type: DocType,
+}
+
+impl Doc {
+    /// Role of `user` on the doc, `None` if they have no access to it
+    pub fn role(&self, user: &ObjectId) -> Option<Role> {
+        if self.author.as_ref().and_then(|a| a.id).as_ref() == Some(user) {
+            return Some(Role::Owner);
+        }
+        self.permissions
+            .iter()
+            .find(|p| p.user == *user)
+            .map(|p| p.role)
+    }
+}
+
+/// What a user may do with a doc, from least to most
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Reads the doc and follows edits live
+    Viewer,
+    /// Like a viewer, kept apart for when docs get comments
+    Commenter,
+    /// Edits the doc
+    Editor,
+    /// The author, manages who has access
+    Owner,
+}
+
+impl Role {
+    pub fn can_edit(self) -> bool {
+        self >= Role::Editor
+    }
+}
+
+/// Role a user other than the author has on a doc
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Permission {
+    pub user: ObjectId,
+    pub role: Role,
+}
+
+/// User the owner hands a doc over to
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OwnershipTransfer {
+    pub user: String,
+}
+
+/// Role the owner gives a user on a doc
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoleChange {
+    pub user: String,
+    pub role: Role,
+}
+
+/// Doc a request is about along with the caller's role on it, resolved by
+/// [`crate::middleware::doc_access`]
+#[derive(Debug, Clone)]
+pub struct DocAccess {
+    pub doc: Doc,
+    pub user: ObjectId,
+    pub role: Role,
+    /// Login session of the caller
+    pub session: String,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -141,7 +363,7 @@ impl UploadedDoc {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "lowercase", tag = "update")]
 pub enum UpdateType {
     Insert { data: String },
@@ -155,6 +377,176 @@ pub struct Update {
     #[serde(rename = "type")]
     pub update_type: UpdateType,
     pub timestamp: Option<chrono::DateTime<Utc>>,
+    /// Revision the client based this update on; once applied it is replaced
+    /// by the revision the server assigned
+    #[serde(default)]
+    pub revision: Option<u64>,
+    /// Id the client gave the op, kept so it can recognise its own ops among
+    /// the ones it missed while disconnected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub op_id: Option<u64>,
+}
+
+/// An applied update as recorded in the `changes` collection, positions
+/// counted in scalar values
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Change {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub doc: ObjectId,
+    pub revision: u64,
+    pub author: Option<ObjectId>,
+    pub timestamp: DateTime,
+    pub position: usize,
+    #[serde(rename = "type")]
+    pub update_type: UpdateType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub op_id: Option<u64>,
+}
+
+impl Change {
+    pub fn new(doc: ObjectId, update: &Update) -> Self {
+        Self {
+            id: None,
+            doc,
+            revision: update.revision.unwrap_or_default(),
+            author: update.from,
+            timestamp: update
+                .timestamp
+                .map_or_else(DateTime::now, DateTime::from_chrono),
+            position: update.position,
+            update_type: update.update_type.clone(),
+            op_id: update.op_id,
+        }
+    }
+
+    /// Change inserting the whole content a doc started out with
+    pub fn baseline(doc: ObjectId, author: Option<ObjectId>, content: &str) -> Self {
+        Self {
+            id: None,
+            doc,
+            revision: 1,
+            author,
+            timestamp: DateTime::now(),
+            position: 0,
+            update_type: UpdateType::Insert {
+                data: content.to_string(),
+            },
+            op_id: None,
+        }
+    }
+
+    /// Whether `other` records the same edit, as a retried write of it does
+    pub fn is_same(&self, other: &Change) -> bool {
+        self.doc == other.doc
+            && self.revision == other.revision
+            && self.author == other.author
+            && self.op_id == other.op_id
+            && self.timestamp == other.timestamp
+            && self.position == other.position
+            && self.update_type == other.update_type
+    }
+
+    pub fn to_update(&self) -> Update {
+        Update {
+            position: self.position,
+            from: self.author,
+            update_type: self.update_type.clone(),
+            timestamp: Some(self.timestamp.to_chrono()),
+            revision: Some(self.revision),
+            op_id: self.op_id,
+        }
+    }
+}
+
+/// Claim a node holds on a doc, keyed by the doc id
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Lease {
+    #[serde(rename = "_id")]
+    pub doc: String,
+    pub node: String,
+    pub expires_at: DateTime,
+}
+
+/// Packet on its way to `node`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RoutedPacket {
+    pub node: String,
+    pub packet: Packet,
+    pub created_at: DateTime,
+}
+
+/// Content of a doc stored at a revision so history replay can start from it
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Snapshot {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub doc: ObjectId,
+    pub revision: u64,
+    pub content: String,
+    pub timestamp: DateTime,
+    /// Set on snapshots taken by compaction: the changes up to this revision
+    /// were dropped, so the doc's history before this time is gone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compacted_before: Option<DateTime>,
+}
+
+impl Snapshot {
+    pub fn new(doc: ObjectId, revision: u64, content: String) -> Self {
+        Self {
+            id: None,
+            doc,
+            revision,
+            content,
+            timestamp: DateTime::now(),
+            compacted_before: None,
+        }
+    }
+}
+
+/// A named state of a doc that can be viewed and restored later
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Version {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub doc: ObjectId,
+    pub name: String,
+    pub revision: u64,
+    #[serde(default)]
+    pub content: String,
+    pub author: ObjectId,
+    pub created_at: DateTime,
+}
+
+impl Version {
+    pub fn new(
+        doc: ObjectId,
+        name: String,
+        revision: u64,
+        content: String,
+        author: ObjectId,
+    ) -> Self {
+        Self {
+            id: None,
+            doc,
+            name,
+            revision,
+            content,
+            author,
+            created_at: DateTime::now(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NewVersion {
+    pub name: String,
+}
+
+/// What to compare a version with, another version or the current content when unset
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VersionDiffQuery {
+    pub against: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -164,7 +556,32 @@ pub struct CollabRequest {
     pub author: ObjectId,
     pub from: ObjectId,
     pub doc: ObjectId,
-    pub timestamp: chrono::DateTime<Utc>,
+    #[serde(with = "rfc3339")]
+    pub timestamp: DateTime,
+}
+
+/// A `DateTime` that is an RFC 3339 string in JSON and a bson date in the
+/// database, told apart by whether the format is human readable
+mod rfc3339 {
+    use mongodb::bson::DateTime;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de, ser};
+
+    pub fn serialize<S: Serializer>(date: &DateTime, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return date.serialize(serializer);
+        }
+        date.try_to_rfc3339_string()
+            .map_err(ser::Error::custom)?
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime, D::Error> {
+        if !deserializer.is_human_readable() {
+            return DateTime::deserialize(deserializer);
+        }
+        let date = String::deserialize(deserializer)?;
+        DateTime::parse_rfc3339_str(&date).map_err(de::Error::custom)
+    }
 }
 
 impl CollabRequest {
@@ -174,7 +591,7 @@ impl CollabRequest {
             author,
             from,
             doc,
-            timestamp: DateTime::now().into(),
+            timestamp: DateTime::now(),
         }
     }
 }
@@ -182,48 +599,472 @@ impl CollabRequest {
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "lowercase", tag = "action", content = "request")]
 pub enum CollabRequestHandler {
-    Accept(CollabRequest),
-    Reject(CollabRequest),
+    Accept(CollabRequestRef),
+    Reject(CollabRequestRef),
 }
 
-impl From<Update> for ws::Message {
-    fn from(value: Update) -> Self {
-        match to_string(&value) {
-            Ok(data) => Message::text(data),
-            Err(e) => {
-                log::error!("{}", e);
-                panic!("cannot convert to Message")
-            }
-        }
-    }
+/// Collab request a client acts on, only its id is taken from the client
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CollabRequestRef {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
 }
-impl From<ws::Message> for Update {
-    fn from(value: ws::Message) -> Self {
-        let data = from_str::<Update>(value.to_text().unwrap());
-        match data {
-            Ok(u) => u,
-            Err(e) => {
-                log::error!("{}", e);
-                panic!("cannot convert to Message")
-            }
-        }
+
+/// Filters and page of the collab requests an owner lists
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CollabRequestQuery {
+    pub doc: Option<String>,
+    /// Only requests made before this time
+    pub before: Option<chrono::DateTime<Utc>>,
+    /// Only requests made after this time
+    pub after: Option<chrono::DateTime<Utc>>,
+    #[serde(default)]
+    pub skip: u64,
+    pub limit: Option<i64>,
+}
+
+/// Access the owner of a doc offers to an email address
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Invitation {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub doc: ObjectId,
+    /// Title of the doc, so invitees know what they are invited to
+    pub title: String,
+    pub email: String,
+    pub role: Role,
+    pub invited_by: ObjectId,
+    /// Account of the invitee, set once they have signed up
+    pub user: Option<ObjectId>,
+    pub created_at: DateTime,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NewInvitation {
+    pub email: String,
+    pub role: Role,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "lowercase", tag = "action", content = "invitation")]
+pub enum InvitationResponse {
+    Accept(String),
+    Decline(String),
+}
+
+/// Secret link granting a role on a doc to whoever opens it. The token handed
+/// out is `<id>.<secret>`, only a hash of the secret is kept.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ShareLink {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub doc: ObjectId,
+    pub role: Role,
+    /// Hash of the secret in the link's token, see `utils::hash_token`
+    pub secret: String,
+    /// Hash of the password needed to open the link, if any
+    pub password: Option<String>,
+    pub expires_at: Option<DateTime>,
+    pub created_by: ObjectId,
+    pub created_at: DateTime,
+}
+
+impl ShareLink {
+    /// What the owner gets to see of a link
+    pub fn summary(&self) -> serde_json::Value {
+        json!({
+            "id":self.id,
+            "role":self.role,
+            "expires_at":self.expires_at,
+            "password":self.password.is_some(),
+            "created_at":self.created_at
+        })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|e| e <= DateTime::now())
     }
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NewShareLink {
+    pub role: Role,
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<Utc>>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OpenShareLink {
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// Which representation a client edits the doc through
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EditMode {
+    /// Position based `Update`s, transformed by the server
+    #[default]
+    Ot,
+    /// `CrdtOp`s that merge without a central transform
+    Crdt,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct EditQuery {
+    #[serde(default)]
+    pub mode: EditMode,
+    #[serde(default)]
+    pub unit: PositionUnit,
+    /// Version of the protocol the client speaks
+    #[serde(default)]
+    pub protocol: u32,
+    /// Last revision the client saw before its connection dropped. It is sent
+    /// the ops it missed instead of the whole doc.
+    #[serde(default)]
+    pub resume: Option<u64>,
+}
+
+/// Message sent over the edit websocket by clients speaking protocol version 0
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum EditMessage {
+    Crdt { crdt: Vec<CrdtOp> },
+    Presence { presence: PresenceUpdate },
+    Update(Update),
+}
+
+/// Persisted crdt state of a doc, keyed by the doc id
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CrdtState {
+    #[serde(rename = "_id")]
+    pub doc: ObjectId,
+    #[serde(flatten)]
+    pub rga: Rga,
+}
+
+#[derive(Clone)]
 pub struct Client {
+    /// Id of the user
     pub id: String,
+    /// Id of this connection, a user may have the doc open more than once
+    pub conn: String,
     pub sender: Sender<Message>,
-    pub author: bool,
+    /// Login session the connection was opened with
+    pub session: String,
+    pub role: Role,
+    pub mode: EditMode,
+    pub unit: PositionUnit,
+    pub protocol: u32,
+    pub name: String,
+    pub color: String,
+    pub cursor: Option<Cursor>,
 }
 
+/// Colors handed out to clients that do not pick their own
+const PRESENCE_COLORS: [&str; 8] = [
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#469990",
+];
+
 impl Client {
     pub fn new(id: impl IntoObjectId, sender: Sender<Message>) -> Client {
+        let id = id.into_objetc_id();
+        let color = id.bytes().iter().map(|b| *b as usize).sum::<usize>() % PRESENCE_COLORS.len();
         Client {
-            id: id.into_objetc_id().to_hex(),
+            id: id.to_hex(),
+            conn: ObjectId::new().to_hex(),
             sender,
-            author: false,
+            session: String::new(),
+            role: Role::Viewer,
+            mode: EditMode::default(),
+            unit: PositionUnit::default(),
+            protocol: 0,
+            name: String::new(),
+            color: PRESENCE_COLORS[color].to_string(),
+            cursor: None,
         }
     }
+
+    /// Queue a message for the client, `false` if its connection is gone or
+    /// it has fallen too far behind to keep up
+    pub fn send(&self, msg: &ServerMessage) -> bool {
+        protocol::send(&self.sender, self.protocol, msg)
+    }
+
+    /// Who the client is, as shown to everyone else
+    pub fn identity(&self) -> serde_json::Value {
+        json!({"conn":self.conn, "user":self.id, "name":self.name, "color":self.color, "role":self.role})
+    }
+
+    /// Presence of the client as sent to a client counting positions in `unit`
+    pub fn presence(&self, content: &str, unit: PositionUnit) -> serde_json::Value {
+        let mut presence = self.identity();
+        let cursor = self
+            .cursor
+            .map(|c| c.map(|p| text::from_scalar(content, p, unit)));
+        presence["cursor"] = json!(cursor.map(|c| c.position));
+        presence["selection"] = json!(cursor.and_then(|c| c.selection));
+        presence
+    }
+}
+
+/// Range of text between two positions
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Caret and selection of a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub position: usize,
+    pub selection: Option<Range>,
+}
+
+impl Cursor {
+    pub fn map(self, f: impl Fn(usize) -> usize) -> Cursor {
+        Cursor {
+            position: f(self.position),
+            selection: self.selection.map(|r| Range {
+                start: f(r.start),
+                end: f(r.end),
+            }),
+        }
+    }
+
+    pub fn try_map<E>(self, f: impl Fn(usize) -> Result<usize, E>) -> Result<Cursor, E> {
+        Ok(Cursor {
+            position: f(self.position)?,
+            selection: match self.selection {
+                Some(r) => Some(Range {
+                    start: f(r.start)?,
+                    end: f(r.end)?,
+                }),
+                None => None,
+            },
+        })
+    }
+}
+
+/// Caret and selection a client reports, counted in its position unit
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PresenceUpdate {
+    pub cursor: usize,
+    #[serde(default)]
+    pub selection: Option<Range>,
+    #[serde(default)]
+    pub color: Option<String>,
+    /// Revision the positions refer to, the latest one when unset
+    #[serde(default)]
+    pub revision: Option<u64>,
+}
+
+/// Whether `color` is a css hex color like `#1e90ff`
+fn is_hex_color(color: &str) -> bool {
+    color
+        .strip_prefix('#')
+        .is_some_and(|hex| matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Number of committed updates kept per doc for transforming late updates
+pub const HISTORY_LIMIT: usize = 1024;
+
+/// Live editing state of a doc shared by every connected client
+pub struct DocSession {
+    pub clients: Vec<Client>,
+    history: VecDeque<AppliedUpdate>,
+}
+
+impl DocSession {
+    pub fn new() -> Self {
+        DocSession {
+            clients: Vec::new(),
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Take a connection off the session
+    pub fn remove(&mut self, conn: &str) -> Option<Client> {
+        let index = self.clients.iter().position(|c| c.conn == conn)?;
+        Some(self.clients.remove(index))
+    }
+
+    /// Drop connections that are gone or too slow and tell everyone else they left
+    pub fn evict(&mut self, mut dead: Vec<String>) {
+        while !dead.is_empty() {
+            let (gone, alive) = self.clients.drain(..).partition(|c| dead.contains(&c.conn));
+            self.clients = alive;
+            dead.clear();
+            for client in gone {
+                log::debug!("dropping connection {}", client.conn);
+                let msg = ServerMessage::Presence {
+                    event: PresenceEvent::Leave,
+                    client: client.identity(),
+                };
+                for other in &self.clients {
+                    if !other.send(&msg) {
+                        dead.push(other.conn.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send a committed change to every client but the `from` connection, in
+    /// the form it edits with
+    pub fn broadcast(&mut self, from: &str, update: &AppliedUpdate, ops: &[CrdtOp]) {
+        let mut dead = Vec::new();
+        for client in &self.clients {
+            if client.conn == from {
+                continue;
+            }
+            let op = match client.mode {
+                EditMode::Ot => Op::Update {
+                    update: update.in_unit(client.unit).clone(),
+                },
+                EditMode::Crdt => Op::Crdt { crdt: ops.to_vec() },
+            };
+            if !client.send(&ServerMessage::Op(op)) {
+                dead.push(client.conn.clone());
+            }
+        }
+        self.evict(dead);
+    }
+
+    /// Send the presence of the client at `index` to every other client
+    pub fn broadcast_presence(&mut self, index: usize, content: &str) {
+        let mut dead = Vec::new();
+        let client = &self.clients[index];
+        for other in &self.clients {
+            if other.conn == client.conn {
+                continue;
+            }
+            let msg = ServerMessage::Presence {
+                event: PresenceEvent::Update,
+                client: client.presence(content, other.unit),
+            };
+            if !other.send(&msg) {
+                dead.push(other.conn.clone());
+            }
+        }
+        self.evict(dead);
+    }
+
+    /// Tell every other client that `client` joined or left
+    pub fn announce(&mut self, client: &Client, event: PresenceEvent) {
+        let mut dead = Vec::new();
+        let msg = ServerMessage::Presence {
+            event,
+            client: client.identity(),
+        };
+        for other in &self.clients {
+            if other.conn != client.conn && !other.send(&msg) {
+                dead.push(other.conn.clone());
+            }
+        }
+        self.evict(dead);
+    }
+
+    /// Update the presence of the `conn` connection, returning its index.
+    /// Positions are rebased from the revision they refer to onto `content`.
+    pub fn set_presence(
+        &mut self,
+        conn: &str,
+        presence: PresenceUpdate,
+        unit: PositionUnit,
+        content: &str,
+        revision: u64,
+    ) -> Result<usize, Error> {
+        let Some(index) = self.clients.iter().position(|c| c.conn == conn) else {
+            return Err(Error::from("not connected to the document"));
+        };
+        if let Some(color) = &presence.color
+            && !is_hex_color(color)
+        {
+            return Err(Error::from("color must be a hex color"));
+        }
+        let missed = self.missed(presence.revision.unwrap_or(revision), revision, unit)?;
+        let client_id = &self.clients[index].id;
+        let cursor = missed.iter().fold(
+            Cursor {
+                position: presence.cursor,
+                selection: presence.selection,
+            },
+            |cursor, u| {
+                let own = u.from.is_some_and(|f| f.to_hex() == *client_id);
+                cursor.map(|p| ot::transform_position(p, u, unit, own))
+            },
+        );
+        let client = &mut self.clients[index];
+        client.cursor = Some(cursor.try_map(|p| text::to_scalar(content, p, unit))?);
+        if let Some(color) = presence.color {
+            client.color = color;
+        }
+        Ok(index)
+    }
+
+    /// Transform an incoming update counted in `unit` against everything
+    /// committed since its base revision, `revision` being the current one
+    pub fn rebase(
+        &self,
+        update: Update,
+        unit: PositionUnit,
+        revision: u64,
+    ) -> Result<Vec<Update>, Error> {
+        let missed = self.missed(update.revision.unwrap_or(revision), revision, unit)?;
+        Ok(ot::transform_all(update, missed, unit)?)
+    }
+
+    /// Updates committed after `base` counted in `unit`, `revision` being the current one
+    pub fn missed(
+        &self,
+        base: u64,
+        revision: u64,
+        unit: PositionUnit,
+    ) -> Result<Vec<&Update>, Error> {
+        if base > revision {
+            return Err(Error::from("revision is ahead of the document"));
+        }
+        let missed: Vec<&Update> = self
+            .history
+            .iter()
+            .map(|a| a.in_unit(unit))
+            .filter(|u| u.revision > Some(base))
+            .collect();
+        if missed.len() as u64 != revision - base {
+            return Err(Error::from("revision too old, reload the document"));
+        }
+        Ok(missed)
+    }
+
+    /// Revision of the oldest update kept, if any
+    pub fn oldest(&self) -> Option<u64> {
+        self.history.front().and_then(|a| a.scalar.revision)
+    }
+
+    /// Prepend updates older than anything kept, e.g. read back from the change log
+    pub fn backfill(&mut self, older: Vec<AppliedUpdate>) {
+        for update in older.into_iter().rev() {
+            self.history.push_front(update);
+        }
+    }
+
+    /// Record an applied update, moving every client's cursor along with it
+    pub fn commit(&mut self, update: AppliedUpdate) {
+        for client in &mut self.clients {
+            let own = update.scalar.from.is_some_and(|f| f.to_hex() == client.id);
+            client.cursor = client.cursor.map(|c| {
+                c.map(|p| ot::transform_position(p, &update.scalar, PositionUnit::Scalar, own))
+            });
+        }
+        while self.history.len() >= HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+        self.history.push_back(update);
+    }
 }
 
 macro_rules! impl_error {
@@ -260,26 +1101,118 @@ impl Display for Error {
 
 impl_error! {
     mongodb::error::Error,
+    bson::error::Error,
+    jsonwebtoken::errors::Error,
     argon2::password_hash::Error,
+    lettre::error::Error,
+    lettre::address::AddressError,
+    lettre::transport::smtp::Error,
     axum::Error,
+    PositionError,
+    serde_json::Error,
     String,
     &str
 }
 
 // JWT Claims
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String,
+    /// Login session the token was issued for
+    pub sid: String,
     exp: u64,
 }
 
 impl Claims {
-    pub fn new(sub: String, exp: u64) -> Self {
-        Claims { sub, exp }
+    pub fn new(sub: String, sid: String, exp: u64) -> Self {
+        Claims { sub, sid, exp }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct DocQuery {
-    pub id: String,
+/// A device a user is logged in on. It is kept alive by a refresh token that
+/// changes on every use, only a hash of the current one is kept.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Session {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user: ObjectId,
+    pub refresh: String,
+    /// Hash of the refresh token `refresh` replaced
+    #[serde(default)]
+    pub previous: Option<String>,
+    /// User agent of the device
+    pub device: String,
+    pub ip: String,
+    pub created_at: DateTime,
+    pub last_seen: DateTime,
+    pub expires_at: DateTime,
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+impl Session {
+    /// What the user gets to see of a session
+    pub fn summary(&self, current: bool) -> serde_json::Value {
+        json!({
+            "id":self.id,
+            "device":self.device,
+            "ip":self.ip,
+            "created_at":self.created_at,
+            "last_seen":self.last_seen,
+            "current":current
+        })
+    }
+}
+
+/// Point in a doc's history, by revision or by time
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryQuery {
+    pub revision: Option<u64>,
+    pub at: Option<chrono::DateTime<Utc>>,
+}
+
+/// Two points in a doc's history to compare, each by revision or named version.
+/// `from` defaults to the empty doc and `to` to its latest revision.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RevisionDiffQuery {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub from_version: Option<String>,
+    pub to_version: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collab_request_timestamp_is_a_string_in_json_and_a_date_in_bson() {
+        let mut req = CollabRequest::new(ObjectId::new(), ObjectId::new(), ObjectId::new());
+        req.timestamp = DateTime::from_millis(1_700_000_000_000);
+        let json = serde_json::to_value(&req).unwrap();
+        assert_eq!(json["timestamp"], "2023-11-14T22:13:20Z");
+        let back: CollabRequest = serde_json::from_value(json).unwrap();
+        assert_eq!(back.timestamp, req.timestamp);
+
+        let raw = bson::serialize_to_raw_document_buf(&req).unwrap();
+        assert!(raw.get_datetime("timestamp").is_ok());
+        let back: CollabRequest = bson::deserialize_from_slice(raw.as_bytes()).unwrap();
+        assert_eq!(back.timestamp, req.timestamp);
+    }
+
+    #[test]
+    fn a_change_is_only_the_same_as_a_copy_of_itself() {
+        let change = Change::baseline(ObjectId::new(), Some(ObjectId::new()), "hello");
+        let stored: Change =
+            bson::deserialize_from_document(bson::serialize_to_document(&change).unwrap()).unwrap();
+        assert!(change.is_same(&stored));
+        let mut other = change.clone();
+        other.update_type = UpdateType::Insert {
+            data: "world".to_string(),
+        };
+        assert!(!change.is_same(&other));
+        let mut other = change.clone();
+        other.author = Some(ObjectId::new());
+        assert!(!change.is_same(&other));
+    }
 }
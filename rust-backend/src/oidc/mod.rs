@@ -0,0 +1,572 @@
+use std::{collections::HashMap, env, sync::Arc, time::Duration};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation, decode, decode_header,
+    jwk::{Jwk, JwkSet},
+};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+
+use crate::{models::Error, utils};
+
+/// Scopes asked for when `OIDC_<NAME>_SCOPES` is unset
+const DEFAULT_SCOPES: &str = "openid email profile";
+/// Where the API is reachable when `API_URL` is unset, for redirect URIs
+const DEFAULT_API_URL: &str = "http://localhost:7878";
+/// Longest wait to connect to a provider
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest wait for a provider to answer, so a stuck one can't hold logins up
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Signing algorithms accepted on ID tokens. Symmetric ones are left out, the
+/// client secret is not meant to be a signing key here.
+const ID_TOKEN_ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::EdDSA,
+];
+
+/// The parts of a provider's discovery document the login flow needs
+#[derive(Deserialize, Debug, Clone)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    #[serde(default)]
+    token_endpoint_auth_methods_supported: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// What the provider says about the user, from a verified ID token
+#[derive(Deserialize, Debug, Clone)]
+pub struct IdClaims {
+    pub sub: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Some providers send this as a string
+    #[serde(default)]
+    email_verified: Option<serde_json::Value>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+}
+
+impl IdClaims {
+    /// Email the provider vouches the user owns, if any
+    pub fn verified_email(&self) -> Option<&str> {
+        let verified = match &self.email_verified {
+            Some(serde_json::Value::Bool(v)) => *v,
+            Some(serde_json::Value::String(v)) => v == "true",
+            _ => false,
+        };
+        self.email.as_deref().filter(|_| verified)
+    }
+}
+
+/// An identity provider users can log in with
+pub struct Provider {
+    pub name: String,
+    /// Name shown on the login button
+    pub label: String,
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    scopes: String,
+    redirect_uri: String,
+    metadata: RwLock<Option<Metadata>>,
+    jwks: RwLock<JwkSet>,
+}
+
+/// Identity providers configured, by name
+pub struct Oidc {
+    providers: HashMap<String, Arc<Provider>>,
+    http: reqwest::Client,
+}
+
+/// Load identity providers from the environment. `OIDC_PROVIDERS` lists
+/// their names, comma separated, and each is configured with:
+///
+/// - `OIDC_<NAME>_ISSUER`: issuer URL, its discovery document is read from
+///   `<issuer>/.well-known/openid-configuration`
+/// - `OIDC_<NAME>_CLIENT_ID`, `OIDC_<NAME>_CLIENT_SECRET`: the client, the
+///   secret is left out for public clients
+/// - `OIDC_<NAME>_LABEL`: name shown to users, the provider name by default
+/// - `OIDC_<NAME>_SCOPES`: `openid email profile` by default
+///
+/// The redirect URI to register is `<API_URL>/api/auth/oidc/<name>/callback`.
+/// Any issuer works, so a local mock provider can stand in for tests.
+pub fn init() -> Result<Arc<Oidc>, Error> {
+    let api_url = env::var("API_URL").unwrap_or_else(|_| DEFAULT_API_URL.to_string());
+    let mut providers = HashMap::new();
+    for name in env::var("OIDC_PROVIDERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+    {
+        let name = name.to_lowercase();
+        let var = |key: &str| env::var(format!("OIDC_{}_{}", name.to_uppercase(), key));
+        let required = |key: &str| {
+            var(key).map_err(|_| {
+                Error::new(format!("OIDC_{}_{} must be set", name.to_uppercase(), key))
+            })
+        };
+        let provider = Provider {
+            label: var("LABEL").unwrap_or_else(|_| name.clone()),
+            issuer: required("ISSUER")?.trim_end_matches('/').to_string(),
+            client_id: required("CLIENT_ID")?,
+            client_secret: var("CLIENT_SECRET").ok().filter(|s| !s.is_empty()),
+            scopes: var("SCOPES").unwrap_or_else(|_| DEFAULT_SCOPES.to_string()),
+            redirect_uri: format!(
+                "{}/api/auth/oidc/{}/callback",
+                api_url.trim_end_matches('/'),
+                name
+            ),
+            metadata: RwLock::new(None),
+            jwks: RwLock::new(JwkSet { keys: Vec::new() }),
+            name: name.clone(),
+        };
+        log::info!("single sign-on with {} at {}", name, provider.issuer);
+        providers.insert(name, Arc::new(provider));
+    }
+    let http = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .map_err(|e| Error::new(format!("could not create http client: {}", e)))?;
+    Ok(Arc::new(Oidc { providers, http }))
+}
+
+impl Oidc {
+    pub fn provider(&self, name: &str) -> Option<&Arc<Provider>> {
+        self.providers.get(name)
+    }
+
+    pub fn providers(&self) -> impl Iterator<Item = &Arc<Provider>> {
+        self.providers.values()
+    }
+
+    /// Where to send the user to log in with a provider
+    pub async fn authorize_url(
+        &self,
+        provider: &Provider,
+        state: &str,
+        nonce: &str,
+        verifier: &str,
+    ) -> Result<String, Error> {
+        let metadata = self.metadata(provider).await?;
+        let url = Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", provider.client_id.as_str()),
+                ("redirect_uri", provider.redirect_uri.as_str()),
+                ("scope", provider.scopes.as_str()),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", &code_challenge(verifier)),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| Error::new(format!("invalid authorization endpoint: {}", e)))?;
+        Ok(url.to_string())
+    }
+
+    /// Trade the code the provider redirected back with for the user's
+    /// verified identity
+    pub async fn exchange(
+        &self,
+        provider: &Provider,
+        code: &str,
+        verifier: &str,
+        nonce: &str,
+    ) -> Result<IdClaims, Error> {
+        let metadata = self.metadata(provider).await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", provider.redirect_uri.as_str()),
+            ("code_verifier", verifier),
+        ];
+        let mut request = self.http.post(&metadata.token_endpoint);
+        match &provider.client_secret {
+            // basic is the default when a provider does not say
+            Some(secret)
+                if metadata.token_endpoint_auth_methods_supported.is_empty()
+                    || metadata
+                        .token_endpoint_auth_methods_supported
+                        .iter()
+                        .any(|m| m == "client_secret_basic") =>
+            {
+                request = request.basic_auth(&provider.client_id, Some(secret));
+            }
+            Some(secret) => {
+                form.push(("client_id", provider.client_id.as_str()));
+                form.push(("client_secret", secret.as_str()));
+            }
+            None => form.push(("client_id", provider.client_id.as_str())),
+        }
+        let res = request
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| Error::new(format!("token request failed: {}", e)))?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await.unwrap_or_default();
+            return Err(Error::new(format!(
+                "token request failed with {}: {}",
+                status, body
+            )));
+        }
+        let tokens: TokenResponse = res
+            .json()
+            .await
+            .map_err(|e| Error::new(format!("invalid token response: {}", e)))?;
+        let claims = self
+            .verify_id_token(provider, &metadata, &tokens.id_token)
+            .await?;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(Error::from("id token nonce does not match"));
+        }
+        Ok(claims)
+    }
+
+    async fn metadata(&self, provider: &Provider) -> Result<Metadata, Error> {
+        if let Some(metadata) = provider.metadata.read().await.as_ref() {
+            return Ok(metadata.clone());
+        }
+        let url = format!("{}/.well-known/openid-configuration", provider.issuer);
+        let metadata: Metadata = self
+            .get_json(&url)
+            .await
+            .map_err(|e| Error::new(format!("could not discover {}: {}", provider.name, e)))?;
+        if metadata.issuer.trim_end_matches('/') != provider.issuer {
+            return Err(Error::new(format!(
+                "{} claims to be issuer {}",
+                provider.issuer, metadata.issuer
+            )));
+        }
+        *provider.metadata.write().await = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    /// Key an ID token was signed with, fetching the provider's keys again
+    /// when it is not known yet as providers rotate them
+    async fn signing_key(
+        &self,
+        provider: &Provider,
+        metadata: &Metadata,
+        kid: Option<&str>,
+    ) -> Result<Jwk, Error> {
+        let find = |jwks: &JwkSet| match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        };
+        if let Some(jwk) = find(&*provider.jwks.read().await) {
+            return Ok(jwk);
+        }
+        let jwks: JwkSet = self.get_json(&metadata.jwks_uri).await?;
+        let jwk = find(&jwks);
+        *provider.jwks.write().await = jwks;
+        jwk.ok_or(Error::from("id token is signed with an unknown key"))
+    }
+
+    async fn verify_id_token(
+        &self,
+        provider: &Provider,
+        metadata: &Metadata,
+        token: &str,
+    ) -> Result<IdClaims, Error> {
+        let header = decode_header(token)?;
+        if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+            return Err(Error::new(format!(
+                "id token is signed with {:?}",
+                header.alg
+            )));
+        }
+        let jwk = self
+            .signing_key(provider, metadata, header.kid.as_deref())
+            .await?;
+        let key = DecodingKey::from_jwk(&jwk)?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&provider.client_id]);
+        validation.set_issuer(&[&metadata.issuer]);
+        Ok(decode::<IdClaims>(token, &key, &validation)?.claims)
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, Error> {
+        self.http
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| Error::new(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| Error::new(e.to_string()))
+    }
+}
+
+/// New PKCE code verifier
+pub fn code_verifier() -> String {
+    utils::random_token()
+}
+
+/// S256 PKCE challenge of a verifier
+fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::SystemTime};
+
+    use axum::{
+        Form, Json, Router,
+        extract::{Query, State},
+        http::StatusCode,
+        response::{IntoResponse, Redirect},
+        routing::{get, post},
+    };
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    /// RFC 8032 test key 1, as PKCS#8 DER
+    const SIGNING_KEY: &str = "302e020100300506032b6570042204209d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const PUBLIC_KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    const CLIENT_ID: &str = "docsly";
+    const CODE: &str = "authorization-code";
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn claims(email: Option<&str>, verified: Option<Value>) -> IdClaims {
+        IdClaims {
+            sub: "subject".to_string(),
+            email: email.map(str::to_string),
+            email_verified: verified,
+            name: None,
+            nonce: None,
+        }
+    }
+
+    #[test]
+    fn code_challenge_matches_rfc_7636() {
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn verified_email_needs_the_provider_to_vouch_for_it() {
+        let email = Some("ada@example.com");
+        assert_eq!(
+            claims(email, Some(json!(true))).verified_email(),
+            Some("ada@example.com")
+        );
+        assert_eq!(
+            claims(email, Some(json!("true"))).verified_email(),
+            Some("ada@example.com")
+        );
+        assert_eq!(claims(email, Some(json!(false))).verified_email(), None);
+        assert_eq!(claims(email, Some(json!("false"))).verified_email(), None);
+        assert_eq!(claims(email, Some(json!(1))).verified_email(), None);
+        assert_eq!(claims(email, None).verified_email(), None);
+        assert_eq!(claims(None, Some(json!(true))).verified_email(), None);
+    }
+
+    /// What the mock provider remembers of the authorization request
+    #[derive(Default)]
+    struct Issuer {
+        url: String,
+        challenge: Option<String>,
+        nonce: Option<String>,
+    }
+
+    type Shared = Arc<Mutex<Issuer>>;
+
+    async fn discovery(State(issuer): State<Shared>) -> Json<Value> {
+        let url = issuer.lock().unwrap().url.clone();
+        Json(json!({
+            "issuer":url,
+            "authorization_endpoint":format!("{}/authorize", url),
+            "token_endpoint":format!("{}/token", url),
+            "jwks_uri":format!("{}/jwks", url),
+            "token_endpoint_auth_methods_supported":["none"]
+        }))
+    }
+
+    async fn jwks() -> Json<Value> {
+        Json(json!({
+            "keys":[{
+                "kty":"OKP",
+                "crv":"Ed25519",
+                "kid":"test",
+                "alg":"EdDSA",
+                "use":"sig",
+                "x":URL_SAFE_NO_PAD.encode(hex(PUBLIC_KEY))
+            }]
+        }))
+    }
+
+    async fn authorize(
+        State(issuer): State<Shared>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> Redirect {
+        assert_eq!(query["response_type"], "code");
+        assert_eq!(query["client_id"], CLIENT_ID);
+        assert_eq!(query["code_challenge_method"], "S256");
+        let mut issuer = issuer.lock().unwrap();
+        issuer.challenge = Some(query["code_challenge"].clone());
+        issuer.nonce = Some(query["nonce"].clone());
+        let redirect = Url::parse_with_params(
+            &query["redirect_uri"],
+            &[("code", CODE), ("state", query["state"].as_str())],
+        )
+        .unwrap();
+        Redirect::to(redirect.as_str())
+    }
+
+    async fn token(
+        State(issuer): State<Shared>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> impl IntoResponse {
+        let issuer = issuer.lock().unwrap();
+        if form.get("code").map(String::as_str) != Some(CODE)
+            || form.get("client_id").map(String::as_str) != Some(CLIENT_ID)
+            || form.get("code_verifier").map(|v| code_challenge(v)) != issuer.challenge
+        {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error":"invalid_grant"})),
+            );
+        }
+        let exp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some("test".to_string());
+        let id_token = encode(
+            &header,
+            &json!({
+                "iss":issuer.url,
+                "aud":CLIENT_ID,
+                "sub":"subject",
+                "exp":exp,
+                "email":"Ada@Example.com",
+                "email_verified":true,
+                "nonce":issuer.nonce
+            }),
+            &EncodingKey::from_ed_der(&hex(SIGNING_KEY)),
+        )
+        .unwrap();
+        (StatusCode::OK, Json(json!({"id_token":id_token})))
+    }
+
+    /// Mock provider on a local port, along with a client set up to use it
+    async fn mock_issuer() -> (Oidc, Arc<Provider>) {
+        let issuer = Shared::default();
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/authorize", get(authorize))
+            .route("/token", post(token))
+            .with_state(issuer.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        issuer.lock().unwrap().url = url.clone();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let provider = Arc::new(Provider {
+            name: "mock".to_string(),
+            label: "Mock".to_string(),
+            issuer: url,
+            client_id: CLIENT_ID.to_string(),
+            client_secret: None,
+            scopes: DEFAULT_SCOPES.to_string(),
+            redirect_uri: format!("{}/api/auth/oidc/mock/callback", DEFAULT_API_URL),
+            metadata: RwLock::new(None),
+            jwks: RwLock::new(JwkSet { keys: Vec::new() }),
+        });
+        let oidc = Oidc {
+            providers: HashMap::from([("mock".to_string(), provider.clone())]),
+            http: reqwest::Client::new(),
+        };
+        (oidc, provider)
+    }
+
+    /// Follow the authorization URL like a browser would, returning the
+    /// code and state the provider redirects back to the callback with
+    async fn log_in_at(url: &str) -> (String, String) {
+        let browser = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let res = browser.get(url).send().await.unwrap();
+        assert!(res.status().is_redirection());
+        let location = Url::parse(res.headers()["location"].to_str().unwrap()).unwrap();
+        assert!(location.as_str().starts_with(DEFAULT_API_URL));
+        let params: HashMap<_, _> = location.query_pairs().into_owned().collect();
+        (params["code"].clone(), params["state"].clone())
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_a_provider() {
+        let (oidc, provider) = mock_issuer().await;
+        let verifier = code_verifier();
+        let url = oidc
+            .authorize_url(&provider, "state", "nonce", &verifier)
+            .await
+            .unwrap();
+        let (code, state) = log_in_at(&url).await;
+        assert_eq!(state, "state");
+        let claims = oidc
+            .exchange(&provider, &code, &verifier, "nonce")
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "subject");
+        assert_eq!(claims.verified_email(), Some("Ada@Example.com"));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_verifier_or_nonce() {
+        let (oidc, provider) = mock_issuer().await;
+        let verifier = code_verifier();
+        let url = oidc
+            .authorize_url(&provider, "state", "nonce", &verifier)
+            .await
+            .unwrap();
+        let (code, _) = log_in_at(&url).await;
+        assert!(
+            oidc.exchange(&provider, &code, &code_verifier(), "nonce")
+                .await
+                .is_err()
+        );
+        assert!(
+            oidc.exchange(&provider, &code, &verifier, "another nonce")
+                .await
+                .is_err()
+        );
+    }
+}
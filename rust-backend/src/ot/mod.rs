@@ -0,0 +1,237 @@
+use crate::{
+    models::{Update, UpdateType},
+    text::{PositionError, PositionUnit},
+};
+
+/// Length of the text an update inserts or removes, counted in `unit`
+pub fn span(update: &Update, unit: PositionUnit) -> usize {
+    match update.update_type {
+        UpdateType::Insert { ref data } => unit.measure(data),
+        UpdateType::Delete { length } => length,
+    }
+}
+
+/// `position + length`, or an error when a client sent a span too far out to count
+fn end_of(position: usize, length: usize) -> Result<usize, PositionError> {
+    position
+        .checked_add(length)
+        .ok_or(PositionError::OutOfBounds { position, length })
+}
+
+/// Transform `update` so it applies on top of `applied`, an update that was
+/// concurrent with it and has already been committed. Both are counted in `unit`.
+///
+/// A delete that straddles a concurrent insert is split in two so the inserted
+/// text survives. The returned updates are ordered by descending position so
+/// they can be applied one after another without shifting each other.
+pub fn transform(
+    mut update: Update,
+    applied: &Update,
+    unit: PositionUnit,
+) -> Result<Vec<Update>, PositionError> {
+    let (pos, applied_pos, applied_len) = (update.position, applied.position, span(applied, unit));
+    let applied_end = end_of(applied_pos, applied_len)?;
+    Ok(match (&mut update.update_type, &applied.update_type) {
+        // ties go to the committed insert so every client ends up with the same order
+        (UpdateType::Insert { .. }, UpdateType::Insert { .. }) => {
+            if applied_pos <= pos {
+                update.position = end_of(pos, applied_len)?;
+            }
+            vec![update]
+        }
+        (UpdateType::Insert { .. }, UpdateType::Delete { .. }) => {
+            if pos >= applied_end {
+                update.position -= applied_len;
+            } else if pos > applied_pos {
+                update.position = applied_pos;
+            }
+            vec![update]
+        }
+        (UpdateType::Delete { length }, UpdateType::Insert { .. }) => {
+            let end = end_of(pos, *length)?;
+            if applied_pos <= pos {
+                update.position = end_of(pos, applied_len)?;
+                vec![update]
+            } else if applied_pos >= end {
+                vec![update]
+            } else {
+                *length = end - applied_pos;
+                let mut head = update.clone();
+                head.update_type = UpdateType::Delete {
+                    length: applied_pos - pos,
+                };
+                update.position = applied_end;
+                vec![update, head]
+            }
+        }
+        (UpdateType::Delete { length }, UpdateType::Delete { .. }) => {
+            let end = end_of(pos, *length)?;
+            if applied_end <= pos {
+                update.position -= applied_len;
+            } else if applied_pos < end {
+                let overlap = end.min(applied_end) - pos.max(applied_pos);
+                *length -= overlap;
+                update.position = pos.min(applied_pos);
+            }
+            if *length == 0 { vec![] } else { vec![update] }
+        }
+    })
+}
+
+/// Transform `update` against every committed update in `history`, oldest first
+pub fn transform_all<'a>(
+    update: Update,
+    history: impl IntoIterator<Item = &'a Update>,
+    unit: PositionUnit,
+) -> Result<Vec<Update>, PositionError> {
+    history
+        .into_iter()
+        .try_fold(vec![update], |pending, applied| {
+            let mut out = Vec::with_capacity(pending.len());
+            for u in pending {
+                out.extend(transform(u, applied, unit)?);
+            }
+            Ok(out)
+        })
+}
+
+/// Move a caret at `position` so it stays next to the same text once `applied`
+/// is committed. Text inserted right at the caret only pushes it along when
+/// `own` is set, i.e. when the caret's owner typed it.
+pub fn transform_position(
+    position: usize,
+    applied: &Update,
+    unit: PositionUnit,
+    own: bool,
+) -> usize {
+    let applied_pos = applied.position;
+    match applied.update_type {
+        UpdateType::Insert { .. } if applied_pos < position || (own && applied_pos == position) => {
+            position + span(applied, unit)
+        }
+        UpdateType::Delete { length } if applied_pos < position => {
+            position - length.min(position - applied_pos)
+        }
+        _ => position,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(position: usize, data: &str) -> Update {
+        Update {
+            position,
+            from: None,
+            update_type: UpdateType::Insert {
+                data: data.to_string(),
+            },
+            timestamp: None,
+            revision: None,
+            op_id: None,
+        }
+    }
+
+    fn delete(position: usize, length: usize) -> Update {
+        Update {
+            position,
+            from: None,
+            update_type: UpdateType::Delete { length },
+            timestamp: None,
+            revision: None,
+            op_id: None,
+        }
+    }
+
+    fn apply(text: &str, update: &Update) -> String {
+        let mut chars: Vec<char> = text.chars().collect();
+        match update.update_type {
+            UpdateType::Insert { ref data } => {
+                chars.splice(update.position..update.position, data.chars());
+            }
+            UpdateType::Delete { length } => {
+                chars.drain(update.position..update.position + length);
+            }
+        }
+        chars.into_iter().collect()
+    }
+
+    /// Apply `first`, then `second` transformed against it
+    fn apply_both(text: &str, first: &Update, second: &Update) -> String {
+        let text = apply(text, first);
+        transform(second.clone(), first, PositionUnit::Scalar)
+            .unwrap()
+            .iter()
+            .fold(text, |text, u| apply(&text, u))
+    }
+
+    #[test]
+    fn insert_insert_tie_goes_to_committed() {
+        let (committed, late) = (insert(2, "ab"), insert(2, "xy"));
+        assert_eq!(apply_both("0123", &committed, &late), "01abxy23");
+    }
+
+    #[test]
+    fn insert_insert_converges() {
+        let (a, b) = (insert(1, "a"), insert(3, "b"));
+        assert_eq!(apply_both("0123", &a, &b), apply_both("0123", &b, &a));
+        assert_eq!(apply_both("0123", &a, &b), "0a12b3");
+    }
+
+    #[test]
+    fn insert_inside_delete_survives() {
+        let (del, ins) = (delete(1, 3), insert(2, "x"));
+        assert_eq!(apply_both("012345", &del, &ins), "0x45");
+        assert_eq!(apply_both("012345", &ins, &del), "0x45");
+    }
+
+    #[test]
+    fn delete_straddling_insert_is_split() {
+        let out = transform(delete(1, 3), &insert(2, "xy"), PositionUnit::Scalar).unwrap();
+        assert_eq!(out.len(), 2);
+        assert!(out[0].position > out[1].position);
+    }
+
+    #[test]
+    fn overlapping_deletes_remove_text_once() {
+        let (a, b) = (delete(1, 3), delete(2, 3));
+        assert_eq!(apply_both("0123456", &a, &b), "056");
+        assert_eq!(apply_both("0123456", &b, &a), "056");
+    }
+
+    #[test]
+    fn contained_delete_vanishes() {
+        assert!(
+            transform(delete(2, 1), &delete(1, 3), PositionUnit::Scalar)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn delete_before_shifts_later_delete() {
+        let (a, b) = (delete(0, 2), delete(4, 1));
+        assert_eq!(apply_both("012345", &a, &b), "235");
+    }
+
+    #[test]
+    fn transform_all_folds_history_in_order() {
+        let history = [insert(0, "ab"), delete(0, 1)];
+        let out = transform_all(insert(1, "x"), &history, PositionUnit::Scalar).unwrap();
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].position, 2);
+    }
+
+    #[test]
+    fn overflowing_delete_is_out_of_bounds() {
+        let res = transform(delete(5, usize::MAX), &insert(6, "x"), PositionUnit::Scalar);
+        assert!(matches!(res, Err(PositionError::OutOfBounds { .. })));
+        let res = transform(
+            insert(usize::MAX, "x"),
+            &insert(0, "ab"),
+            PositionUnit::Scalar,
+        );
+        assert!(matches!(res, Err(PositionError::OutOfBounds { .. })));
+    }
+}
@@ -0,0 +1,190 @@
+use axum::extract::ws::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, from_str, json};
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    crdt::{CrdtOp, Rga},
+    models::{EditMessage, Error, PresenceUpdate, Update},
+};
+
+/// Newest version of the edit websocket protocol. Clients that do not ask for
+/// a version speak version 0, the original untagged format.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// An edit, as a position update or as crdt ops
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Op {
+    Crdt { crdt: Vec<CrdtOp> },
+    Update { update: Update },
+}
+
+/// Message a client sends over the edit websocket
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientMessage {
+    /// An edit, acked with the revision it ends up at. `id` is echoed back in
+    /// the ack or error so clients can match them to the op.
+    Op {
+        #[serde(default)]
+        id: Option<u64>,
+        #[serde(flatten)]
+        op: Op,
+    },
+    Presence(PresenceUpdate),
+    /// Ask for the current state of the doc
+    Sync,
+    Ping,
+}
+
+impl ClientMessage {
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            ClientMessage::Op { id, .. } => *id,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceEvent {
+    Join,
+    Leave,
+    Update,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message could not be decoded
+    InvalidMessage,
+    /// The client asked for a protocol version this server does not speak
+    UnsupportedProtocol,
+    /// The doc could not be opened
+    NotFound,
+    /// The message was understood but could not be applied
+    Rejected,
+    /// The client's role on the doc does not allow it
+    Forbidden,
+    /// The login session the client connected with has ended
+    Unauthorized,
+}
+
+/// Message the server sends over the edit websocket
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerMessage {
+    /// An edit made by another client
+    Op(Op),
+    Ack {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        revision: u64,
+    },
+    Presence {
+        event: PresenceEvent,
+        #[serde(flatten)]
+        client: Value,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+    },
+    /// Ops a resuming client missed, in the order they were committed. Its own
+    /// ops among them carry the `op_id` it gave them.
+    Resume {
+        from: u64,
+        revision: u64,
+        ops: Vec<Update>,
+    },
+    /// State of the doc, sent on connect and when asked for
+    Sync {
+        protocol: u32,
+        revision: u64,
+        content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        crdt: Option<Rga>,
+        presences: Vec<Value>,
+    },
+    Pong,
+}
+
+impl ServerMessage {
+    pub fn error(code: ErrorCode, message: impl Into<String>, id: Option<u64>) -> Self {
+        ServerMessage::Error {
+            code,
+            message: message.into(),
+            id,
+        }
+    }
+}
+
+/// Decode a websocket frame sent by a client speaking `version`. Frames that
+/// are not meant for the doc, like pings, decode to `None`.
+pub fn decode(msg: Message, version: u32) -> Result<Option<ClientMessage>, Error> {
+    let text = match msg {
+        Message::Text(text) => text,
+        Message::Binary(_) => return Err(Error::from("binary frames are not supported")),
+        Message::Ping(_) | Message::Pong(_) | Message::Close(_) => return Ok(None),
+    };
+    if version > 0 {
+        return Ok(Some(from_str(&text)?));
+    }
+    Ok(Some(match from_str(&text)? {
+        EditMessage::Crdt { crdt } => ClientMessage::Op {
+            id: None,
+            op: Op::Crdt { crdt },
+        },
+        EditMessage::Presence { presence } => ClientMessage::Presence(presence),
+        EditMessage::Update(update) => ClientMessage::Op {
+            id: None,
+            op: Op::Update { update },
+        },
+    }))
+}
+
+/// Encode a message for a client speaking `version`, `None` if that version
+/// has no such message
+pub fn encode(msg: &ServerMessage, version: u32) -> Option<Message> {
+    let value = if version > 0 {
+        json!(msg)
+    } else {
+        match msg {
+            ServerMessage::Op(Op::Update { update }) => json!(update),
+            ServerMessage::Op(Op::Crdt { crdt }) => json!({ "crdt": crdt }),
+            ServerMessage::Ack { .. } | ServerMessage::Resume { .. } | ServerMessage::Pong => {
+                return None;
+            }
+            ServerMessage::Presence { event, client } => {
+                let key = match event {
+                    PresenceEvent::Join => "join",
+                    PresenceEvent::Leave => "leave",
+                    PresenceEvent::Update => "presence",
+                };
+                json!({ key: client })
+            }
+            ServerMessage::Error { message, .. } => json!({ "err": message }),
+            ServerMessage::Sync {
+                revision,
+                crdt: Some(crdt),
+                presences,
+                ..
+            } => json!({"state":crdt, "revision":revision, "presences":presences}),
+            ServerMessage::Sync { presences, .. } => json!({ "presences": presences }),
+        }
+    };
+    Some(Message::text(value.to_string()))
+}
+
+/// Queue a message for a client speaking `version` without waiting for room,
+/// `false` if it is gone or its queue is full
+pub fn send(sender: &Sender<Message>, version: u32, msg: &ServerMessage) -> bool {
+    match encode(msg, version) {
+        Some(msg) => sender.try_send(msg).is_ok(),
+        None => true,
+    }
+}
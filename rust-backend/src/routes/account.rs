@@ -0,0 +1,241 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{Extension, Json, http::StatusCode, response::IntoResponse};
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde_json::json;
+
+use crate::{
+    db::Db,
+    mailer::{self, Mail, Mailer},
+    models::{
+        Claims, EmailToken, Error, ForgotPassword, ResetPassword, TokenPurpose, User, VerifyEmail,
+    },
+    utils,
+};
+
+/// Seconds a password reset link works for
+const RESET_TOKEN_TTL: u64 = 60 * 60;
+/// Seconds an email verification link works for
+const VERIFY_TOKEN_TTL: u64 = 24 * 60 * 60;
+
+///Mail a user a link carrying a new token for `purpose`, replacing the last one
+pub async fn send_token(
+    db: &Db,
+    mailer: &dyn Mailer,
+    user: &User,
+    purpose: TokenPurpose,
+) -> Result<(), Error> {
+    let Some(user_id) = user.id else {
+        return Err(Error::from("User Not Found"));
+    };
+    let ttl = match purpose {
+        TokenPurpose::PasswordReset => RESET_TOKEN_TTL,
+        TokenPurpose::VerifyEmail => VERIFY_TOKEN_TTL,
+    };
+    let secret = utils::random_token();
+    let token = EmailToken {
+        id: None,
+        user: user_id,
+        purpose,
+        email: user.email.clone(),
+        secret: utils::hash_password(secret.as_bytes())?,
+        created_at: DateTime::now(),
+        expires_at: DateTime::from_system_time(SystemTime::now() + Duration::from_secs(ttl)),
+    };
+    let token_id = db.create_email_token(&token).await?;
+    let token = format!("{}.{}", token_id.to_hex(), secret);
+    let mail = match purpose {
+        TokenPurpose::PasswordReset => Mail {
+            to: user.email.clone(),
+            subject: "Reset your Docsly password".to_string(),
+            body: format!(
+                "Hi {},\n\nSomeone asked to reset the password of your Docsly account. \
+                 Open this link within an hour to choose a new one:\n\n{}\n\n\
+                 If it was not you, you can ignore this email.",
+                user.name,
+                mailer::app_link(&format!("/reset-password?token={}", token))
+            ),
+        },
+        TokenPurpose::VerifyEmail => Mail {
+            to: user.email.clone(),
+            subject: "Verify your Docsly email".to_string(),
+            body: format!(
+                "Hi {},\n\nOpen this link within a day to confirm this is your email:\n\n{}",
+                user.name,
+                mailer::app_link(&format!("/verify-email?token={}", token))
+            ),
+        },
+    };
+    mailer.send(&mail).await
+}
+
+///Use up the token named by `<id>.<secret>`, as long as it is for `purpose`
+async fn redeem(db: &Db, token: &str, purpose: TokenPurpose) -> Result<EmailToken, Error> {
+    let invalid = || Error::from("invalid or expired token");
+    let (token_id, secret) = token.split_once('.').ok_or_else(invalid)?;
+    let token_id = ObjectId::parse_str(token_id).map_err(|_| invalid())?;
+    let stored = db.find_email_token(token_id, purpose).await?;
+    utils::verify_password_hash(secret, &stored.secret).map_err(|_| invalid())?;
+    db.take_email_token(token_id).await
+}
+
+///Mail a password reset link. Answers the same whether the email is
+///registered or not, so it cannot be used to find out.
+pub async fn forgot_password(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Json(body): Json<ForgotPassword>,
+) -> impl IntoResponse {
+    // the mail goes out in the background, so the response does not take longer for real accounts
+    tokio::spawn(async move {
+        let user = match db.find_user_with_email(body.email.trim()).await {
+            Ok(Some(u)) => u,
+            Ok(None) => return,
+            Err(e) => {
+                log::error!("{}", e);
+                return;
+            }
+        };
+        if let Err(e) = send_token(&db, &*mailer, &user, TokenPurpose::PasswordReset).await {
+            log::error!("could not send password reset to {}: {}", user.email, e);
+        }
+    });
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success":true
+        })),
+    )
+}
+
+///Set a new password with a reset token. Every device the user was logged
+///in on is logged out.
+pub async fn reset_password(
+    Extension(db): Extension<Arc<Db>>,
+    Json(body): Json<ResetPassword>,
+) -> impl IntoResponse {
+    if body.password.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "success":false,
+                "err":"password cannot be empty"
+            })),
+        );
+    }
+    let token = match redeem(&db, &body.token, TokenPurpose::PasswordReset).await {
+        Ok(t) => t,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "success":false,
+                    "err":e.to_string()
+                })),
+            );
+        }
+    };
+    let res = async {
+        let password = utils::hash_password(body.password.as_bytes())?;
+        db.set_password(token.user, &password).await?;
+        db.revoke_user_sessions(token.user).await?;
+        Ok::<_, Error>(())
+    }
+    .await;
+    if let Err(e) = res {
+        log::error!("{}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({
+                "success":false,
+                "err":"an error occurred"
+            })),
+        );
+    }
+    // following the link proves the address is theirs too
+    if let Err(e) = db.verify_email(token.user, &token.email).await {
+        log::info!("{}", e);
+    }
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success":true
+        })),
+    )
+}
+
+///Mail the caller a new link to verify their email
+pub async fn request_verification(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Extension(claims): Extension<Claims>,
+) -> impl IntoResponse {
+    let user = match db.find_user_with_id(&claims.sub).await {
+        Ok(u) => u,
+        Err(e) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "success":false,
+                    "err":e
+                })),
+            );
+        }
+    };
+    if user.email_verified {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "success":false,
+                "err":"email is already verified"
+            })),
+        );
+    }
+    match send_token(&db, &*mailer, &user, TokenPurpose::VerifyEmail).await {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(json!({
+                "success":true
+            })),
+        ),
+        Err(e) => {
+            log::error!("could not send verification to {}: {}", user.email, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "success":false,
+                    "err":"could not send the email"
+                })),
+            )
+        }
+    }
+}
+
+///Mark the email a verification token was sent to as verified
+pub async fn verify_email(
+    Extension(db): Extension<Arc<Db>>,
+    Json(body): Json<VerifyEmail>,
+) -> impl IntoResponse {
+    let res = match redeem(&db, &body.token, TokenPurpose::VerifyEmail).await {
+        Ok(token) => db.verify_email(token.user, &token.email).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(json!({
+                "success":true
+            })),
+        ),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "success":false,
+                "err":e.to_string()
+            })),
+        ),
+    }
+}
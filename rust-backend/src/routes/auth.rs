@@ -1,74 +1,264 @@
-use std::{env, sync::Arc, time::SystemTime};
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use axum::{Extension, Json, extract::Request, http::StatusCode, response::IntoResponse};
-use jsonwebtoken::{EncodingKey, Header, encode};
+use axum::{
+    Extension, Json,
+    extract::{ConnectInfo, Path},
+    http::{HeaderMap, StatusCode, header::USER_AGENT},
+    response::IntoResponse,
+};
+use mongodb::bson::{DateTime, oid::ObjectId};
 use serde_json::json;
 use tower_cookies::{
     Cookie, Cookies,
     cookie::{CookieBuilder, SameSite},
 };
 
+use super::{account, two_factor};
 use crate::{
     db::Db,
-    models::{Claims, LoginUser, User},
+    jwt,
+    mailer::Mailer,
+    models::{
+        Claims, Error, LoginChallenge, LoginUser, Session, TokenPurpose, TwoFactorLogin, User,
+    },
     utils::{self, decode_cookie},
 };
 
+/// Seconds an access token is valid for
+const ACCESS_TOKEN_TTL: u64 = 15 * 60;
+/// Seconds a session lives on without being refreshed
+const REFRESH_TOKEN_TTL: u64 = 2592000;
+/// How long the refresh token a session just replaced is still let through,
+/// for requests that were already on their way when it changed
+const REFRESH_REUSE_GRACE: Duration = Duration::from_secs(30);
+/// Path the refresh cookie is sent to, only the auth routes need it
+const REFRESH_COOKIE_PATH: &str = "/api/auth";
+/// Seconds a user has to give their second factor after their password
+const LOGIN_CHALLENGE_TTL: u64 = 5 * 60;
+/// Wrong codes a login can take before it has to start over
+const MAX_CHALLENGE_ATTEMPTS: u32 = 5;
+/// Wrong codes a user can enter across logins before 2FA locks for a while
+const MAX_TWO_FACTOR_FAILURES: u32 = 10;
+/// Seconds the second login step stays locked after too many wrong codes
+const TWO_FACTOR_LOCKOUT: u64 = 15 * 60;
+/// Longest user agent kept on a session
+const MAX_DEVICE_LEN: usize = 256;
+
+pub fn cookie(name: &'static str, value: String, path: &'static str) -> Cookie<'static> {
+    let (secure, same_site) = if utils::is_production() {
+        (true, SameSite::None)
+    } else {
+        (false, SameSite::Lax)
+    };
+    CookieBuilder::from(Cookie::new(name, value))
+        .http_only(true)
+        .secure(secure)
+        .same_site(same_site)
+        .path(path)
+        .build()
+}
+
+fn clear_cookies(cookies: &Cookies) {
+    cookies.remove(cookie("token", String::new(), "/"));
+    cookies.remove(cookie("refresh", String::new(), REFRESH_COOKIE_PATH));
+}
+
+/// Device and IP a request comes from, as recorded on its session.
+/// `X-Forwarded-For` is only believed when the request comes from one of the
+/// proxies in `TRUSTED_PROXIES`, and then the client is the last entry that is
+/// not one of them.
+pub fn origin(headers: &HeaderMap, addr: SocketAddr) -> (String, String) {
+    let device = headers
+        .get(USER_AGENT)
+        .and_then(|d| d.to_str().ok())
+        .unwrap_or("unknown")
+        .chars()
+        .take(MAX_DEVICE_LEN)
+        .collect();
+    let trusted: Vec<IpAddr> = env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+    let mut ip = addr.ip();
+    if trusted.contains(&ip) {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|f| f.to_str().ok())
+            .flat_map(|f| f.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        for hop in forwarded.into_iter().rev() {
+            let Ok(hop) = hop.parse() else {
+                break;
+            };
+            ip = hop;
+            if !trusted.contains(&ip) {
+                break;
+            }
+        }
+    }
+    (device, ip.to_string())
+}
+
+fn in_seconds(secs: u64) -> DateTime {
+    DateTime::from_system_time(SystemTime::now() + Duration::from_secs(secs))
+}
+
+/// Hand out an access token for the session along with its refresh token
+fn issue(
+    cookies: &Cookies,
+    user: ObjectId,
+    session: ObjectId,
+    secret: &str,
+) -> Result<String, Error> {
+    let exp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + ACCESS_TOKEN_TTL;
+    let claims = Claims::new(user.to_hex(), session.to_hex(), exp);
+    let token = jwt::sign(&claims)?;
+    cookies.add(cookie("token", token.clone(), "/"));
+    let mut refresh = cookie(
+        "refresh",
+        format!("{}.{}", session.to_hex(), secret),
+        REFRESH_COOKIE_PATH,
+    );
+    refresh.set_max_age(tower_cookies::cookie::time::Duration::seconds(
+        REFRESH_TOKEN_TTL as i64,
+    ));
+    cookies.add(refresh);
+    Ok(token)
+}
+
+/// Log a user in on a new session
+pub async fn start_session(
+    db: &Db,
+    cookies: &Cookies,
+    user: ObjectId,
+    (device, ip): (String, String),
+) -> Result<String, Error> {
+    let secret = utils::random_token();
+    let now = DateTime::now();
+    let session = Session {
+        id: None,
+        user,
+        refresh: utils::hash_password(secret.as_bytes())?,
+        previous: None,
+        device,
+        ip,
+        created_at: now,
+        last_seen: now,
+        expires_at: in_seconds(REFRESH_TOKEN_TTL),
+        revoked: false,
+    };
+    let session = db.create_session(&session).await?;
+    issue(cookies, user, session, &secret)
+}
+
+/// Hold a login that passed the password check until the second factor is
+/// given, returning the token to give it with
+pub async fn challenge(db: &Db, user: ObjectId) -> Result<String, Error> {
+    let secret = utils::random_token();
+    let challenge = LoginChallenge {
+        id: None,
+        user,
+        secret: utils::hash_password(secret.as_bytes())?,
+        attempts: 0,
+        expires_at: in_seconds(LOGIN_CHALLENGE_TTL),
+    };
+    let challenge = db.create_login_challenge(&challenge).await?;
+    Ok(format!("{}.{}", challenge.to_hex(), secret))
+}
+
+/// Session and secret named by the refresh cookie
+fn refresh_cookie(cookies: &Cookies) -> Option<(ObjectId, String)> {
+    let cookie = cookies.get("refresh")?;
+    let (session, secret) = cookie.value().split_once('.')?;
+    Some((ObjectId::parse_str(session).ok()?, secret.to_string()))
+}
+
 pub async fn login(
     Extension(db): Extension<Arc<Db>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     cookies: Cookies,
     Json(user): Json<LoginUser>,
 ) -> impl IntoResponse {
-    let mut env = String::from("dev");
-    if let Ok(v) = env::var("ENV") {
-        env = v
-    };
     match db.find_user(&user).await {
         Ok(u) => match utils::verify_password_hash(&user.password, &u.password) {
             Ok(()) => {
-                // check if cookies already exist 
-                if let Some(cookie) = cookies.get("token") {
-                    if let Some(claims) = decode_cookie(cookie.clone()).await {
-                        if !claims.sub.is_empty() {
-                            return (
-                                StatusCode::OK,
+                let user_id = u.id.unwrap();
+                // check if this user is already logged in here
+                if let Some(claims) = utils::session_claims(&db, &cookies).await
+                    && claims.sub == user_id.to_hex()
+                    && let Some(cookie) = cookies.get("token")
+                {
+                    return (
+                        StatusCode::OK,
+                        Json(json!({
+                            "token":cookie.value(),
+                            "success":true
+                        })),
+                    );
+                }
+                if u.two_factor.as_ref().is_some_and(|t| t.enabled) {
+                    if two_factor::locked(&u) {
+                        return (
+                            StatusCode::TOO_MANY_REQUESTS,
+                            Json(json!({
+                                "success":false,
+                                "err":"too many wrong codes, try again later"
+                            })),
+                        );
+                    }
+                    return match challenge(&db, user_id).await {
+                        Ok(challenge) => (
+                            StatusCode::OK,
+                            Json(json!({
+                                "success":false,
+                                "two_factor":true,
+                                "challenge":challenge
+                            })),
+                        ),
+                        Err(e) => {
+                            log::error!("{}", e);
+                            (
+                                StatusCode::INTERNAL_SERVER_ERROR,
                                 Json(json!({
-                                    "token":cookie.value(),
-                                    "success":true
+                                    "success":false,
+                                    "err":"an error occurred"
                                 })),
-                            );
+                            )
                         }
+                    };
+                }
+                match start_session(&db, &cookies, user_id, origin(&headers, addr)).await {
+                    Ok(t) => (
+                        StatusCode::OK,
+                        Json(json!({
+                            "token":t,
+                            "success":true
+                        })),
+                    ),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            Json(json!({
+                                "success":false,
+                                "err":"an error occurred"
+                            })),
+                        )
                     }
                 }
-                let exp = SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs()
-                    + 2592000;
-                let claims = Claims::new(u.id.unwrap().to_hex(), exp);
-                let key = &EncodingKey::from_secret("hello".as_ref());
-                let token = encode(&Header::default(), &claims, key);
-                let t = token.unwrap().to_string();
-                let cookie = Cookie::new("token", t.clone());
-                let (secure, same_site) = if env.as_str() == "prod" {
-                    (true, SameSite::None)
-                } else {
-                    (false, SameSite::Lax)
-                };
-                let final_cookie = CookieBuilder::from(cookie)
-                    .http_only(true)
-                    .secure(secure)
-                    .same_site(same_site)
-                    .path("/")
-                    .build();
-                cookies.add(final_cookie);
-                (
-                    StatusCode::OK,
-                    Json(json!({
-                        "token":t,
-                        "success":true
-                    })),
-                )
             }
             Err(e) => {
                 log::error!("{}", e);
@@ -92,50 +282,458 @@ pub async fn login(
     }
 }
 
-pub async fn signup(
+///Second login step for users with 2FA, taking the challenge `login`
+///answered with and a TOTP or recovery code
+pub async fn login_two_factor(
     Extension(db): Extension<Arc<Db>>,
-    Json(user): Json<User>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    cookies: Cookies,
+    Json(body): Json<TwoFactorLogin>,
 ) -> impl IntoResponse {
-    match db.create_user(user).await {
-        Ok(()) => (
+    let unauthorized = |err: &str| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "success":false,
+                "err":err
+            })),
+        )
+    };
+    let Some((challenge_id, secret)) = body
+        .challenge
+        .split_once('.')
+        .and_then(|(id, secret)| Some((ObjectId::parse_str(id).ok()?, secret)))
+    else {
+        return unauthorized("login has expired, sign in again");
+    };
+    let challenge = match db.find_login_challenge(challenge_id).await {
+        Ok(c) if utils::verify_password_hash(secret, &c.secret).is_ok() => c,
+        _ => return unauthorized("login has expired, sign in again"),
+    };
+    match db
+        .claim_login_challenge(challenge_id, MAX_CHALLENGE_ATTEMPTS)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            // claiming it already fails from now on, this only tidies up
+            if let Err(e) = db.take_login_challenge(challenge_id).await {
+                log::error!("could not remove login challenge {}: {}", challenge_id, e);
+            }
+            return unauthorized("too many wrong codes, sign in again");
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "success":false,
+                    "err":"an error occurred"
+                })),
+            );
+        }
+    }
+    let user = match db.find_user_with_id(&challenge.user).await {
+        Ok(user) => user,
+        Err(e) => {
+            log::error!("{}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "success":false,
+                    "err":"an error occurred"
+                })),
+            );
+        }
+    };
+    if two_factor::locked(&user) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({
+                "success":false,
+                "err":"too many wrong codes, try again later"
+            })),
+        );
+    }
+    match two_factor::verify_code(&db, &user, &body.code).await {
+        Ok(true) => {
+            if let Err(e) = db.reset_two_factor_failures(challenge.user).await {
+                log::error!("{}", e);
+            }
+        }
+        Ok(false) => {
+            let locked_until = in_seconds(TWO_FACTOR_LOCKOUT);
+            if let Err(e) = db
+                .fail_two_factor(challenge.user, MAX_TWO_FACTOR_FAILURES, locked_until)
+                .await
+            {
+                log::error!("{}", e);
+            }
+            return unauthorized("invalid code");
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "success":false,
+                    "err":"an error occurred"
+                })),
+            );
+        }
+    }
+    if db.take_login_challenge(challenge_id).await.is_err() {
+        return unauthorized("login has expired, sign in again");
+    }
+    match start_session(&db, &cookies, challenge.user, origin(&headers, addr)).await {
+        Ok(t) => (
             StatusCode::OK,
             Json(json!({
+                "token":t,
                 "success":true
             })),
         ),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "success":false,
+                    "err":"an error occurred"
+                })),
+            )
+        }
+    }
+}
+
+///Swap the refresh cookie for a new one along with a fresh access token.
+///A refresh token used after it was replaced means it leaked, so the session
+///it belongs to is revoked.
+pub async fn refresh(
+    Extension(db): Extension<Arc<Db>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    cookies: Cookies,
+) -> impl IntoResponse {
+    let unauthorized = |err: &str| {
+        (
+            StatusCode::UNAUTHORIZED,
             Json(json!({
                 "success":false,
-                "err":e.to_string()
+                "err":err
+            })),
+        )
+    };
+    let Some((session_id, secret)) = refresh_cookie(&cookies) else {
+        return unauthorized("not logged in");
+    };
+    let session = match db.find_session(session_id).await {
+        Ok(s) => s,
+        Err(_) => {
+            clear_cookies(&cookies);
+            return unauthorized("session has ended");
+        }
+    };
+    if utils::verify_password_hash(&secret, &session.refresh).is_err() {
+        let just_replaced = session.previous.as_ref().is_some_and(|p| {
+            utils::verify_password_hash(&secret, p).is_ok()
+                && session.last_seen.to_system_time() + REFRESH_REUSE_GRACE > SystemTime::now()
+        });
+        if just_replaced {
+            // another request refreshed first, its cookies are already set
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "success":false,
+                    "err":"session was just refreshed"
+                })),
+            );
+        }
+        log::warn!("refresh token of session {} was reused", session_id);
+        if let Err(e) = db.revoke_session(session.user, session_id).await {
+            log::error!("{}", e);
+        }
+        clear_cookies(&cookies);
+        return unauthorized("session has ended");
+    }
+    let secret = utils::random_token();
+    let (device, ip) = origin(&headers, addr);
+    let update = match utils::hash_password(secret.as_bytes()) {
+        Ok(refresh) => Session {
+            refresh,
+            device,
+            ip,
+            last_seen: DateTime::now(),
+            expires_at: in_seconds(REFRESH_TOKEN_TTL),
+            ..session.clone()
+        },
+        Err(e) => {
+            log::error!("{}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "success":false,
+                    "err":"an error occurred"
+                })),
+            );
+        }
+    };
+    match db
+        .rotate_session(session_id, &session.refresh, &update)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "success":false,
+                    "err":"session was just refreshed"
+                })),
+            );
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "success":false,
+                    "err":"an error occurred"
+                })),
+            );
+        }
+    }
+    match issue(&cookies, session.user, session_id, &secret) {
+        Ok(t) => (
+            StatusCode::OK,
+            Json(json!({
+                "token":t,
+                "success":true
             })),
         ),
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "success":false,
+                    "err":"an error occurred"
+                })),
+            )
+        }
+    }
+}
+
+///Revoke the session the caller is logged in with and clear its cookies.
+///Works with an expired access token as long as the refresh token is valid.
+pub async fn logout(Extension(db): Extension<Arc<Db>>, cookies: Cookies) -> impl IntoResponse {
+    let access = match cookies.get("token") {
+        Some(c) => decode_cookie(c).await.and_then(|c| {
+            Some((
+                ObjectId::parse_str(&c.sub).ok()?,
+                ObjectId::parse_str(&c.sid).ok()?,
+            ))
+        }),
+        None => None,
+    };
+    let session = match access {
+        Some(s) => Some(s),
+        None => match refresh_cookie(&cookies) {
+            Some((session_id, secret)) => match db.find_session(session_id).await {
+                Ok(s) if utils::verify_password_hash(&secret, &s.refresh).is_ok() => {
+                    Some((s.user, session_id))
+                }
+                _ => None,
+            },
+            None => None,
+        },
+    };
+    if let Some((user, session_id)) = session
+        && let Err(e) = db.revoke_session(user, session_id).await
+    {
+        log::error!("{}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({
+                "success":false,
+                "err":"an error occurred"
+            })),
+        );
+    }
+    clear_cookies(&cookies);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success":true
+        })),
+    )
+}
+
+///Revoke every session of the caller, on every device
+pub async fn logout_all(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(claims): Extension<Claims>,
+    cookies: Cookies,
+) -> impl IntoResponse {
+    match db.revoke_user_sessions(claims.sub.as_str()).await {
+        Ok(revoked) => {
+            clear_cookies(&cookies);
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true,
+                    "revoked":revoked
+                })),
+            )
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "success":false,
+                    "err":"an error occurred"
+                })),
+            )
+        }
     }
 }
 
-pub async fn me(cookies: Cookies, _req: Request) -> impl IntoResponse {
-    // log::debug!("me route");
-    let cookie = cookies.get("token");
-    match cookie {
-        Some(c) => match decode_cookie(c).await {
-            Some(_) => (
+///Devices the caller is logged in on
+pub async fn get_sessions(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(claims): Extension<Claims>,
+) -> impl IntoResponse {
+    match db.find_user_sessions(claims.sub.as_str()).await {
+        Ok(sessions) => {
+            let sessions: Vec<_> = sessions
+                .iter()
+                .map(|s| s.summary(s.id.is_some_and(|id| id.to_hex() == claims.sid)))
+                .collect();
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "sessions":sessions
+                })),
+            )
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":"an error occurred"
+                })),
+            )
+        }
+    }
+}
+
+///Log one of the caller's devices out
+pub async fn revoke_session(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(claims): Extension<Claims>,
+    cookies: Cookies,
+    Path(session_id): Path<String>,
+) -> impl IntoResponse {
+    let Ok(session) = ObjectId::parse_str(&session_id) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"session not found"
+            })),
+        );
+    };
+    match db.revoke_session(claims.sub.as_str(), session).await {
+        Ok(true) => {
+            if session_id == claims.sid {
+                clear_cookies(&cookies);
+            }
+            (
                 StatusCode::OK,
                 Json(json!({
                     "success":true
                 })),
-            ),
-            None => (
-                StatusCode::BAD_REQUEST,
+            )
+        }
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"session not found"
+            })),
+        ),
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({
-                    "success":false
+                    "err":"an error occurred"
                 })),
-            ),
-        },
-        None => (
+            )
+        }
+    }
+}
+
+pub async fn signup(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Json(user): Json<User>,
+) -> impl IntoResponse {
+    let email = user.email.clone();
+    match db.create_user(user).await {
+        Ok(()) => {
+            tokio::spawn(async move {
+                let res = match db.find_user_with_email(&email).await {
+                    Ok(Some(u)) => {
+                        account::send_token(&db, &*mailer, &u, TokenPurpose::VerifyEmail).await
+                    }
+                    Ok(None) => return,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
+                    log::error!("could not send verification to {}: {}", email, e);
+                }
+            });
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true
+                })),
+            )
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({
+                "success":false,
+                "err":e.to_string()
+            })),
+        ),
+    }
+}
+
+pub async fn me(Extension(db): Extension<Arc<Db>>, cookies: Cookies) -> impl IntoResponse {
+    if cookies.get("token").is_none() {
+        return (
             StatusCode::UNAUTHORIZED,
             Json(json!({
                 "err":"token not valid"
             })),
+        );
+    }
+    // expired and revoked tokens are unauthorized too, so the client knows to refresh
+    match utils::session_claims(&db, &cookies).await {
+        Some(_) => (
+            StatusCode::OK,
+            Json(json!({
+                "success":true
+            })),
+        ),
+        None => (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "success":false
+            })),
         ),
     }
 }
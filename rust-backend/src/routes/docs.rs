@@ -1,4 +1,7 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    sync::Arc,
+};
 
 use axum::{
     Extension, Json, RequestExt,
@@ -6,32 +9,159 @@ use axum::{
     http::{StatusCode, request::Parts},
     response::IntoResponse,
 };
-use mongodb::results::InsertOneResult;
+use mongodb::{bson::oid::ObjectId, results::InsertOneResult};
 use serde_json::json;
 use tower_cookies::Cookies;
 
 use crate::{
     db::Db,
-    models::{Author, CollabRequestHandler, Doc, DocQuery, IntoObjectId, UploadedDoc},
+    history,
+    models::{
+        Author, CollabRequestHandler, CollabRequestQuery, Doc, DocAccess, DocsMap, HistoryQuery,
+        IntoObjectId, NewDoc, RevisionDiffQuery, Role, UploadedDoc,
+    },
+    session,
     utils::{self, decode_cookie, extract_cookies},
 };
 
 pub async fn get_doc(
-    Query(params): Query<DocQuery>,
+    Extension(access): Extension<DocAccess>,
+    Extension(docs): Extension<DocsMap>,
+) -> impl IntoResponse {
+    let mut doc = access.doc;
+    session::overlay(&docs, &mut doc).await;
+    // clients need the caller's role to know what to offer them
+    let mut body = json!(doc);
+    body["role"] = json!(access.role);
+    (StatusCode::OK, Json(body))
+}
+
+pub async fn get_doc_history(
+    Path(doc_id): Path<String>,
+    Query(params): Query<HistoryQuery>,
     Extension(db): Extension<Arc<Db>>,
+    Extension(docs): Extension<DocsMap>,
 ) -> impl IntoResponse {
-    let res = db.find_doc_with_id(params.id).await;
+    // unflushed edits are not in the changes collection yet
+    if let Err(e) = session::flush(&docs, &doc_id).await {
+        log::error!("{}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"err":"an error occurred"})),
+        );
+    }
+    let doc = match db.find_doc_with_id(doc_id.as_str()).await {
+        Ok(d) => d,
+        Err(e) => {
+            log::error!("{}", e);
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({"err":"Document not found"})),
+            );
+        }
+    };
+    let res = match history::target_revision(&db, doc_id.as_str(), &params, doc.revision).await {
+        Ok(revision) => history::reconstruct(&db, doc_id.as_str(), revision).await,
+        Err(e) => Err(e),
+    };
     match res {
-        Ok(d) => (StatusCode::OK, Json(d)).into_response(),
+        Ok(r) => (
+            StatusCode::OK,
+            Json(json!({
+                "revision":r.revision,
+                "content":r.content
+            })),
+        ),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":e.to_string()
+            })),
+        ),
+    }
+}
+
+pub async fn get_doc_diff(
+    Path(doc_id): Path<String>,
+    Query(params): Query<RevisionDiffQuery>,
+    Extension(db): Extension<Arc<Db>>,
+    Extension(docs): Extension<DocsMap>,
+) -> impl IntoResponse {
+    let parse = |id: Option<String>| id.map(|id| ObjectId::parse_str(&id)).transpose();
+    let (Ok(from_version), Ok(to_version)) = (parse(params.from_version), parse(params.to_version))
+    else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"err":"invalid version id"})),
+        );
+    };
+    if let Err(e) = session::flush(&docs, &doc_id).await {
+        log::error!("{}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"err":"an error occurred"})),
+        );
+    }
+    let doc = match db.find_doc_with_id(doc_id.as_str()).await {
+        Ok(d) => d,
         Err(e) => {
             log::error!("{}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
+            return (
+                StatusCode::NOT_FOUND,
                 Json(json!({"err":"Document not found"})),
-            )
-                .into_response()
+            );
+        }
+    };
+    let from = match from_version {
+        Some(v) => db
+            .find_version(doc_id.as_str(), v)
+            .await
+            .map(|v| v.revision),
+        None => Ok(params.from.unwrap_or(0)),
+    };
+    let to = match to_version {
+        Some(v) => db
+            .find_version(doc_id.as_str(), v)
+            .await
+            .map(|v| v.revision),
+        None => Ok(params.to.unwrap_or(doc.revision)),
+    };
+    let (from, to) = match (from, to) {
+        (Ok(from), Ok(to)) if to <= doc.revision => (from, to),
+        (Ok(_), Ok(_)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"err":"revision does not exist"})),
+            );
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            return (StatusCode::NOT_FOUND, Json(json!({"err":e.to_string()})));
+        }
+    };
+    let spans = match history::diff(&db, doc_id.as_str(), from, to).await {
+        Ok(s) => s,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({"err":e.to_string()})));
+        }
+    };
+    // names to show next to each change
+    let mut authors = HashMap::new();
+    for author in spans.iter().filter_map(|s| s.author) {
+        if let Entry::Vacant(e) = authors.entry(author.to_hex())
+            && let Ok(user) = db.find_user_with_id(&author).await
+        {
+            e.insert(user.name);
         }
     }
+    (
+        StatusCode::OK,
+        Json(json!({
+            "from":from,
+            "to":to,
+            "diff":spans,
+            "authors":authors
+        })),
+    )
 }
 
 pub async fn get_docs(Extension(db): Extension<Arc<Db>>, mut req: Request) -> impl IntoResponse {
@@ -39,13 +169,16 @@ pub async fn get_docs(Extension(db): Extension<Arc<Db>>, mut req: Request) -> im
     let claims = extract_cookies(&parts).await.unwrap();
     let user_id = &claims.sub.clone();
     let res = db.find_docs_with_user_id(user_id.to_owned()).await;
-    let uploads = match db.get_uploads(user_id.to_owned()).await{
+    let uploads = match db.get_uploads(user_id.to_owned()).await {
         Ok(u) => u,
         Err(e) => {
-            log::error!("{}",e);
-            return (StatusCode::INTERNAL_SERVER_ERROR,Json(json!({
-                "err":"an error occurred with uploads"
-            })))
+            log::error!("{}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":"an error occurred with uploads"
+                })),
+            );
         }
     };
     match res {
@@ -53,14 +186,11 @@ pub async fn get_docs(Extension(db): Extension<Arc<Db>>, mut req: Request) -> im
             let mut user_docs = vec![];
             let mut collab_docs = vec![];
             for doc in docs {
-                if let Some(Author { id: Some(i), .. }) = doc.author {
-                    if i == user_id.into_objetc_id() {
-                        user_docs.push(doc.clone());
-                    }
-                } 
-                if doc.collaborators.contains(&user_id.into_objetc_id()) {
-                    collab_docs.push(doc);
-                };
+                match doc.role(&user_id.into_objetc_id()) {
+                    Some(Role::Owner) => user_docs.push(doc),
+                    Some(_) => collab_docs.push(doc),
+                    None => {}
+                }
             }
             (
                 StatusCode::OK,
@@ -83,8 +213,19 @@ pub async fn get_docs(Extension(db): Extension<Arc<Db>>, mut req: Request) -> im
 pub async fn create(
     Extension(db): Extension<Arc<Db>>,
     cookies: Cookies,
-    Json(mut doc): Json<Doc>,
+    Json(body): Json<NewDoc>,
 ) -> impl IntoResponse {
+    let mut doc = Doc {
+        id: None,
+        author: None,
+        permissions: Vec::new(),
+        title: body.title,
+        content: body.content,
+        doc_type: body.doc_type,
+        starred: None,
+        last_update: None,
+        revision: 0,
+    };
     if let Some(cookie) = cookies.get("token") {
         let id = utils::decode_cookie(cookie).await.unwrap();
         let author = match db.find_user_with_id(&id.sub).await {
@@ -102,7 +243,6 @@ pub async fn create(
             }
         };
         doc.author = Some(author);
-    } else {
     };
     match db.create_doc(doc).await {
         Ok(_) => (
@@ -129,35 +269,35 @@ pub async fn collab_request(
     Path(doc_id): Path<String>,
     cookies: Cookies,
 ) -> impl IntoResponse {
-    if let Some(cookie) = cookies.get("token") {
-        if let Some(claims) = decode_cookie(cookie).await {
-            match db.add_collab_request(doc_id, claims.sub).await {
-                Ok(i) if i.inserted_id == InsertOneResult::default().inserted_id => {
-                    return (
-                        StatusCode::OK,
-                        Json(json!({
-                            "message":"Already has permission",
-                            "redirect":true
-                        })),
-                    );
-                }
-                Ok(_) => {
-                    return (
-                        StatusCode::OK,
-                        Json(json!({
-                            "message":"Permission Pending",
-                            "redirect":false
-                        })),
-                    );
-                }
-                Err(e) => {
-                    return (
-                        StatusCode::OK,
-                        Json(json!({
-                            "err":e.to_string(),
-                        })),
-                    );
-                }
+    if let Some(cookie) = cookies.get("token")
+        && let Some(claims) = decode_cookie(cookie).await
+    {
+        match db.add_collab_request(doc_id, claims.sub).await {
+            Ok(i) if i.inserted_id == InsertOneResult::default().inserted_id => {
+                return (
+                    StatusCode::OK,
+                    Json(json!({
+                        "message":"Already has permission",
+                        "redirect":true
+                    })),
+                );
+            }
+            Ok(_) => {
+                return (
+                    StatusCode::OK,
+                    Json(json!({
+                        "message":"Permission Pending",
+                        "redirect":false
+                    })),
+                );
+            }
+            Err(e) => {
+                return (
+                    StatusCode::OK,
+                    Json(json!({
+                        "err":e.to_string(),
+                    })),
+                );
             }
         }
     }
@@ -171,27 +311,29 @@ pub async fn collab_request(
 
 pub async fn get_collab_requests(
     Extension(db): Extension<Arc<Db>>,
+    Query(params): Query<CollabRequestQuery>,
     cookies: Cookies,
 ) -> impl IntoResponse {
-    if let Some(cookie) = cookies.get("token") {
-        if let Some(claims) = decode_cookie(cookie).await {
-            match db.get_collab_requests(claims.sub).await {
-                Ok(reqs) => {
-                    return (
-                        StatusCode::OK,
-                        Json(json!({
-                            "requests":reqs
-                        })),
-                    );
-                }
-                Err(e) => {
-                    return (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(json!({
-                            "err":e.to_string()
-                        })),
-                    );
-                }
+    if let Some(cookie) = cookies.get("token")
+        && let Some(claims) = decode_cookie(cookie).await
+    {
+        match db.get_collab_requests(claims.sub, &params).await {
+            Ok((reqs, total)) => {
+                return (
+                    StatusCode::OK,
+                    Json(json!({
+                        "requests":reqs,
+                        "total":total
+                    })),
+                );
+            }
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "err":e.to_string()
+                    })),
+                );
             }
         }
     }
@@ -203,12 +345,69 @@ pub async fn get_collab_requests(
     )
 }
 
+///Accept or reject a request to join a doc. Only the stored request is
+///trusted, and only the owner of its doc may act on it.
 pub async fn handle_collab_request(
     Extension(db): Extension<Arc<Db>>,
+    cookies: Cookies,
     Json(req): Json<CollabRequestHandler>,
 ) -> impl IntoResponse {
-    match req {
-        CollabRequestHandler::Accept(r) => match db.handle_collab_request(r).await {
+    let Some(cookie) = cookies.get("token") else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "err":"unauthorized"
+            })),
+        );
+    };
+    let Some(claims) = decode_cookie(cookie).await else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "err":"unauthorized"
+            })),
+        );
+    };
+    let (accept, request_id) = match req {
+        CollabRequestHandler::Accept(r) => (true, r.id),
+        CollabRequestHandler::Reject(r) => (false, r.id),
+    };
+    let request = match db.find_collab_request(request_id).await {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("{}", e);
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "err":"request not found"
+                })),
+            );
+        }
+    };
+    let doc = match db.find_doc_with_id(request.doc).await {
+        Ok(d) => d,
+        Err(e) => {
+            log::error!("{}", e);
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "err":"Document not found"
+                })),
+            );
+        }
+    };
+    let owner =
+        ObjectId::parse_str(&claims.sub).is_ok_and(|user| doc.role(&user) == Some(Role::Owner));
+    if !owner {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"only the owner can answer requests to join"
+            })),
+        );
+    }
+    if accept {
+        match db.handle_collab_request(&doc, &request).await {
             Ok(_) => (
                 StatusCode::OK,
                 Json(json!({
@@ -224,11 +423,13 @@ pub async fn handle_collab_request(
                     })),
                 )
             }
-        },
-        CollabRequestHandler::Reject(r) => match db.reject_collab_request(r).await {
+        }
+    } else {
+        match db.reject_collab_request(&request).await {
             Ok(n) => (
                 StatusCode::OK,
                 Json(json!({
+                    "success":true,
                     "request_id":n.id
                 })),
             ),
@@ -241,7 +442,7 @@ pub async fn handle_collab_request(
                     })),
                 )
             }
-        },
+        }
     }
 }
 
@@ -255,7 +456,7 @@ pub async fn upload_doc(
         let file_name = field.file_name().unwrap_or("untitled").to_string();
         let contet_type = field.content_type().map(|s| s.to_string()).unwrap();
         let bytes = field.bytes().await.unwrap();
-        if !str::from_utf8(&bytes).is_ok() {
+        if str::from_utf8(&bytes).is_err() {
             return (
                 StatusCode::BAD_REQUEST,
                 Json(json!({
@@ -267,16 +468,16 @@ pub async fn upload_doc(
             subtype: bson::spec::BinarySubtype::Generic,
             bytes: bytes.to_vec(),
         };
-        if let Some(cookie) = cookies.get("token") {
-            if let Some(claims) = decode_cookie(cookie).await {
-                let doc = UploadedDoc::new(claims.sub, file_name, contet_type, bytes.len(), data);
-                match db.upload_doc(doc).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        log::error!("{}", e)
-                    }
-                };
-            }
+        if let Some(cookie) = cookies.get("token")
+            && let Some(claims) = decode_cookie(cookie).await
+        {
+            let doc = UploadedDoc::new(claims.sub, file_name, contet_type, bytes.len(), data);
+            match db.upload_doc(doc).await {
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("{}", e)
+                }
+            };
         }
         log::info!("file written successfully");
     }
@@ -287,3 +488,32 @@ pub async fn upload_doc(
         })),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn malformed_version_ids_are_bad_requests() {
+        let db = Arc::new(Db::unreachable().await);
+        let query = |from_version: Option<&str>, to_version: Option<&str>| {
+            Query(RevisionDiffQuery {
+                from: None,
+                to: None,
+                from_version: from_version.map(str::to_string),
+                to_version: to_version.map(str::to_string),
+            })
+        };
+        for params in [query(Some("abc"), None), query(None, Some("abc"))] {
+            let res = get_doc_diff(
+                Path("65a1b2c3d4e5f60718293a4b".to_string()),
+                params,
+                Extension(db.clone()),
+                Extension(DocsMap::default()),
+            )
+            .await
+            .into_response();
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+}
@@ -1,132 +1,357 @@
 use std::sync::Arc;
 
 use axum::{
-    Error, Extension,
+    Extension,
     extract::{
-        Path, WebSocketUpgrade,
+        Path, Query, WebSocketUpgrade,
         ws::{Message, WebSocket},
     },
     response::Response,
 };
-use chrono::Utc;
-use futures::{SinkExt, StreamExt};
+use futures::{Sink, SinkExt, Stream, StreamExt, future, sink, stream};
 use log::error;
-use serde_json::json;
-use tokio::sync::mpsc::{self};
-use tower_cookies::Cookies;
+use mongodb::bson::oid::ObjectId;
+use tokio::sync::mpsc::{self, Receiver};
 
 use crate::{
     db::Db,
-    models::{BufferMap, Client, DocsMap, IntoObjectId, Update},
-    utils::decode_cookie,
+    fanout::{Fanout, LEASE_TTL, Packet},
+    models::{Client, DocAccess, DocsMap, EditQuery, Error, IntoObjectId},
+    protocol::{self, ErrorCode, PROTOCOL_VERSION, ServerMessage},
+    session::{self, CLIENT_QUEUE},
 };
 
+#[allow(clippy::too_many_arguments)]
 pub async fn edit(
     Extension(doc_states): Extension<DocsMap>,
     Extension(db): Extension<Arc<Db>>,
-    Extension(buffer_map): Extension<BufferMap>,
+    Extension(fanout): Extension<Arc<Fanout>>,
+    Extension(access): Extension<DocAccess>,
     doc_id: Path<String>,
+    Query(params): Query<EditQuery>,
     ws: WebSocketUpgrade,
-    cookies: Cookies,
 ) -> Response {
-    ws.on_failed_upgrade(|err: Error| {
+    ws.on_failed_upgrade(|err: axum::Error| {
         error!("{}", err);
     })
-    .on_upgrade(async move |ws| {
-        let user_id = match cookies.get("token") {
-            Some(c) => match decode_cookie(c).await {
-                Some(claims) => claims.sub,
-                None => return,
-            },
-
-            None => return,
+    .on_upgrade(async move |mut ws| {
+        let user_id = access.user.to_hex();
+        let session_id = access.session;
+        let doc_id = doc_id.to_string();
+        let owner = match fanout.broker.claim(&doc_id).await {
+            Ok(owner) => owner,
+            Err(e) => {
+                log::error!("could not claim {}: {}", doc_id, e);
+                let msg = ServerMessage::error(ErrorCode::Rejected, "try again later", None);
+                if let Some(msg) = protocol::encode(&msg, params.protocol) {
+                    #[allow(unused)]
+                    ws.send(msg).await;
+                }
+                return;
+            }
         };
-        handle_edit(doc_states, user_id, doc_id.to_string(), db, ws, buffer_map).await;
+        if owner != fanout.broker.node() {
+            tunnel(fanout, owner, (user_id, session_id), doc_id, params, ws).await;
+            return;
+        }
+        let (sender, receiver) = ws.split();
+        let receiver = receiver
+            .take_while(|msg| future::ready(msg.is_ok()))
+            .filter_map(|msg| future::ready(msg.ok()));
+        handle_edit(
+            doc_states,
+            (user_id, session_id),
+            doc_id,
+            params,
+            db,
+            sender,
+            receiver,
+            fanout,
+        )
+        .await;
     })
 }
 
 ///Websocket Function
 // #[allow(unused_variables)]
 // #[allow(unused_assignments)]
-async fn handle_edit<'a>(
+#[allow(clippy::too_many_arguments)]
+async fn handle_edit<S, R>(
     docs: DocsMap,
-    user_id: String,
+    (user_id, session_id): (String, String),
     doc_id: String,
+    params: EditQuery,
     db: Arc<Db>,
-    mut ws: WebSocket,
-    _buffer: BufferMap,
-) {
+    mut sender: S,
+    mut receiver: R,
+    fanout: Arc<Fanout>,
+) where
+    S: Sink<Message> + Unpin + Send + 'static,
+    R: Stream<Item = Message> + Unpin,
+{
     log::debug!("{} connected", &user_id);
     let user_id = Arc::new(user_id.as_str());
     let doc_id = doc_id.as_str();
     let db = Arc::clone(&db);
+    let (tx, mut rx) = mpsc::channel::<Message>(CLIENT_QUEUE);
+    // only the doc holds on to the queue, so it can end it by dropping the client
+    let errors = tx.downgrade();
+    if params.protocol > PROTOCOL_VERSION {
+        let msg = ServerMessage::error(
+            ErrorCode::UnsupportedProtocol,
+            format!("newest supported protocol is {}", PROTOCOL_VERSION),
+            None,
+        );
+        if let Some(msg) = protocol::encode(&msg, PROTOCOL_VERSION) {
+            #[allow(unused)]
+            sender.send(msg).await;
+        }
+        return;
+    }
     let doc = match db.find_doc_with_id(doc_id).await {
         Ok(d) => d,
         Err(e) => {
+            let msg = ServerMessage::error(ErrorCode::NotFound, e.error, None);
+            if let Some(msg) = protocol::encode(&msg, params.protocol) {
+                #[allow(unused)]
+                sender.send(msg).await;
+            }
+            return;
+        }
+    };
+    // tunnelled clients were only checked on the node they connected to
+    let Some(role) = doc.role(&user_id.into_objetc_id()) else {
+        let msg = ServerMessage::error(
+            ErrorCode::Forbidden,
+            "you do not have access to this document",
+            None,
+        );
+        if let Some(msg) = protocol::encode(&msg, params.protocol) {
             #[allow(unused)]
-            ws.send(Message::text(e.error));
+            sender.send(msg).await;
+        }
+        return;
+    };
+    let mut client = Client::new(Arc::clone(&user_id), tx);
+    client.role = role;
+    client.session = session_id;
+    client.mode = params.mode;
+    client.unit = params.unit;
+    client.protocol = params.protocol;
+    client.name = match db.find_user_with_id(&*user_id).await {
+        Ok(u) => u.name,
+        Err(e) => {
+            log::error!("{}", e);
+            String::new()
+        }
+    };
+    let conn = client.conn.clone();
+    let handle = match session::join(&docs, &db, &fanout, doc_id, client, params.resume).await {
+        Ok(h) => h,
+        Err(e) => {
+            let msg = ServerMessage::error(ErrorCode::NotFound, e.error, None);
+            if let Some(msg) = protocol::encode(&msg, params.protocol) {
+                #[allow(unused)]
+                sender.send(msg).await;
+            }
             return;
         }
     };
-    let (tx, mut rx) = mpsc::channel::<Message>(128);
-    let (mut sender, mut receiver) = ws.split();
-    let mut client = Client::new(Arc::clone(&user_id), tx.clone());
-    client.author = client.id == doc.author.unwrap().id.unwrap().to_hex();
-    docs.lock()
-        .await
-        .entry(doc_id.to_string())
-        .or_insert_with(Vec::new)
-        .push(client);
-    let readloop = tokio::spawn(async move {
+    // the doc ends the queue when it drops a slow or gone client, closing the socket
+    let mut writer = tokio::spawn(async move {
         while let Some(msg) = rx.recv().await {
-            #[allow(unused)]
-            sender.send(Message::from(msg)).await;
+            if sender.send(msg).await.is_err() {
+                return;
+            }
         }
+        #[allow(unused)]
+        sender.close().await;
     });
-    while let Some(Ok(msg)) = receiver.next().await {
-        if let Message::Close(_) = msg{
-            log::info!("user: {} disconnected",*user_id);
-            return;
+    loop {
+        let msg = tokio::select! {
+            _ = &mut writer => break,
+            msg = receiver.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
+        if let Message::Close(_) = msg {
+            log::info!("user: {} disconnected", *user_id);
+            break;
         }
-        let mut update = Update::from(msg);
-        update.from = Some(Arc::clone(&user_id).into_objetc_id());
-        update.timestamp = Some(Utc::now());
-        match docs.lock().await.get(doc_id) {
-            Some(clients) => {
-                for client in clients {
-                    if client.id == *user_id{
-                        continue;
-                    }
-                    #[allow(unused)]
-                    client.sender.send(Message::from(update.clone())).await;
+        let msg = match protocol::decode(msg, params.protocol) {
+            Ok(Some(m)) => m,
+            Ok(None) => continue,
+            Err(e) => {
+                let msg = ServerMessage::error(ErrorCode::InvalidMessage, e.error, None);
+                if let Some(tx) = errors.upgrade() {
+                    protocol::send(&tx, params.protocol, &msg);
                 }
-                if let Err(e) = db.handle_update(doc_id, update).await {
-                    #[allow(unused)]
-                    tx.send(Message::from(
-                        json!({
-                            "err":e.error
-                        })
-                        .to_string(),
-                    ))
-                    .await;
-                };
-            }
-            None => {
-                #[allow(unused)]
-                tx.send(Message::Text(
-                    json!({
-                        "err":"An error occurred",
-                    })
-                    .to_string()
-                    .into(),
-                ));
+                continue;
             }
         };
+        if !handle.message(&conn, msg).await {
+            break;
+        }
+    }
+
+    writer.abort();
+    handle.leave(&conn).await;
+    log::debug!("{} disconnected", user_id);
+}
+
+///Relay a client to the node that owns its doc, until either end goes away
+///or the owner loses its claim
+async fn tunnel(
+    fanout: Arc<Fanout>,
+    owner: String,
+    (user_id, session_id): (String, String),
+    doc_id: String,
+    params: EditQuery,
+    ws: WebSocket,
+) {
+    let conn = ObjectId::new().to_hex();
+    let (tx, mut rx) = mpsc::channel::<Message>(128);
+    fanout.tunnels.lock().await.insert(conn.clone(), tx);
+    let (mut sender, mut receiver) = ws.split();
+    let open = Packet::Open {
+        node: fanout.broker.node().to_string(),
+        conn: conn.clone(),
+        doc: doc_id.clone(),
+        user: user_id,
+        session: session_id,
+        query: params.clone(),
+    };
+    let mut res = fanout.broker.send(&owner, open).await;
+    let mut check = tokio::time::interval(LEASE_TTL);
+    check.tick().await;
+    while res.is_ok() {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => {
+                    if sender.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            frame = receiver.next() => match frame {
+                Some(Ok(Message::Text(text))) => {
+                    let frame = Packet::Frame { conn: conn.clone(), text: text.to_string() };
+                    res = fanout.broker.send(&owner, frame).await;
+                }
+                Some(Ok(Message::Binary(_))) => {
+                    let msg = ServerMessage::error(
+                        ErrorCode::InvalidMessage,
+                        "binary frames are not supported",
+                        None,
+                    );
+                    if let Some(msg) = protocol::encode(&msg, params.protocol) {
+                        #[allow(unused)]
+                        sender.send(msg).await;
+                    }
+                }
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            _ = check.tick() => {
+                if fanout.broker.owner(&doc_id).await.ok().flatten().as_ref() != Some(&owner) {
+                    log::info!("{} changed owner, closing tunnel {}", doc_id, conn);
+                    break;
+                }
+            }
+        }
+    }
+    if let Err(e) = res {
+        log::error!("tunnel {} to {} failed: {}", conn, owner, e);
     }
+    fanout.tunnels.lock().await.remove(&conn);
+    #[allow(unused)]
+    fanout.broker.send(&owner, Packet::Close { conn }).await;
+}
 
-    readloop.abort();
+///Serve the packets other nodes send this one
+pub fn spawn_relay(fanout: Arc<Fanout>, mut inbox: Receiver<Packet>, docs: DocsMap, db: Arc<Db>) {
+    tokio::spawn(async move {
+        while let Some(packet) = inbox.recv().await {
+            match packet {
+                Packet::Open {
+                    node,
+                    conn,
+                    doc,
+                    user,
+                    session,
+                    query,
+                } => {
+                    let (tx, rx) = mpsc::channel::<Message>(128);
+                    fanout.tunnels.lock().await.insert(conn.clone(), tx);
+                    tokio::spawn(host_tunnel(
+                        Arc::clone(&fanout),
+                        node,
+                        conn,
+                        doc,
+                        (user, session),
+                        query,
+                        rx,
+                        (Arc::clone(&docs), Arc::clone(&db)),
+                    ));
+                }
+                Packet::Frame { conn, text } | Packet::Deliver { conn, text } => {
+                    fanout.forward(&conn, Message::text(text)).await;
+                }
+                Packet::Close { conn } => {
+                    fanout.tunnels.lock().await.remove(&conn);
+                }
+            }
+        }
+    });
+}
 
-    if let Some(_) = docs.lock().await.remove(*user_id) {
-        log::debug!("{} disconnected", user_id);
+///Edit for a client tunnelled from `node` as if it were connected here
+#[allow(clippy::too_many_arguments)]
+async fn host_tunnel(
+    fanout: Arc<Fanout>,
+    node: String,
+    conn: String,
+    doc_id: String,
+    user: (String, String),
+    params: EditQuery,
+    frames: Receiver<Message>,
+    (docs, db): (DocsMap, Arc<Db>),
+) {
+    match fanout.owns(&doc_id).await {
+        Ok(true) => {
+            let state = (Arc::clone(&fanout), node.clone(), conn.clone());
+            let sender = sink::unfold(state, async |(fanout, node, conn), msg: Message| {
+                if let Message::Text(text) = msg {
+                    let text = text.to_string();
+                    let packet = Packet::Deliver {
+                        conn: conn.clone(),
+                        text,
+                    };
+                    fanout.broker.send(&node, packet).await?;
+                }
+                Ok::<_, Error>((fanout, node, conn))
+            });
+            let receiver = stream::unfold(frames, async |mut frames| {
+                frames.recv().await.map(|msg| (msg, frames))
+            });
+            handle_edit(
+                docs,
+                user,
+                doc_id,
+                params,
+                db,
+                Box::pin(sender),
+                Box::pin(receiver),
+                Arc::clone(&fanout),
+            )
+            .await;
+        }
+        Ok(false) => log::info!("{} moved to another node before {} opened", doc_id, conn),
+        Err(e) => log::error!("could not claim {}: {}", doc_id, e),
     }
+    fanout.tunnels.lock().await.remove(&conn);
+    #[allow(unused)]
+    fanout.broker.send(&node, Packet::Close { conn }).await;
 }
@@ -0,0 +1,234 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, extract::Path, http::StatusCode, response::IntoResponse};
+use mongodb::bson::oid::ObjectId;
+use serde_json::json;
+use tower_cookies::Cookies;
+
+use crate::{
+    db::Db,
+    models::{DocAccess, InvitationResponse, NewInvitation, Role},
+    utils::decode_cookie,
+};
+
+///Invite an email address to a doc, for the owner only. Addresses without an
+///account get the invitation once they verify their email.
+pub async fn invite(
+    Extension(access): Extension<DocAccess>,
+    Extension(db): Extension<Arc<Db>>,
+    Json(body): Json<NewInvitation>,
+) -> impl IntoResponse {
+    if access.role != Role::Owner {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"only the owner can invite collaborators"
+            })),
+        );
+    }
+    if body.role == Role::Owner {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"a document has a single owner"
+            })),
+        );
+    }
+    if !body.email.contains('@') {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"invalid email address"
+            })),
+        );
+    }
+    match db
+        .invite(&access.doc, &body.email, body.role, access.user)
+        .await
+    {
+        Ok(invitation) => (
+            StatusCode::OK,
+            Json(json!({
+                "invitation":invitation
+            })),
+        ),
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "err":e.to_string()
+                })),
+            )
+        }
+    }
+}
+
+///Pending invitations to a doc, for the owner only
+pub async fn get_doc_invitations(
+    Extension(access): Extension<DocAccess>,
+    Extension(db): Extension<Arc<Db>>,
+) -> impl IntoResponse {
+    if access.role != Role::Owner {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"only the owner can see invitations"
+            })),
+        );
+    }
+    let Some(doc_id) = access.doc.id else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"Document not found"
+            })),
+        );
+    };
+    match db.find_doc_invitations(doc_id).await {
+        Ok(invitations) => (
+            StatusCode::OK,
+            Json(json!({
+                "invitations":invitations
+            })),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({
+                "err":e.to_string()
+            })),
+        ),
+    }
+}
+
+///Withdraw an invitation to a doc, for the owner only
+pub async fn revoke_invitation(
+    Extension(access): Extension<DocAccess>,
+    Extension(db): Extension<Arc<Db>>,
+    Path((_, invitation_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    if access.role != Role::Owner {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"only the owner can revoke invitations"
+            })),
+        );
+    }
+    let (Some(doc_id), Ok(invitation_id)) = (access.doc.id, ObjectId::parse_str(&invitation_id))
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"invitation not found"
+            })),
+        );
+    };
+    match db.delete_invitation(doc_id, invitation_id).await {
+        Ok(invitation) => (
+            StatusCode::OK,
+            Json(json!({
+                "invitation_id":invitation.id
+            })),
+        ),
+        Err(e) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":e.to_string()
+            })),
+        ),
+    }
+}
+
+///Invitations waiting for the caller
+pub async fn get_invitations(
+    Extension(db): Extension<Arc<Db>>,
+    cookies: Cookies,
+) -> impl IntoResponse {
+    if let Some(cookie) = cookies.get("token")
+        && let Some(claims) = decode_cookie(cookie).await
+    {
+        return match db.find_user_invitations(claims.sub).await {
+            Ok(invitations) => (
+                StatusCode::OK,
+                Json(json!({
+                    "invitations":invitations
+                })),
+            ),
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.to_string()
+                })),
+            ),
+        };
+    }
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(json!({
+            "err":"unauthorized"
+        })),
+    )
+}
+
+///Accept or decline an invitation addressed to the caller
+pub async fn respond_to_invitation(
+    Extension(db): Extension<Arc<Db>>,
+    cookies: Cookies,
+    Json(res): Json<InvitationResponse>,
+) -> impl IntoResponse {
+    let Some(claims) = cookies.get("token") else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "err":"unauthorized"
+            })),
+        );
+    };
+    let Some(claims) = decode_cookie(claims).await else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "err":"unauthorized"
+            })),
+        );
+    };
+    let (accept, invitation_id) = match res {
+        InvitationResponse::Accept(id) => (true, id),
+        InvitationResponse::Decline(id) => (false, id),
+    };
+    let (Ok(invitation_id), Ok(user_id)) = (
+        ObjectId::parse_str(&invitation_id),
+        ObjectId::parse_str(&claims.sub),
+    ) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"invitation not found"
+            })),
+        );
+    };
+    let res = if accept {
+        db.accept_invitation(invitation_id, user_id).await
+    } else {
+        db.decline_invitation(invitation_id, user_id).await
+    };
+    match res {
+        Ok(invitation) => (
+            StatusCode::OK,
+            Json(json!({
+                "success":true,
+                "doc":invitation.doc
+            })),
+        ),
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "err":"invitation not found"
+                })),
+            )
+        }
+    }
+}
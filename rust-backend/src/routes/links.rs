@@ -0,0 +1,266 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, extract::Path, http::StatusCode, response::IntoResponse};
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde_json::json;
+use tower_cookies::Cookies;
+
+use crate::{
+    db::Db,
+    models::{DocAccess, DocsMap, NewShareLink, OpenShareLink, Role, ShareLink},
+    session,
+    utils::{self, session_claims},
+};
+
+///Create a share link for a doc, for the owner only. The token is only ever
+///shown in this response.
+pub async fn create_link(
+    Extension(access): Extension<DocAccess>,
+    Extension(db): Extension<Arc<Db>>,
+    Json(body): Json<NewShareLink>,
+) -> impl IntoResponse {
+    if access.role != Role::Owner {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"only the owner can share links"
+            })),
+        );
+    }
+    if body.role == Role::Owner {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"a document has a single owner"
+            })),
+        );
+    }
+    let expires_at = body.expires_at.map(DateTime::from_chrono);
+    if expires_at.is_some_and(|e| e <= DateTime::now()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"expiry must be in the future"
+            })),
+        );
+    }
+    let Some(doc_id) = access.doc.id else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"Document not found"
+            })),
+        );
+    };
+    let secret = utils::random_token();
+    let password = match body.password.filter(|p| !p.is_empty()) {
+        Some(p) => utils::hash_password(p.as_bytes()).map(Some),
+        None => Ok(None),
+    };
+    let password = match password {
+        Ok(p) => p,
+        Err(e) => {
+            log::error!("{}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":"an error occurred"
+                })),
+            );
+        }
+    };
+    let mut link = ShareLink {
+        id: None,
+        doc: doc_id,
+        role: body.role,
+        secret: utils::hash_token(&secret),
+        password,
+        expires_at,
+        created_by: access.user,
+        created_at: DateTime::now(),
+    };
+    match db.create_share_link(&link).await {
+        Ok(id) => {
+            link.id = Some(id);
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "token":format!("{}.{}", id.to_hex(), secret),
+                    "link":link.summary()
+                })),
+            )
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":"an error occurred"
+                })),
+            )
+        }
+    }
+}
+
+///Share links of a doc, for the owner only
+pub async fn get_links(
+    Extension(access): Extension<DocAccess>,
+    Extension(db): Extension<Arc<Db>>,
+) -> impl IntoResponse {
+    if access.role != Role::Owner {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"only the owner can see share links"
+            })),
+        );
+    }
+    let Some(doc_id) = access.doc.id else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"Document not found"
+            })),
+        );
+    };
+    match db.find_doc_share_links(doc_id).await {
+        Ok(links) => (
+            StatusCode::OK,
+            Json(json!({
+                "links":links
+                    .iter()
+                    .filter(|l| !l.is_expired())
+                    .map(ShareLink::summary)
+                    .collect::<Vec<_>>()
+            })),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({
+                "err":e.to_string()
+            })),
+        ),
+    }
+}
+
+///Stop a share link from working, for the owner only. Access already granted
+///through it is kept.
+pub async fn revoke_link(
+    Extension(access): Extension<DocAccess>,
+    Extension(db): Extension<Arc<Db>>,
+    Path((_, link_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    if access.role != Role::Owner {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"only the owner can revoke share links"
+            })),
+        );
+    }
+    let (Some(doc_id), Ok(link_id)) = (access.doc.id, ObjectId::parse_str(&link_id)) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"link not found"
+            })),
+        );
+    };
+    match db.delete_share_link(doc_id, link_id).await {
+        Ok(link) => (
+            StatusCode::OK,
+            Json(json!({
+                "link_id":link.id
+            })),
+        ),
+        Err(e) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":e.to_string()
+            })),
+        ),
+    }
+}
+
+///Open a share link. Logged in users get the link's role on the doc, anyone
+///else can only read docs shared with a view link.
+pub async fn open_link(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(docs): Extension<DocsMap>,
+    Path(token): Path<String>,
+    cookies: Cookies,
+    body: Option<Json<OpenShareLink>>,
+) -> impl IntoResponse {
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"link not found or expired"
+            })),
+        )
+    };
+    let Some((link_id, secret)) = token.split_once('.') else {
+        return not_found();
+    };
+    let link = match ObjectId::parse_str(link_id) {
+        Ok(id) => db.find_share_link(id).await,
+        Err(e) => Err(e.to_string().into()),
+    };
+    let Ok(link) = link else {
+        return not_found();
+    };
+    if link.is_expired() || !utils::verify_token_hash(secret, &link.secret) {
+        return not_found();
+    }
+    if let Some(hash) = &link.password {
+        let password = body.and_then(|b| b.0.password).unwrap_or_default();
+        if utils::verify_password_hash(&password, hash).is_err() {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":"this link needs the right password",
+                    "password":true
+                })),
+            );
+        }
+    }
+    let mut doc = match db.find_doc_with_id(link.doc).await {
+        Ok(d) => d,
+        Err(_) => return not_found(),
+    };
+    let user = match session_claims(&db, &cookies).await {
+        Some(claims) => db
+            .find_user_with_id(&claims.sub)
+            .await
+            .ok()
+            .and_then(|u| u.id),
+        None => None,
+    };
+    let role = match user {
+        Some(user) => {
+            if let Err(e) = db.grant_role(&doc, user, link.role).await {
+                log::error!("{}", e);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({
+                        "err":"an error occurred"
+                    })),
+                );
+            }
+            doc.role(&user).map_or(link.role, |r| r.max(link.role))
+        }
+        None if link.role == Role::Viewer => Role::Viewer,
+        None => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":"log in to open this link"
+                })),
+            );
+        }
+    };
+    session::overlay(&docs, &mut doc).await;
+    let mut body = json!(doc);
+    body["role"] = json!(role);
+    (StatusCode::OK, Json(body))
+}
@@ -1,31 +1,125 @@
-use axum::{Router, extract::Request, routing::{get, post, put}};
+use crate::middleware;
+use axum::{
+    Router,
+    extract::Request,
+    routing::{delete, get, post, put},
+};
+mod account;
 mod auth;
 mod docs;
 mod edit;
+mod invitations;
+mod links;
+mod permissions;
+mod sso;
+mod two_factor;
+mod versions;
+
+pub use edit::spawn_relay;
 // mod user;
-pub fn auth_routes() -> Router{
-    let router = Router::new()
+pub fn auth_routes() -> Router {
+    Router::new()
         .route("/login", post(auth::login))
         .route("/signup", post(auth::signup))
-        .route("/me", get(auth::me));
-    router
+        .route("/me", get(auth::me))
+        .route("/refresh", post(auth::refresh))
+        .route("/login/2fa", post(auth::login_two_factor))
+        .route("/oidc", get(sso::providers))
+        .route("/oidc/{provider}", get(sso::start))
+        .route("/oidc/{provider}/callback", get(sso::callback))
+        .route("/logout", post(auth::logout))
+        .route("/password/forgot", post(account::forgot_password))
+        .route("/password/reset", post(account::reset_password))
+        .route("/verify", post(account::verify_email))
+        .merge(
+            Router::new()
+                .route("/logout_all", post(auth::logout_all))
+                .route("/verify/request", post(account::request_verification))
+                .route("/2fa/setup", post(two_factor::setup))
+                .route("/2fa/confirm", post(two_factor::confirm))
+                .route("/2fa/disable", post(two_factor::disable))
+                .route(
+                    "/2fa/recovery_codes",
+                    post(two_factor::regenerate_recovery_codes),
+                )
+                .route("/sessions", get(auth::get_sessions))
+                .route("/sessions/{session_id}", delete(auth::revoke_session))
+                .route_layer(axum::middleware::from_fn(middleware::auth_middleware)),
+        )
 }
-pub fn doc_routes() -> Router{
-    let router = Router::new()
-        .route("/edit/{id}", get(edit::edit))
+pub fn doc_routes() -> Router {
+    Router::new()
         .route("/get_docs", get(docs::get_docs))
         .route("/create", post(docs::create))
         .route("/collab/{doc_id}", get(docs::collab_request))
         .route("/get_collab_requests", get(docs::get_collab_requests))
         .route("/collab/request", post(docs::handle_collab_request))
+        .route("/get_invitations", get(invitations::get_invitations))
+        .route("/invitation", post(invitations::respond_to_invitation))
+        .route("/upload", put(docs::upload_doc))
+        .merge(doc_access_routes())
+}
+///Routes about a single doc, only reachable by users with a role on it.
+///Every new route that reads or changes a doc belongs here.
+fn doc_access_routes() -> Router {
+    Router::new()
+        .route("/edit/{id}", get(edit::edit))
         .route("/get_doc", get(docs::get_doc))
-        .route("/upload", put(docs::upload_doc));
-    router
+        .route("/history/{doc_id}", get(docs::get_doc_history))
+        .route("/history/{doc_id}/diff", get(docs::get_doc_diff))
+        .route(
+            "/versions/{doc_id}",
+            get(versions::get_versions).post(versions::create_version),
+        )
+        .route(
+            "/versions/{doc_id}/{version_id}",
+            get(versions::get_version),
+        )
+        .route(
+            "/versions/{doc_id}/{version_id}/diff",
+            get(versions::diff_version),
+        )
+        .route(
+            "/versions/{doc_id}/{version_id}/restore",
+            post(versions::restore_version),
+        )
+        .route(
+            "/invitations/{doc_id}",
+            get(invitations::get_doc_invitations).post(invitations::invite),
+        )
+        .route(
+            "/invitations/{doc_id}/{invitation_id}",
+            delete(invitations::revoke_invitation),
+        )
+        .route(
+            "/links/{doc_id}",
+            get(links::get_links).post(links::create_link),
+        )
+        .route("/links/{doc_id}/{link_id}", delete(links::revoke_link))
+        .route(
+            "/permissions/{doc_id}",
+            get(permissions::get_permissions).put(permissions::set_role),
+        )
+        .route(
+            "/permissions/{doc_id}/{user_id}",
+            delete(permissions::remove_collaborator),
+        )
+        .route("/permissions/{doc_id}/leave", post(permissions::leave))
+        .route(
+            "/permissions/{doc_id}/transfer",
+            post(permissions::transfer_ownership),
+        )
+        .route_layer(axum::middleware::from_fn(middleware::doc_access))
+}
+///Share links, opened with or without an account
+pub fn share_routes() -> Router {
+    Router::new().route("/{token}", post(links::open_link))
+}
+pub fn user_routes() -> Router {
+    Router::new().route(
+        "/profile",
+        get(|req: Request| async move {
+            log::debug!("{:?}", req);
+        }),
+    )
 }
-pub fn user_routes() -> Router{
-    let router = Router::new()
-        .route("/profile", get( |req: Request| async move {
-            log::debug!("{:?}",req);
-        }));
-    router
-}
\ No newline at end of file
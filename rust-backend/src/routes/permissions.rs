@@ -0,0 +1,249 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, extract::Path, http::StatusCode, response::IntoResponse};
+use mongodb::bson::oid::ObjectId;
+use serde_json::json;
+
+use crate::{
+    db::Db,
+    models::{Author, DocAccess, DocsMap, OwnershipTransfer, Role, RoleChange},
+    session,
+};
+
+///Who has access to a doc and with which role
+pub async fn get_permissions(Extension(access): Extension<DocAccess>) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(json!({
+            "owner":access.doc.author,
+            "permissions":access.doc.permissions
+        })),
+    )
+}
+
+///Change the role of someone the doc is shared with, for the owner only.
+///Connected clients of that user get the new role right away.
+pub async fn set_role(
+    Extension(access): Extension<DocAccess>,
+    Extension(db): Extension<Arc<Db>>,
+    Extension(docs): Extension<DocsMap>,
+    Json(body): Json<RoleChange>,
+) -> impl IntoResponse {
+    if access.role != Role::Owner {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"only the owner can change roles"
+            })),
+        );
+    }
+    if body.role == Role::Owner {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"a document has a single owner"
+            })),
+        );
+    }
+    let Ok(user) = ObjectId::parse_str(&body.user) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"invalid user id"
+            })),
+        );
+    };
+    let Some(doc_id) = access.doc.id else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"Document not found"
+            })),
+        );
+    };
+    match db.set_role(doc_id, user, body.role).await {
+        Ok(true) => {
+            session::refresh(&docs, &doc_id.to_hex()).await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "user":user,
+                    "role":body.role
+                })),
+            )
+        }
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"the document is not shared with this user"
+            })),
+        ),
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":"an error occurred"
+                })),
+            )
+        }
+    }
+}
+
+///Take a collaborator off a doc, for the owner only. Their open sessions are
+///closed.
+pub async fn remove_collaborator(
+    Extension(access): Extension<DocAccess>,
+    Extension(db): Extension<Arc<Db>>,
+    Extension(docs): Extension<DocsMap>,
+    Path((_, user_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    if access.role != Role::Owner {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"only the owner can remove collaborators"
+            })),
+        );
+    }
+    let Ok(user) = ObjectId::parse_str(&user_id) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"invalid user id"
+            })),
+        );
+    };
+    revoke(&db, &docs, &access, user).await
+}
+
+///Stop collaborating on a doc. The owner has to hand it over first.
+pub async fn leave(
+    Extension(access): Extension<DocAccess>,
+    Extension(db): Extension<Arc<Db>>,
+    Extension(docs): Extension<DocsMap>,
+) -> impl IntoResponse {
+    if access.role == Role::Owner {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"transfer ownership before leaving the document"
+            })),
+        );
+    }
+    revoke(&db, &docs, &access, access.user).await
+}
+
+///Make another collaborator the owner of a doc, for the owner only. The
+///previous owner stays on as an editor.
+pub async fn transfer_ownership(
+    Extension(access): Extension<DocAccess>,
+    Extension(db): Extension<Arc<Db>>,
+    Extension(docs): Extension<DocsMap>,
+    Json(body): Json<OwnershipTransfer>,
+) -> impl IntoResponse {
+    if access.role != Role::Owner {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"only the owner can transfer ownership"
+            })),
+        );
+    }
+    let Ok(user) = ObjectId::parse_str(&body.user) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"invalid user id"
+            })),
+        );
+    };
+    let Some(doc_id) = access.doc.id else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"Document not found"
+            })),
+        );
+    };
+    let user = match db.find_user_with_id(&user).await {
+        Ok(u) => u,
+        Err(e) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "err":e
+                })),
+            );
+        }
+    };
+    match db.transfer_ownership(doc_id, access.user, &user).await {
+        Ok(true) => {
+            session::refresh(&docs, &doc_id.to_hex()).await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "owner":Author { id: user.id, name: user.name }
+                })),
+            )
+        }
+        Ok(false) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"ownership can only go to a collaborator"
+            })),
+        ),
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":"an error occurred"
+                })),
+            )
+        }
+    }
+}
+
+///Take `user` off the doc and close their open sessions
+async fn revoke(
+    db: &Db,
+    docs: &DocsMap,
+    access: &DocAccess,
+    user: ObjectId,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let Some(doc_id) = access.doc.id else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"Document not found"
+            })),
+        );
+    };
+    match db.remove_permission(doc_id, user).await {
+        Ok(true) => {
+            session::refresh(docs, &doc_id.to_hex()).await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "removed":user
+                })),
+            )
+        }
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"the document is not shared with this user"
+            })),
+        ),
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":"an error occurred"
+                })),
+            )
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    Extension, Json,
+    extract::{ConnectInfo, Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+};
+use mongodb::bson::DateTime;
+use reqwest::Url;
+use serde_json::json;
+use tower_cookies::Cookies;
+
+use super::auth;
+use crate::{
+    db::Db,
+    mailer,
+    models::{Error, Identity, OidcCallback, OidcLogin, OidcStart, User},
+    oidc::{self, IdClaims, Oidc, Provider},
+    utils,
+};
+
+/// Seconds a user has to log in at the provider
+const OIDC_LOGIN_TTL: u64 = 10 * 60;
+/// Path the state cookie is sent to
+const STATE_COOKIE_PATH: &str = "/api/auth/oidc";
+/// Page of the web app to land on when the login did not ask for one
+const DEFAULT_REDIRECT: &str = "/dashboard";
+
+///Send the browser back to the login page of the web app with `params`
+fn to_login(params: &[(&str, &str)]) -> Response {
+    match Url::parse_with_params(&mailer::app_link("/auth"), params) {
+        Ok(url) => Redirect::to(url.as_str()).into_response(),
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":"an error occurred"
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+fn fail(err: &str) -> Response {
+    to_login(&[("error", err)])
+}
+
+///Identity providers users can log in with
+pub async fn providers(Extension(oidc): Extension<Arc<Oidc>>) -> impl IntoResponse {
+    let providers: Vec<_> = oidc
+        .providers()
+        .map(|p| json!({"name":p.name, "label":p.label}))
+        .collect();
+    (
+        StatusCode::OK,
+        Json(json!({
+            "providers":providers
+        })),
+    )
+}
+
+///Start logging in with a provider, redirecting the browser to it
+pub async fn start(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(oidc): Extension<Arc<Oidc>>,
+    cookies: Cookies,
+    Path(provider): Path<String>,
+    Query(query): Query<OidcStart>,
+) -> Response {
+    let Some(provider) = oidc.provider(&provider) else {
+        return fail("unknown sign-in provider");
+    };
+    // only pages of the web app, not other sites
+    let redirect = query
+        .redirect
+        .filter(|r| r.starts_with('/') && !r.starts_with("//") && !r.starts_with("/\\"))
+        .unwrap_or_else(|| DEFAULT_REDIRECT.to_string());
+    let login = OidcLogin {
+        id: None,
+        provider: provider.name.clone(),
+        state: utils::random_token(),
+        nonce: utils::random_token(),
+        verifier: oidc::code_verifier(),
+        redirect,
+        expires_at: DateTime::from_system_time(
+            SystemTime::now() + Duration::from_secs(OIDC_LOGIN_TTL),
+        ),
+    };
+    let url = match oidc
+        .authorize_url(provider, &login.state, &login.nonce, &login.verifier)
+        .await
+    {
+        Ok(url) => url,
+        Err(e) => {
+            log::error!("{}", e);
+            return fail("could not reach the sign-in provider");
+        }
+    };
+    if let Err(e) = db.create_oidc_login(&login).await {
+        log::error!("{}", e);
+        return fail("an error occurred");
+    }
+    // ties the callback to this browser, so nobody can log it in as someone else
+    let mut state = auth::cookie("oidc_state", login.state, STATE_COOKIE_PATH);
+    state.set_max_age(tower_cookies::cookie::time::Duration::seconds(
+        OIDC_LOGIN_TTL as i64,
+    ));
+    cookies.add(state);
+    Redirect::to(&url).into_response()
+}
+
+///Where providers send the browser back to. Logs the user in, with the
+///account the provider identity is linked to or the one with its verified
+///email, which is created if there is none.
+pub async fn callback(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(oidc): Extension<Arc<Oidc>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    cookies: Cookies,
+    Path(provider): Path<String>,
+    Query(query): Query<OidcCallback>,
+) -> Response {
+    let Some(provider) = oidc.provider(&provider) else {
+        return fail("unknown sign-in provider");
+    };
+    if let Some(error) = query.error {
+        log::info!("{} login failed: {}", provider.name, error);
+        return fail(query.error_description.as_deref().unwrap_or(&error));
+    }
+    let (Some(code), Some(state)) = (query.code, query.state) else {
+        return fail("the sign-in provider sent an invalid response");
+    };
+    let expected = cookies.get("oidc_state").map(|c| c.value().to_string());
+    cookies.remove(auth::cookie("oidc_state", String::new(), STATE_COOKIE_PATH));
+    if expected.as_deref() != Some(state.as_str()) {
+        return fail("sign-in could not be verified, try again");
+    }
+    let login = match db.take_oidc_login(&provider.name, &state).await {
+        Ok(l) => l,
+        Err(e) => return fail(&e.to_string()),
+    };
+    let claims = match oidc
+        .exchange(provider, &code, &login.verifier, &login.nonce)
+        .await
+    {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("{} login failed: {}", provider.name, e);
+            return fail("sign-in could not be verified, try again");
+        }
+    };
+    let user = match linked_user(&db, provider, &claims).await {
+        Ok(u) => u,
+        Err(e) => return fail(&e.to_string()),
+    };
+    let Some(user_id) = user.id else {
+        return fail("an error occurred");
+    };
+    if user.two_factor.as_ref().is_some_and(|t| t.enabled) {
+        return match auth::challenge(&db, user_id).await {
+            Ok(challenge) => to_login(&[("challenge", &challenge), ("redirect", &login.redirect)]),
+            Err(e) => {
+                log::error!("{}", e);
+                fail("an error occurred")
+            }
+        };
+    }
+    if let Err(e) = auth::start_session(&db, &cookies, user_id, auth::origin(&headers, addr)).await
+    {
+        log::error!("{}", e);
+        return fail("an error occurred");
+    }
+    Redirect::to(&mailer::app_link(&login.redirect)).into_response()
+}
+
+///User a provider identity logs in as, linking it on first use
+async fn linked_user(db: &Db, provider: &Provider, claims: &IdClaims) -> Result<User, Error> {
+    if let Some(identity) = db.find_identity(&provider.name, &claims.sub).await? {
+        return db
+            .find_user_with_id(&identity.user)
+            .await
+            .map_err(Error::from);
+    }
+    let Some(email) = claims.verified_email().map(utils::normalize_email) else {
+        return Err(Error::new(format!(
+            "your {} account has no verified email",
+            provider.label
+        )));
+    };
+    let email = email.as_str();
+    let user = match db.find_user_with_email(email).await? {
+        // someone may have signed up with the address without owning it
+        Some(u) if !u.email_verified => {
+            return Err(Error::from(
+                "an account with this email is not verified yet, verify it or reset its password first",
+            ));
+        }
+        Some(u) => u,
+        None => {
+            let name = claims
+                .name
+                .clone()
+                .filter(|n| !n.trim().is_empty())
+                .unwrap_or_else(|| email.split('@').next().unwrap_or(email).to_string());
+            let user = User {
+                id: None,
+                name,
+                email: email.to_string(),
+                // nobody knows it, the account logs in through the provider
+                password: utils::random_token(),
+                doc_count: None,
+                email_verified: false,
+                two_factor: None,
+            };
+            db.create_user(user).await?;
+            let user = db
+                .find_user_with_email(email)
+                .await?
+                .ok_or(Error::from("could not create account"))?;
+            let user_id = user.id.ok_or(Error::from("could not create account"))?;
+            db.verify_email(user_id, email).await?;
+            user
+        }
+    };
+    let identity = Identity {
+        id: None,
+        provider: provider.name.clone(),
+        subject: claims.sub.clone(),
+        user: user.id.ok_or(Error::from("User Not Found"))?,
+        email: Some(email.to_string()),
+        created_at: DateTime::now(),
+    };
+    db.link_identity(&identity).await?;
+    Ok(user)
+}
@@ -0,0 +1,225 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, http::StatusCode, response::IntoResponse};
+use mongodb::bson::DateTime;
+use serde_json::json;
+
+use crate::{
+    db::Db,
+    models::{Claims, Error, TwoFactor, TwoFactorCode, User},
+    totp, utils,
+};
+
+///Check a TOTP or recovery code against a user's enabled second factor,
+///using it up if it is valid
+pub async fn verify_code(db: &Db, user: &User, code: &str) -> Result<bool, Error> {
+    let (Some(user_id), Some(two_factor)) = (user.id, &user.two_factor) else {
+        return Ok(false);
+    };
+    if !two_factor.enabled {
+        return Ok(false);
+    }
+    if let Some(step) = totp::check(&two_factor.secret, code, two_factor.last_step)? {
+        return db.use_totp_step(user_id, step).await;
+    }
+    let code = totp::normalize(code);
+    for hash in &two_factor.recovery_codes {
+        if utils::verify_password_hash(&code, hash).is_ok() {
+            return db.use_recovery_code(user_id, hash).await;
+        }
+    }
+    Ok(false)
+}
+
+///Whether too many wrong codes at login locked a user's second factor for now
+pub fn locked(user: &User) -> bool {
+    user.two_factor
+        .as_ref()
+        .and_then(|t| t.locked_until)
+        .is_some_and(|until| until > DateTime::now())
+}
+
+async fn current_user(
+    db: &Db,
+    claims: &Claims,
+) -> Result<User, (StatusCode, Json<serde_json::Value>)> {
+    db.find_user_with_id(&claims.sub).await.map_err(|e| {
+        (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "success":false,
+                "err":e
+            })),
+        )
+    })
+}
+
+fn bad_request(err: &str) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "success":false,
+            "err":err
+        })),
+    )
+}
+
+fn internal_error(e: Error) -> (StatusCode, Json<serde_json::Value>) {
+    log::error!("{}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({
+            "success":false,
+            "err":"an error occurred"
+        })),
+    )
+}
+
+///Start enrolling an authenticator. Returns the secret and the provisioning
+///URI to show as a QR code, 2FA is only enabled once a first code confirms it.
+pub async fn setup(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(claims): Extension<Claims>,
+) -> impl IntoResponse {
+    let user = match current_user(&db, &claims).await {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+    if user.two_factor.as_ref().is_some_and(|t| t.enabled) {
+        return bad_request("two-factor authentication is already enabled");
+    }
+    let secret = totp::generate_secret();
+    let uri = match totp::provisioning_uri(&secret, &user.email) {
+        Ok(u) => u,
+        Err(e) => return internal_error(e),
+    };
+    let two_factor = TwoFactor {
+        secret: secret.clone(),
+        enabled: false,
+        recovery_codes: Vec::new(),
+        last_step: 0,
+        failures: 0,
+        locked_until: None,
+    };
+    if let Err(e) = db
+        .set_two_factor(claims.sub.as_str(), Some(&two_factor))
+        .await
+    {
+        return internal_error(e);
+    }
+    (
+        StatusCode::OK,
+        Json(json!({
+            "secret":secret,
+            "uri":uri
+        })),
+    )
+}
+
+///Enable 2FA with a first code from the authenticator being enrolled.
+///Returns the recovery codes, which are not shown again.
+pub async fn confirm(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(claims): Extension<Claims>,
+    Json(body): Json<TwoFactorCode>,
+) -> impl IntoResponse {
+    let user = match current_user(&db, &claims).await {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+    let Some(mut two_factor) = user.two_factor else {
+        return bad_request("start two-factor setup first");
+    };
+    if two_factor.enabled {
+        return bad_request("two-factor authentication is already enabled");
+    }
+    let step = match totp::check(&two_factor.secret, &body.code, two_factor.last_step) {
+        Ok(Some(step)) => step,
+        Ok(None) => return bad_request("invalid code"),
+        Err(e) => return internal_error(e),
+    };
+    let (codes, hashes) = match totp::recovery_codes() {
+        Ok(c) => c,
+        Err(e) => return internal_error(e),
+    };
+    two_factor.enabled = true;
+    two_factor.recovery_codes = hashes;
+    two_factor.last_step = step;
+    if let Err(e) = db
+        .set_two_factor(claims.sub.as_str(), Some(&two_factor))
+        .await
+    {
+        return internal_error(e);
+    }
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success":true,
+            "recovery_codes":codes
+        })),
+    )
+}
+
+///Turn 2FA off, with a code to prove the caller still holds the second factor
+pub async fn disable(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(claims): Extension<Claims>,
+    Json(body): Json<TwoFactorCode>,
+) -> impl IntoResponse {
+    let user = match current_user(&db, &claims).await {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+    if !user.two_factor.as_ref().is_some_and(|t| t.enabled) {
+        // a pending setup is dropped all the same
+        if let Err(e) = db.set_two_factor(claims.sub.as_str(), None).await {
+            return internal_error(e);
+        }
+        return bad_request("two-factor authentication is not enabled");
+    }
+    match verify_code(&db, &user, &body.code).await {
+        Ok(true) => {}
+        Ok(false) => return bad_request("invalid code"),
+        Err(e) => return internal_error(e),
+    }
+    if let Err(e) = db.set_two_factor(claims.sub.as_str(), None).await {
+        return internal_error(e);
+    }
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success":true
+        })),
+    )
+}
+
+///Replace the recovery codes, for when they ran out or leaked
+pub async fn regenerate_recovery_codes(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(claims): Extension<Claims>,
+    Json(body): Json<TwoFactorCode>,
+) -> impl IntoResponse {
+    let user = match current_user(&db, &claims).await {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+    match verify_code(&db, &user, &body.code).await {
+        Ok(true) => {}
+        Ok(false) => return bad_request("invalid code"),
+        Err(e) => return internal_error(e),
+    }
+    let (codes, hashes) = match totp::recovery_codes() {
+        Ok(c) => c,
+        Err(e) => return internal_error(e),
+    };
+    if let Err(e) = db.set_recovery_codes(claims.sub.as_str(), hashes).await {
+        return internal_error(e);
+    }
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success":true,
+            "recovery_codes":codes
+        })),
+    )
+}
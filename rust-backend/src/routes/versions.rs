@@ -0,0 +1,292 @@
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use mongodb::bson::oid::ObjectId;
+use serde_json::{Value, json};
+
+use crate::{
+    db::Db,
+    diff,
+    fanout::Fanout,
+    models::{Doc, DocAccess, DocsMap, Error, IntoObjectId, NewVersion, Version, VersionDiffQuery},
+    session,
+};
+
+///Version named in the URL. A malformed id can't name one, so it is not found.
+fn version_id(id: &str) -> Result<ObjectId, (StatusCode, Json<Value>)> {
+    ObjectId::parse_str(id).map_err(|_| {
+        (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"version not found"
+            })),
+        )
+    })
+}
+
+///Doc with any unflushed edits applied
+async fn current_doc(db: &Db, docs: &DocsMap, doc_id: &str) -> Result<Doc, Error> {
+    let mut doc = db.find_doc_with_id(doc_id).await?;
+    session::overlay(docs, &mut doc).await;
+    Ok(doc)
+}
+
+pub async fn create_version(
+    Extension(access): Extension<DocAccess>,
+    Extension(db): Extension<Arc<Db>>,
+    Extension(docs): Extension<DocsMap>,
+    Path(doc_id): Path<String>,
+    Json(body): Json<NewVersion>,
+) -> impl IntoResponse {
+    if !access.role.can_edit() {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"your role does not allow editing this document"
+            })),
+        );
+    }
+    let name = body.name.trim().to_string();
+    if name.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"version name cannot be empty"
+            })),
+        );
+    }
+    let doc = match current_doc(&db, &docs, &doc_id).await {
+        Ok(d) => d,
+        Err(e) => {
+            log::error!("{}", e);
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "err":"Document not found"
+                })),
+            );
+        }
+    };
+    let version = Version::new(
+        doc_id.as_str().into_objetc_id(),
+        name,
+        doc.revision,
+        doc.content,
+        access.user,
+    );
+    match db.create_version(&version).await {
+        Ok(r) => (
+            StatusCode::OK,
+            Json(json!({
+                "version_id":r.inserted_id,
+                "revision":version.revision
+            })),
+        ),
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":"an error occurred"
+                })),
+            )
+        }
+    }
+}
+
+pub async fn get_versions(
+    Extension(db): Extension<Arc<Db>>,
+    Path(doc_id): Path<String>,
+) -> impl IntoResponse {
+    match db.find_versions(doc_id).await {
+        Ok(versions) => (
+            StatusCode::OK,
+            Json(json!({
+                "versions":versions
+            })),
+        ),
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":"an error occurred"
+                })),
+            )
+        }
+    }
+}
+
+pub async fn get_version(
+    Extension(db): Extension<Arc<Db>>,
+    Path((doc_id, version_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let version_id = match self::version_id(&version_id) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+    match db.find_version(doc_id, version_id).await {
+        Ok(version) => (StatusCode::OK, Json(json!(version))),
+        Err(e) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":e.to_string()
+            })),
+        ),
+    }
+}
+
+pub async fn diff_version(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(docs): Extension<DocsMap>,
+    Path((doc_id, version_id)): Path<(String, String)>,
+    Query(params): Query<VersionDiffQuery>,
+) -> impl IntoResponse {
+    let version_id = match self::version_id(&version_id) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+    let against = match params.against.as_deref().map(self::version_id) {
+        Some(Ok(id)) => Some(id),
+        Some(Err(e)) => return e,
+        None => None,
+    };
+    let version = match db.find_version(doc_id.as_str(), version_id).await {
+        Ok(v) => v,
+        Err(e) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "err":e.to_string()
+                })),
+            );
+        }
+    };
+    let other = match against {
+        Some(against) => db
+            .find_version(doc_id.as_str(), against)
+            .await
+            .map(|v| v.content),
+        None => current_doc(&db, &docs, &doc_id).await.map(|d| d.content),
+    };
+    match other {
+        Ok(content) => (
+            StatusCode::OK,
+            Json(json!({
+                "diff":diff::diff(&version.content, &content)
+            })),
+        ),
+        Err(e) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":e.to_string()
+            })),
+        ),
+    }
+}
+
+///Bring the doc back to a version by editing it like a collaborator would, so
+///open sessions and the history pick the restore up
+pub async fn restore_version(
+    Extension(access): Extension<DocAccess>,
+    Extension(db): Extension<Arc<Db>>,
+    Extension(docs): Extension<DocsMap>,
+    Extension(fanout): Extension<Arc<Fanout>>,
+    Path((doc_id, version_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    if !access.role.can_edit() {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"your role does not allow editing this document"
+            })),
+        );
+    }
+    let version_id = match self::version_id(&version_id) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+    let version = match db.find_version(doc_id.as_str(), version_id).await {
+        Ok(v) => v,
+        Err(e) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "err":e.to_string()
+                })),
+            );
+        }
+    };
+    match session::replace(
+        &docs,
+        &db,
+        &fanout,
+        &doc_id,
+        &access.user.to_hex(),
+        &version.content,
+    )
+    .await
+    {
+        Ok(revision) => (
+            StatusCode::OK,
+            Json(json!({
+                "revision":revision
+            })),
+        ),
+        Err(e) => {
+            log::error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.to_string()
+                })),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOC_ID: &str = "65a1b2c3d4e5f60718293a4b";
+
+    fn status(res: impl IntoResponse) -> StatusCode {
+        res.into_response().status()
+    }
+
+    #[tokio::test]
+    async fn malformed_version_ids_are_not_found() {
+        let db = Arc::new(Db::unreachable().await);
+        let docs = DocsMap::default();
+        let res = get_version(
+            Extension(db.clone()),
+            Path((DOC_ID.to_string(), "abc".to_string())),
+        )
+        .await;
+        assert_eq!(status(res), StatusCode::NOT_FOUND);
+        let res = diff_version(
+            Extension(db.clone()),
+            Extension(docs.clone()),
+            Path((DOC_ID.to_string(), "abc".to_string())),
+            Query(VersionDiffQuery { against: None }),
+        )
+        .await;
+        assert_eq!(status(res), StatusCode::NOT_FOUND);
+        let res = diff_version(
+            Extension(db),
+            Extension(docs),
+            Path((DOC_ID.to_string(), DOC_ID.to_string())),
+            Query(VersionDiffQuery {
+                against: Some("abc".to_string()),
+            }),
+        )
+        .await;
+        assert_eq!(status(res), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,715 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
+use tokio::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+    time::{Instant, MissedTickBehavior},
+};
+
+use crate::{
+    buffer::{self, DocBuffer},
+    crdt::CrdtOp,
+    db::Db,
+    diff,
+    fanout::{Fanout, LEASE_TTL},
+    history,
+    models::{
+        Client, Doc, DocSession, DocsMap, EditMode, Error, HISTORY_LIMIT, IntoObjectId, Update,
+        UpdateType,
+    },
+    protocol::{ClientMessage, ErrorCode, Op, PROTOCOL_VERSION, PresenceEvent, ServerMessage},
+    text::PositionUnit,
+};
+
+/// Commands a doc task queues up before senders have to wait
+const INBOX_SIZE: usize = 256;
+
+/// How often an open doc re-reads roles and login sessions on its own, to catch
+/// changes made through other nodes and logouts
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Messages queued for a client before it counts as too slow and is dropped
+pub const CLIENT_QUEUE: usize = 256;
+
+/// Handle to the task that owns an open doc
+#[derive(Clone)]
+pub struct DocHandle {
+    tx: Sender<Command>,
+}
+
+enum Command {
+    Join {
+        client: Client,
+        resume: Option<u64>,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    Message {
+        conn: String,
+        msg: ClientMessage,
+    },
+    Leave {
+        conn: String,
+    },
+    Replace {
+        user_id: String,
+        content: String,
+        reply: oneshot::Sender<Result<u64, Error>>,
+    },
+    State {
+        reply: oneshot::Sender<(String, u64)>,
+    },
+    Flush {
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    /// Re-read who has access to the doc
+    Refresh,
+    /// This node's claim on the doc was renewed and holds until `until`
+    Renewed {
+        until: Instant,
+    },
+    /// Another node took the doc over
+    Disown,
+}
+
+impl Command {
+    /// Answer a command that could not be run
+    fn fail(self, e: &Error) {
+        #[allow(unused)]
+        match self {
+            Command::Join { reply, .. } | Command::Flush { reply } => {
+                reply.send(Err(Error::new(e)));
+            }
+            Command::Replace { reply, .. } => {
+                reply.send(Err(Error::new(e)));
+            }
+            Command::Message { .. }
+            | Command::Leave { .. }
+            | Command::State { .. }
+            | Command::Refresh
+            | Command::Renewed { .. }
+            | Command::Disown => {}
+        }
+    }
+}
+
+impl DocHandle {
+    /// Queue a client message for the doc, `false` if the doc was closed
+    pub async fn message(&self, conn: &str, msg: ClientMessage) -> bool {
+        let conn = conn.to_string();
+        self.tx.send(Command::Message { conn, msg }).await.is_ok()
+    }
+
+    /// Take a connection off the doc
+    pub async fn leave(&self, conn: &str) {
+        let conn = conn.to_string();
+        #[allow(unused)]
+        self.tx.send(Command::Leave { conn }).await;
+    }
+
+    /// Run a command built around `reply`, `None` if the doc closed before answering
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> Option<T> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(command(reply)).await.ok()?;
+        rx.await.ok()
+    }
+}
+
+/// Handle to the task of an open doc, starting one if it is not open yet
+async fn open(docs: &DocsMap, db: &Arc<Db>, fanout: &Arc<Fanout>, doc_id: &str) -> DocHandle {
+    let mut handles = docs.lock().await;
+    if let Some(handle) = handles.get(doc_id)
+        && !handle.tx.is_closed()
+    {
+        return handle.clone();
+    }
+    let (tx, inbox) = mpsc::channel(INBOX_SIZE);
+    let handle = DocHandle { tx };
+    handles.insert(doc_id.to_string(), handle.clone());
+    tokio::spawn(run(
+        Arc::clone(docs),
+        Arc::clone(db),
+        Arc::clone(fanout),
+        doc_id.to_string(),
+        inbox,
+    ));
+    handle
+}
+
+/// Handle to the task of a doc if it is open on this node
+async fn find(docs: &DocsMap, doc_id: &str) -> Option<DocHandle> {
+    docs.lock().await.get(doc_id).cloned()
+}
+
+/// Register a client on a doc, opening it if nobody has it open. The doc must
+/// be claimed by this node.
+pub async fn join(
+    docs: &DocsMap,
+    db: &Arc<Db>,
+    fanout: &Arc<Fanout>,
+    doc_id: &str,
+    client: Client,
+    resume: Option<u64>,
+) -> Result<DocHandle, Error> {
+    loop {
+        let handle = open(docs, db, fanout, doc_id).await;
+        let client = client.clone();
+        // a doc that closes while we join hands us nothing back, open it again
+        if let Some(res) = handle
+            .request(|reply| Command::Join {
+                client,
+                resume,
+                reply,
+            })
+            .await
+        {
+            return res.map(|()| handle);
+        }
+    }
+}
+
+/// Edit a doc into `content` on behalf of `user_id` and push the edits to
+/// everyone editing it, returning the revision the doc ends up at. Fails if
+/// the doc is being edited on another node.
+pub async fn replace(
+    docs: &DocsMap,
+    db: &Arc<Db>,
+    fanout: &Arc<Fanout>,
+    doc_id: &str,
+    user_id: &str,
+    content: &str,
+) -> Result<u64, Error> {
+    if !fanout.owns(doc_id).await? {
+        return Err(Error::from("document is open on another server"));
+    }
+    loop {
+        let handle = open(docs, db, fanout, doc_id).await;
+        if let Some(res) = handle
+            .request(|reply| Command::Replace {
+                user_id: user_id.to_string(),
+                content: content.to_string(),
+                reply,
+            })
+            .await
+        {
+            return res;
+        }
+    }
+}
+
+/// Replace the stored content of `doc` with the live one if it is open
+pub async fn overlay(docs: &DocsMap, doc: &mut Doc) {
+    let Some(id) = doc.id else {
+        return;
+    };
+    let Some(handle) = find(docs, &id.to_hex()).await else {
+        return;
+    };
+    // a doc that closed meanwhile was flushed, so the stored copy is current
+    if let Some((content, revision)) = handle.request(|reply| Command::State { reply }).await {
+        doc.content = content;
+        doc.revision = revision;
+    }
+}
+
+/// Write unflushed edits of a doc to the database if it is open
+pub async fn flush(docs: &DocsMap, doc_id: &str) -> Result<(), Error> {
+    let Some(handle) = find(docs, doc_id).await else {
+        return Ok(());
+    };
+    handle
+        .request(|reply| Command::Flush { reply })
+        .await
+        .unwrap_or(Ok(()))
+}
+
+/// Apply changed roles to the clients of a doc if it is open on this node.
+/// Docs open elsewhere pick changes up within `REFRESH_INTERVAL`.
+pub async fn refresh(docs: &DocsMap, doc_id: &str) {
+    if let Some(handle) = find(docs, doc_id).await {
+        #[allow(unused)]
+        handle.tx.send(Command::Refresh).await;
+    }
+}
+
+/// Let a doc open on this node know its claim was renewed until `until`
+pub async fn renewed(docs: &DocsMap, doc_id: &str, until: Instant) {
+    if let Some(handle) = find(docs, doc_id).await {
+        #[allow(unused)]
+        handle.tx.send(Command::Renewed { until }).await;
+    }
+}
+
+/// Flush a doc another node took over and close it here, sending its clients
+/// away so they reconnect through the new owner
+pub async fn disown(docs: &DocsMap, doc_id: &str) {
+    if let Some(handle) = find(docs, doc_id).await {
+        #[allow(unused)]
+        handle.tx.send(Command::Disown).await;
+    }
+}
+
+/// Flush every open doc
+pub async fn flush_all(docs: &DocsMap) {
+    let handles: Vec<(String, DocHandle)> = docs
+        .lock()
+        .await
+        .iter()
+        .map(|(id, h)| (id.clone(), h.clone()))
+        .collect();
+    for (doc_id, handle) in handles {
+        if let Some(Err(e)) = handle.request(|reply| Command::Flush { reply }).await {
+            log::error!("could not flush {}: {}", doc_id, e);
+        }
+    }
+}
+
+/// Task owning an open doc. Commands are handled one at a time, and the doc is
+/// flushed and closed once its last client leaves.
+async fn run(
+    docs: DocsMap,
+    db: Arc<Db>,
+    fanout: Arc<Fanout>,
+    doc_id: String,
+    mut inbox: Receiver<Command>,
+) {
+    let mut buffer = match DocBuffer::load(&db, &doc_id).await {
+        Ok(b) => b,
+        Err(e) => {
+            unregister(&mut *docs.lock().await, &doc_id, &mut inbox);
+            release(&fanout, &doc_id).await;
+            while let Some(command) = inbox.recv().await {
+                command.fail(&e);
+            }
+            return;
+        }
+    };
+    let mut session = DocSession::new();
+    // the doc was claimed just before it was opened
+    let mut leased_until = Instant::now() + LEASE_TTL;
+    let mut ticks = tokio::time::interval(buffer::flush_interval());
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticks.tick().await;
+    let mut refreshes = tokio::time::interval(REFRESH_INTERVAL);
+    refreshes.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    refreshes.tick().await;
+    loop {
+        tokio::select! {
+            command = inbox.recv() => match command {
+                Some(Command::Renewed { until }) => leased_until = leased_until.max(until),
+                Some(Command::Disown) => {
+                    hand_over(&mut session, &mut buffer, &db).await;
+                    unregister(&mut *docs.lock().await, &doc_id, &mut inbox);
+                    break;
+                }
+                Some(command) => {
+                    let leased = Instant::now() < leased_until;
+                    handle(&mut session, &mut buffer, &db, command, leased).await
+                }
+                None => break,
+            },
+            _ = ticks.tick() => {
+                collect_garbage(&session, &mut buffer);
+                if let Err(e) = buffer.flush(&db).await {
+                    log::error!("could not flush {}: {}", doc_id, e);
+                }
+            }
+            _ = refreshes.tick() => refresh_roles(&mut session, &db, buffer.id).await,
+        }
+        if !session.clients.is_empty() {
+            continue;
+        }
+        collect_garbage(&session, &mut buffer);
+        if let Err(e) = buffer.flush(&db).await {
+            // stay open so the next tick retries rather than losing edits
+            log::error!("could not flush {} before closing it: {}", doc_id, e);
+            continue;
+        }
+        {
+            let mut handles = docs.lock().await;
+            if !inbox.is_empty() {
+                continue;
+            }
+            unregister(&mut handles, &doc_id, &mut inbox);
+        }
+        release(&fanout, &doc_id).await;
+        break;
+    }
+    // whoever still gets through sees the doc closed and opens it again
+    while inbox.recv().await.is_some() {}
+}
+
+/// Save the edits of a doc another node took over and drop its clients, telling
+/// them to reconnect
+async fn hand_over(session: &mut DocSession, buffer: &mut DocBuffer, db: &Db) {
+    if let Err(e) = buffer.flush(db).await {
+        log::error!(
+            "could not flush {} before handing it over: {}",
+            buffer.id,
+            e
+        );
+    }
+    let msg = ServerMessage::error(
+        ErrorCode::Rejected,
+        "the document moved to another server, reconnect",
+        None,
+    );
+    for client in session.clients.drain(..) {
+        client.send(&msg);
+    }
+}
+
+/// Drop crdt tombstones once no crdt client is left to anchor ops on them.
+/// Clients that join later are synced the state without them.
+fn collect_garbage(session: &DocSession, buffer: &mut DocBuffer) {
+    if !session.clients.iter().any(|c| c.mode == EditMode::Crdt) {
+        buffer.collect_garbage();
+    }
+}
+
+/// Stop taking commands for a doc and take it off the open docs
+fn unregister(
+    handles: &mut HashMap<String, DocHandle>,
+    doc_id: &str,
+    inbox: &mut Receiver<Command>,
+) {
+    inbox.close();
+    if handles.get(doc_id).is_some_and(|h| h.tx.is_closed()) {
+        handles.remove(doc_id);
+    }
+}
+
+/// Let other nodes have a doc this one closed
+async fn release(fanout: &Fanout, doc_id: &str) {
+    if let Err(e) = fanout.broker.release(doc_id).await {
+        log::error!("could not release {}: {}", doc_id, e);
+    }
+}
+
+/// Handle a command for a doc, `leased` telling whether this node's claim on it
+/// is known to still hold
+async fn handle(
+    session: &mut DocSession,
+    buffer: &mut DocBuffer,
+    db: &Db,
+    command: Command,
+    leased: bool,
+) {
+    #[allow(unused)]
+    match command {
+        Command::Join {
+            client,
+            resume,
+            reply,
+        } => {
+            join_session(session, buffer, db, client, resume).await;
+            reply.send(Ok(()));
+        }
+        Command::Message { conn, msg } => handle_message(session, buffer, &conn, msg, leased),
+        Command::Leave { conn } => {
+            if let Some(client) = session.remove(&conn) {
+                session.announce(&client, PresenceEvent::Leave);
+            }
+        }
+        Command::Replace {
+            user_id,
+            content,
+            reply,
+        } => {
+            let res = replace_content(session, buffer, &user_id, &content);
+            let res = match res {
+                Ok(()) => buffer.flush(db).await.map(|()| buffer.revision),
+                Err(e) => Err(e),
+            };
+            reply.send(res);
+        }
+        Command::State { reply } => {
+            reply.send((buffer.content.clone(), buffer.revision));
+        }
+        Command::Flush { reply } => {
+            reply.send(buffer.flush(db).await);
+        }
+        Command::Refresh => refresh_roles(session, db, buffer.id).await,
+        Command::Renewed { .. } | Command::Disown => {}
+    }
+}
+
+///Catch connected clients up with role changes, dropping those who lost
+///access or whose login session was revoked
+async fn refresh_roles(session: &mut DocSession, db: &Db, doc_id: ObjectId) {
+    if session.clients.is_empty() {
+        return;
+    }
+    let doc = match db.find_doc_with_id(doc_id).await {
+        Ok(d) => d,
+        Err(e) => {
+            log::error!("could not refresh roles on {}: {}", doc_id, e);
+            return;
+        }
+    };
+    let sessions = session
+        .clients
+        .iter()
+        .filter_map(|c| ObjectId::parse_str(&c.session).ok())
+        .collect();
+    let live = match db.find_live_sessions(sessions).await {
+        Ok(live) => Some(live),
+        Err(e) => {
+            log::error!("could not check sessions on {}: {}", doc_id, e);
+            None
+        }
+    };
+    let mut revoked = Vec::new();
+    for client in &mut session.clients {
+        let logged_out = live.as_ref().is_some_and(|live| {
+            ObjectId::parse_str(&client.session).is_ok_and(|s| !live.contains(&s))
+        });
+        if logged_out {
+            let msg = ServerMessage::error(ErrorCode::Unauthorized, "your session has ended", None);
+            client.send(&msg);
+            revoked.push(client.conn.clone());
+            continue;
+        }
+        match doc.role(&client.id.into_objetc_id()) {
+            Some(role) => client.role = role,
+            None => {
+                let msg = ServerMessage::error(
+                    ErrorCode::Forbidden,
+                    "you no longer have access to this document",
+                    None,
+                );
+                client.send(&msg);
+                revoked.push(client.conn.clone());
+            }
+        }
+    }
+    session.evict(revoked);
+}
+
+///Act on a decoded client message and reply to the connection it came from
+fn handle_message(
+    session: &mut DocSession,
+    buffer: &mut DocBuffer,
+    conn: &str,
+    msg: ClientMessage,
+    leased: bool,
+) {
+    // the connection was dropped while its message was queued
+    let Some(client) = session.clients.iter().find(|c| c.conn == conn) else {
+        return;
+    };
+    let (user_id, mode, unit, role) = (client.id.clone(), client.mode, client.unit, client.role);
+    let id = msg.id();
+    if matches!(msg, ClientMessage::Op { .. }) && !role.can_edit() {
+        let msg = ServerMessage::error(
+            ErrorCode::Forbidden,
+            "your role does not allow editing this document",
+            id,
+        );
+        reply(session, conn, &msg);
+        return;
+    }
+    // another node may have taken the doc over, edits could be lost
+    if matches!(msg, ClientMessage::Op { .. }) && !leased {
+        let msg = ServerMessage::error(
+            ErrorCode::Rejected,
+            "the document is moving between servers, try again",
+            id,
+        );
+        reply(session, conn, &msg);
+        return;
+    }
+    let res = match msg {
+        ClientMessage::Op {
+            id,
+            op: Op::Update { mut update },
+        } => {
+            update.from = Some(user_id.into_objetc_id());
+            update.timestamp = Some(Utc::now());
+            update.op_id = id;
+            apply_update(session, buffer, conn, update, unit).map(|()| {
+                Some(ServerMessage::Ack {
+                    id,
+                    revision: buffer.revision,
+                })
+            })
+        }
+        ClientMessage::Op {
+            id,
+            op: Op::Crdt { crdt },
+        } => apply_crdt(session, buffer, conn, &user_id, &crdt, id).map(|()| {
+            Some(ServerMessage::Ack {
+                id,
+                revision: buffer.revision,
+            })
+        }),
+        ClientMessage::Presence(presence) => session
+            .set_presence(conn, presence, unit, &buffer.content, buffer.revision)
+            .map(|index| {
+                session.broadcast_presence(index, &buffer.content);
+                None
+            }),
+        ClientMessage::Sync => Ok(Some(sync(session, buffer, mode, unit))),
+        ClientMessage::Ping => Ok(Some(ServerMessage::Pong)),
+    };
+    let msg = match res {
+        Ok(Some(msg)) => msg,
+        Ok(None) => return,
+        Err(e) => ServerMessage::error(ErrorCode::Rejected, e.error, id),
+    };
+    reply(session, conn, &msg);
+}
+
+///Send a message to the `conn` connection, dropping it if it cannot keep up
+fn reply(session: &mut DocSession, conn: &str, msg: &ServerMessage) {
+    if let Some(client) = session.clients.iter().find(|c| c.conn == conn)
+        && !client.send(msg)
+    {
+        session.evict(vec![conn.to_string()]);
+    }
+}
+
+///Current state of the doc for a client editing in `mode` and counting in `unit`
+fn sync(
+    session: &DocSession,
+    buffer: &mut DocBuffer,
+    mode: EditMode,
+    unit: PositionUnit,
+) -> ServerMessage {
+    let crdt = match mode {
+        EditMode::Crdt => Some(buffer.crdt().clone()),
+        EditMode::Ot => None,
+    };
+    ServerMessage::Sync {
+        protocol: PROTOCOL_VERSION,
+        revision: buffer.revision,
+        content: buffer.content.clone(),
+        crdt,
+        presences: session
+            .clients
+            .iter()
+            .map(|c| c.presence(&buffer.content, unit))
+            .collect(),
+    }
+}
+
+///Ops committed since `since` for a client that lost its connection, reading
+///them back from the change log if the session does not reach back that far.
+///Clients too far behind get an error and should be sent the whole doc.
+async fn missed_ops(
+    session: &mut DocSession,
+    buffer: &DocBuffer,
+    db: &Db,
+    since: u64,
+    unit: PositionUnit,
+) -> Result<ServerMessage, Error> {
+    let revision = buffer.revision;
+    if since > revision {
+        return Err(Error::from("revision is ahead of the document"));
+    }
+    if revision - since > HISTORY_LIMIT as u64 {
+        return Err(Error::from("too far behind"));
+    }
+    let oldest = session.oldest().unwrap_or(revision + 1);
+    if since + 1 < oldest {
+        let older = history::applied_updates(db, buffer.id, since, oldest - 1).await?;
+        session.backfill(older);
+    }
+    let ops = session
+        .missed(since, revision, unit)?
+        .into_iter()
+        .cloned()
+        .collect();
+    Ok(ServerMessage::Resume {
+        from: since,
+        revision,
+        ops,
+    })
+}
+
+///Catch a client up with the doc and add it to the session
+async fn join_session(
+    session: &mut DocSession,
+    buffer: &mut DocBuffer,
+    db: &Db,
+    client: Client,
+    resume: Option<u64>,
+) {
+    let msg = match resume {
+        Some(since) if client.mode == EditMode::Ot && client.protocol > 0 => {
+            match missed_ops(session, buffer, db, since, client.unit).await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    log::debug!("cannot resume {} from {}: {}", buffer.id, since, e);
+                    sync(session, buffer, client.mode, client.unit)
+                }
+            }
+        }
+        _ => sync(session, buffer, client.mode, client.unit),
+    };
+    client.send(&msg);
+    session.announce(&client, PresenceEvent::Join);
+    session.clients.push(client);
+}
+
+///Edit the doc into `content` as `user_id`, pushing each edit to every client
+fn replace_content(
+    session: &mut DocSession,
+    buffer: &mut DocBuffer,
+    user_id: &str,
+    content: &str,
+) -> Result<(), Error> {
+    for mut update in diff::to_updates(&buffer.content, content) {
+        update.from = Some(user_id.into_objetc_id());
+        update.timestamp = Some(Utc::now());
+        let (applied, ops) = buffer.apply_update(update, PositionUnit::Scalar)?;
+        session.broadcast("", &applied, &ops);
+        session.commit(applied);
+    }
+    Ok(())
+}
+
+///Transform a position update against concurrent edits and commit it
+fn apply_update(
+    session: &mut DocSession,
+    buffer: &mut DocBuffer,
+    conn: &str,
+    update: Update,
+    unit: PositionUnit,
+) -> Result<(), Error> {
+    for update in session.rebase(update, unit, buffer.revision)? {
+        let (applied, ops) = buffer.apply_update(update, unit)?;
+        session.broadcast(conn, &applied, &ops);
+        session.commit(applied);
+    }
+    Ok(())
+}
+
+///Merge crdt ops, committing the position update each one amounts to
+fn apply_crdt(
+    session: &mut DocSession,
+    buffer: &mut DocBuffer,
+    conn: &str,
+    user_id: &str,
+    ops: &[CrdtOp],
+    op_id: Option<u64>,
+) -> Result<(), Error> {
+    let template = Update {
+        position: 0,
+        from: Some(user_id.into_objetc_id()),
+        update_type: UpdateType::Delete { length: 0 },
+        timestamp: Some(Utc::now()),
+        revision: None,
+        op_id,
+    };
+    for op in ops {
+        if let Some(applied) = buffer.apply_crdt(op, &template)? {
+            session.broadcast(conn, &applied, std::slice::from_ref(op));
+            session.commit(applied);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Update, UpdateType};
+
+/// Unit `Update` positions and lengths are counted in
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionUnit {
+    /// UTF-16 code units, what browsers report for textarea selections
+    #[default]
+    Utf16,
+    /// Unicode scalar values, the unit docs are stored and transformed in
+    Scalar,
+}
+
+impl PositionUnit {
+    /// Length of `text` in this unit
+    pub fn measure(self, text: &str) -> usize {
+        match self {
+            PositionUnit::Utf16 => text.encode_utf16().count(),
+            PositionUnit::Scalar => text.chars().count(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum PositionError {
+    /// Position lies past the end of the content
+    OutOfBounds { position: usize, length: usize },
+    /// Position falls between the two halves of a surrogate pair
+    SplitsCharacter { position: usize },
+}
+
+impl Display for PositionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PositionError::OutOfBounds { position, length } => {
+                write!(
+                    f,
+                    "position {} is out of bounds for length {}",
+                    position, length
+                )
+            }
+            PositionError::SplitsCharacter { position } => {
+                write!(f, "position {} splits a character", position)
+            }
+        }
+    }
+}
+
+/// An applied update expressed in every position unit
+#[derive(Debug, Clone)]
+pub struct AppliedUpdate {
+    pub scalar: Update,
+    pub utf16: Update,
+}
+
+impl AppliedUpdate {
+    /// Build from an update in scalar values, `content` being the text it applies to
+    pub fn from_scalar(content: &str, update: Update) -> Self {
+        let prefix: String = content.chars().take(update.position).collect();
+        let mut utf16 = update.clone();
+        utf16.position = PositionUnit::Utf16.measure(&prefix);
+        if let UpdateType::Delete { length } = update.update_type {
+            let removed: String = content.chars().skip(update.position).take(length).collect();
+            utf16.update_type = UpdateType::Delete {
+                length: PositionUnit::Utf16.measure(&removed),
+            };
+        }
+        AppliedUpdate {
+            scalar: update,
+            utf16,
+        }
+    }
+
+    pub fn in_unit(&self, unit: PositionUnit) -> &Update {
+        match unit {
+            PositionUnit::Utf16 => &self.utf16,
+            PositionUnit::Scalar => &self.scalar,
+        }
+    }
+}
+
+/// Convert a position in `unit` to a count of scalar values into `content`
+pub fn to_scalar(
+    content: &str,
+    position: usize,
+    unit: PositionUnit,
+) -> Result<usize, PositionError> {
+    let mut offset = 0;
+    for (i, c) in content.chars().enumerate() {
+        if offset == position {
+            return Ok(i);
+        }
+        if offset > position {
+            return Err(PositionError::SplitsCharacter { position });
+        }
+        offset += match unit {
+            PositionUnit::Utf16 => c.len_utf16(),
+            PositionUnit::Scalar => 1,
+        };
+    }
+    match offset {
+        o if o == position => Ok(content.chars().count()),
+        o if o > position => Err(PositionError::SplitsCharacter { position }),
+        o => Err(PositionError::OutOfBounds {
+            position,
+            length: o,
+        }),
+    }
+}
+
+/// Convert a count of scalar values into `content` to a position in `unit`
+pub fn from_scalar(content: &str, position: usize, unit: PositionUnit) -> usize {
+    match unit {
+        PositionUnit::Utf16 => content.chars().take(position).map(char::len_utf16).sum(),
+        PositionUnit::Scalar => position,
+    }
+}
+
+/// Convert an update counted in `unit` to scalar values, validating it against `content`
+pub fn normalize(
+    content: &str,
+    update: &Update,
+    unit: PositionUnit,
+) -> Result<Update, PositionError> {
+    let mut scalar = update.clone();
+    scalar.position = to_scalar(content, update.position, unit)?;
+    if let UpdateType::Delete { length } = update.update_type {
+        let end = update
+            .position
+            .checked_add(length)
+            .ok_or(PositionError::OutOfBounds {
+                position: update.position,
+                length: unit.measure(content),
+            })?;
+        let end = to_scalar(content, end, unit)?;
+        scalar.update_type = UpdateType::Delete {
+            length: end - scalar.position,
+        };
+    }
+    Ok(scalar)
+}
+
+/// Apply an update counted in `unit` to `content`, returning the new content
+pub fn apply(
+    content: &str,
+    update: &Update,
+    unit: PositionUnit,
+) -> Result<(String, AppliedUpdate), PositionError> {
+    let scalar = normalize(content, update, unit)?;
+    let byte_offset = |position: usize| {
+        content
+            .char_indices()
+            .nth(position)
+            .map_or(content.len(), |(i, _)| i)
+    };
+    let start = byte_offset(scalar.position);
+    let new_content = match scalar.update_type {
+        UpdateType::Insert { ref data } => content[..start].to_owned() + data + &content[start..],
+        UpdateType::Delete { length } => {
+            let end = content[start..]
+                .char_indices()
+                .nth(length)
+                .map_or(content.len(), |(i, _)| start + i);
+            content[..start].to_owned() + &content[end..]
+        }
+    };
+    Ok((new_content, AppliedUpdate::from_scalar(content, scalar)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(position: usize, update_type: UpdateType) -> Update {
+        Update {
+            position,
+            from: None,
+            update_type,
+            timestamp: None,
+            revision: None,
+            op_id: None,
+        }
+    }
+
+    fn delete(position: usize, length: usize) -> Update {
+        update(position, UpdateType::Delete { length })
+    }
+
+    #[test]
+    fn utf16_positions_count_surrogate_pairs_twice() {
+        // "😀" is two UTF-16 code units but a single scalar value
+        let scalar = normalize("a😀b", &delete(1, 2), PositionUnit::Utf16).unwrap();
+        assert_eq!(scalar.position, 1);
+        assert!(matches!(
+            scalar.update_type,
+            UpdateType::Delete { length: 1 }
+        ));
+        let after = normalize("a😀b", &delete(3, 1), PositionUnit::Utf16).unwrap();
+        assert_eq!(after.position, 2);
+    }
+
+    #[test]
+    fn position_inside_surrogate_pair_is_rejected() {
+        let res = normalize("a😀b", &delete(2, 1), PositionUnit::Utf16);
+        assert_eq!(
+            res.unwrap_err(),
+            PositionError::SplitsCharacter { position: 2 }
+        );
+        let res = normalize("a😀b", &delete(1, 1), PositionUnit::Utf16);
+        assert_eq!(
+            res.unwrap_err(),
+            PositionError::SplitsCharacter { position: 2 }
+        );
+    }
+
+    #[test]
+    fn out_of_bounds_positions_are_rejected() {
+        let insert = update(
+            5,
+            UpdateType::Insert {
+                data: "x".to_string(),
+            },
+        );
+        assert!(matches!(
+            normalize("abc", &insert, PositionUnit::Scalar),
+            Err(PositionError::OutOfBounds { position: 5, .. })
+        ));
+        assert!(matches!(
+            normalize("abc", &delete(1, 3), PositionUnit::Scalar),
+            Err(PositionError::OutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn overflowing_length_is_out_of_bounds() {
+        assert!(matches!(
+            normalize("abc", &delete(1, usize::MAX), PositionUnit::Utf16),
+            Err(PositionError::OutOfBounds { position: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn apply_reports_the_update_in_both_units() {
+        let (content, applied) = apply("a😀bc", &delete(1, 3), PositionUnit::Utf16).unwrap();
+        assert_eq!(content, "ac");
+        assert!(matches!(
+            applied.scalar.update_type,
+            UpdateType::Delete { length: 2 }
+        ));
+        assert!(matches!(
+            applied.utf16.update_type,
+            UpdateType::Delete { length: 3 }
+        ));
+    }
+}
@@ -0,0 +1,133 @@
+use std::time::SystemTime;
+
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{
+    models::Error,
+    utils::{self, hash_password},
+};
+
+/// Name authenticator apps list accounts under
+const ISSUER: &str = "Docsly";
+/// Seconds each code is valid for
+const STEP: u64 = 30;
+const DIGITS: usize = 6;
+/// Recovery codes handed out at once
+const RECOVERY_CODES: usize = 10;
+
+fn totp(secret: &str, account: &str) -> Result<TOTP, Error> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|_| Error::from("invalid totp secret"))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        1,
+        STEP,
+        secret,
+        Some(ISSUER.to_string()),
+        account.to_string(),
+    )
+    .map_err(|e| Error::new(e.to_string()))
+}
+
+/// New base32 secret to enroll an authenticator with
+pub fn generate_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+/// `otpauth://` URI authenticator apps enroll from, usually shown as a QR code
+pub fn provisioning_uri(secret: &str, account: &str) -> Result<String, Error> {
+    Ok(totp(secret, account)?.get_url())
+}
+
+/// Time step of `code` if it is valid for the previous, current or next step
+/// and newer than `last_step`, so a code cannot be used twice
+pub fn check(secret: &str, code: &str, last_step: u64) -> Result<Option<u64>, Error> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    check_at(secret, code, last_step, now)
+}
+
+fn check_at(secret: &str, code: &str, last_step: u64, time: u64) -> Result<Option<u64>, Error> {
+    let code = normalize(code);
+    if code.len() != DIGITS {
+        return Ok(None);
+    }
+    let totp = totp(secret, "")?;
+    let now = time / STEP;
+    Ok((now.saturating_sub(1)..=now + 1)
+        .filter(|step| *step > last_step)
+        .find(|step| totp.generate(step * STEP) == code))
+}
+
+/// Code as typed, without the spaces and dashes people add
+pub fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Fresh recovery codes, along with the hashes to store
+pub fn recovery_codes() -> Result<(Vec<String>, Vec<String>), Error> {
+    let mut codes = Vec::with_capacity(RECOVERY_CODES);
+    let mut hashes = Vec::with_capacity(RECOVERY_CODES);
+    for _ in 0..RECOVERY_CODES {
+        let code = utils::random_token()[..10].to_string();
+        hashes.push(hash_password(code.as_bytes())?);
+        codes.push(format!("{}-{}", &code[..5], &code[5..]));
+    }
+    Ok((codes, hashes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Base32 of the RFC 6238 test secret "12345678901234567890"
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    const TIME: u64 = 1_700_000_000;
+
+    fn code_at(step: u64) -> String {
+        totp(SECRET, "").unwrap().generate(step * STEP)
+    }
+
+    #[test]
+    fn accepts_codes_one_step_either_side() {
+        let now = TIME / STEP;
+        for step in [now - 1, now, now + 1] {
+            let code = code_at(step);
+            assert_eq!(check_at(SECRET, &code, 0, TIME).unwrap(), Some(step));
+        }
+        for step in [now - 2, now + 2] {
+            let code = code_at(step);
+            assert_eq!(check_at(SECRET, &code, 0, TIME).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn rejects_codes_already_used() {
+        let now = TIME / STEP;
+        let code = code_at(now);
+        assert_eq!(check_at(SECRET, &code, now, TIME).unwrap(), None);
+        // an older code can't come back after a newer one was accepted
+        let previous = code_at(now - 1);
+        assert_eq!(check_at(SECRET, &previous, now, TIME).unwrap(), None);
+        let next = code_at(now + 1);
+        assert_eq!(check_at(SECRET, &next, now, TIME).unwrap(), Some(now + 1));
+    }
+
+    #[test]
+    fn ignores_spaces_and_dashes() {
+        let code = code_at(TIME / STEP);
+        let typed = format!("{} {}", &code[..3], &code[3..]);
+        assert_eq!(
+            check_at(SECRET, &typed, 0, TIME).unwrap(),
+            Some(TIME / STEP)
+        );
+        assert_eq!(check_at(SECRET, "12345", 0, TIME).unwrap(), None);
+    }
+}
@@ -1,13 +1,23 @@
+use std::env;
 
 use argon2::{
     Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
-    password_hash::{SaltString, rand_core::OsRng},
+    password_hash::{
+        SaltString,
+        rand_core::{OsRng, RngCore},
+    },
 };
 use axum::http::request::Parts;
-use jsonwebtoken::{DecodingKey, Validation, decode};
-use tower_cookies::Cookie;
+use mongodb::bson::oid::ObjectId;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tower_cookies::{Cookie, Cookies};
 
-use crate::models::{self};
+use crate::{
+    db::Db,
+    jwt,
+    models::{self},
+};
 
 pub async fn extract_cookies(parts: &Parts) -> Option<models::Claims> {
     let cookie = parts.headers.get("Cookie");
@@ -15,12 +25,10 @@ pub async fn extract_cookies(parts: &Parts) -> Option<models::Claims> {
         Some(c) => {
             let jwt = c.to_str().unwrap();
             if let Some((_, token)) = jwt.split_once("=") {
-                let key = &DecodingKey::from_secret("hello".as_ref());
-                let claims = decode::<models::Claims>(token, key, &Validation::default());
-                match claims {
-                    Ok(c) => Some(c.claims),
+                match jwt::verify(token) {
+                    Ok(c) => Some(c),
                     Err(e) => {
-                        log::error!("{}", e.to_string());
+                        log::error!("{}", e);
                         None
                     }
                 }
@@ -34,21 +42,43 @@ pub async fn extract_cookies(parts: &Parts) -> Option<models::Claims> {
 
 pub async fn decode_cookie(cookie: Cookie<'_>) -> Option<models::Claims> {
     let (name, token) = (cookie.name(), cookie.value());
-    if !(name == "token") {
+    if name != "token" {
         log::error!("name did not match\nname: {}", name);
         return None;
     }
-    let key = &DecodingKey::from_secret("hello".as_ref());
-    let claims = decode::<models::Claims>(token, key, &Validation::default());
-    match claims {
-        Ok(c) => Some(c.claims),
+    match jwt::verify(token) {
+        Ok(c) => Some(c),
+        Err(e) => {
+            log::error!("{}", e);
+            None
+        }
+    }
+}
+
+/// Claims of the access token cookie, as long as the session it was issued
+/// for has not been revoked
+pub async fn session_claims(db: &Db, cookies: &Cookies) -> Option<models::Claims> {
+    let claims = decode_cookie(cookies.get("token")?).await?;
+    let session = ObjectId::parse_str(&claims.sid).ok()?;
+    match db.find_session(session).await {
+        Ok(_) => Some(claims),
         Err(e) => {
-            log::error!("{}", e.to_string());
+            log::debug!("{}: {}", claims.sid, e);
             None
         }
     }
 }
 
+/// Whether `ENV` says this is a production deployment, in any case
+pub fn is_production() -> bool {
+    env::var("ENV").is_ok_and(|e| e.eq_ignore_ascii_case("prod"))
+}
+
+/// Whether `ENV` says this is a development setup, in any case
+pub fn is_development() -> bool {
+    env::var("ENV").is_ok_and(|e| e.eq_ignore_ascii_case("dev"))
+}
+
 pub fn hash_password(pass: &[u8]) -> Result<String, argon2::password_hash::Error> {
     let salt = SaltString::generate(&mut OsRng);
     let argon = Argon2::default();
@@ -56,6 +86,32 @@ pub fn hash_password(pass: &[u8]) -> Result<String, argon2::password_hash::Error
     Ok(hash.to_string())
 }
 
+/// Form emails are stored and compared in
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Random hex string to hand out as a secret
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hex SHA-256 of a random token, for storage. Tokens carry enough entropy that
+/// a slow password hash buys nothing.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Whether `token` hashes to `hash`, compared in constant time
+pub fn verify_token_hash(token: &str, hash: &str) -> bool {
+    hash_token(token).as_bytes().ct_eq(hash.as_bytes()).into()
+}
+
 pub fn verify_password_hash(pass: &str, hash: &str) -> Result<(), argon2::password_hash::Error> {
     let hash = PasswordHash::new(hash)?;
     let argon = Argon2::default();